@@ -0,0 +1,13 @@
+// Bencoded values as the "decode" command and torrent files see them
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use bittorrent::decoder;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(encoded_value) = std::str::from_utf8(data) {
+        let _ = decoder::decode_bencoded_value(encoded_value);
+    }
+});
@@ -0,0 +1,34 @@
+// Extended messages: the first byte picks the extension, the rest is its payload
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio::sync::mpsc;
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use bittorrent::extension::{ExtensionHandshake, ExtensionRegistry};
+use bittorrent::pex::{self, PexMessage, PexSwarm};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&id, payload)) = data.split_first() else {
+        return;
+    };
+    if let Ok(handshake) = ExtensionHandshake::parse(payload) {
+        let _ = handshake.your_ip();
+        let _ = handshake.extension_id(pex::EXTENSION_NAME);
+    }
+    if let Ok(message) = PexMessage::parse(payload) {
+        let _ = message.added_peers();
+        let _ = message.dropped_peers();
+    }
+
+    // through the registry as a connection would, handshake first
+    let (discovered, _) = mpsc::unbounded_channel();
+    let swarm = PexSwarm::new(discovered);
+    let mut extensions = ExtensionRegistry::new();
+    let pex_id = extensions.register(pex::EXTENSION_NAME, Box::new(swarm.handler(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881), true)));
+    let handshake = format!("d1:md{}:{}i{}eee", pex::EXTENSION_NAME.len(), pex::EXTENSION_NAME, pex_id);
+    let _ = extensions.handle(0, handshake.as_bytes());
+    let _ = extensions.handle(id, payload);
+});
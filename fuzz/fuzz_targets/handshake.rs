@@ -0,0 +1,64 @@
+// Everything a peer connecting to us sends: the MSE or plaintext handshake and
+// the messages after it, read until the connection fails. An odd first byte has
+// a valid plaintext handshake sent first, so the messages get looked at too.
+// Handshakes that parse have to serialize back to the same bytes.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio::io::AsyncWriteExt;
+use tokio::runtime;
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use bittorrent::extension::ExtensionRegistry;
+use bittorrent::handshake::{Capabilities, Handshake};
+use bittorrent::message::MessageLimits;
+use bittorrent::mse::EncryptionPolicy;
+use bittorrent::peer::{PeerConnection, PeerTimeouts, BLOCK_SIZE};
+use bittorrent::torrent::TorrentInfo;
+use bittorrent::transport::{MemoryNetwork, Transport};
+
+const PIECE_NUM: usize = 20;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(handshake) = Handshake::parse(data) {
+        assert_eq!(Handshake::parse(&handshake.to_bytes()).unwrap(), handshake);
+    }
+
+    let Some((&mode, data)) = data.split_first() else {
+        return;
+    };
+    let info = TorrentInfo{
+        length: PIECE_NUM as u64 * BLOCK_SIZE as u64 - 100,
+        files: None,
+        name: "fuzz".to_string(),
+        piece_length: BLOCK_SIZE,
+        pieces: vec![0; PIECE_NUM * 20],
+    };
+    let mut sent = Vec::new();
+    if mode % 2 == 1 {
+        let capabilities = Capabilities{ extension: true, fast: true, dht: false };
+        sent = Handshake::new(&info.get_hash().unwrap(), [0x42; 20], capabilities).unwrap().to_bytes();
+    }
+    sent.extend_from_slice(data);
+
+    let runtime = runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async {
+        let network = MemoryNetwork::new();
+        let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        let us = network.transport(*addr.ip());
+        us.listen(addr).await.unwrap();
+
+        // the peer says everything at once and hangs up
+        let mut peer = network.transport(Ipv4Addr::new(10, 0, 0, 2)).connect(addr).await.unwrap();
+        tokio::spawn(async move {
+            let _ = peer.write_all(&sent).await;
+        });
+
+        let stream = us.accept().await.unwrap();
+        if let Ok(mut connection) = PeerConnection::accept(stream, &info, ExtensionRegistry::new(), EncryptionPolicy::Enabled, MessageLimits::default(), PeerTimeouts::default()).await {
+            while connection.next_message().await.is_ok() {}
+        }
+    });
+});
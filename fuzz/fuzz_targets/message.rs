@@ -0,0 +1,24 @@
+// Peer wire messages: whatever parses has to serialize back to the same message,
+// and a stream of them is read within the size limits
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio::runtime;
+
+use bittorrent::message::{self, Message, MessageLimits};
+
+const PIECE_NUM: usize = 100;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = Message::parse(data) {
+        let bytes = message.to_bytes();
+        assert_eq!(Message::parse(&bytes[4..]).unwrap(), message);
+    }
+
+    let runtime = runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(async {
+        let mut reader = data;
+        while message::read_message(&mut reader, &MessageLimits::default(), PIECE_NUM).await.is_ok() {}
+    });
+});
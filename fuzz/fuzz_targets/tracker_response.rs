@@ -0,0 +1,12 @@
+// Responses of HTTP trackers
+
+#![no_main]
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+
+use bittorrent::decoder;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decoder::decode_tracker_response(&Bytes::copy_from_slice(data));
+});
@@ -0,0 +1,60 @@
+// bitfield.rs
+
+// Piece bitfield, laid out as in the bitfield message: the high bit of the
+// first byte is piece 0, spare bits at the end are zero.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self{ bytes: vec![0u8; len.div_ceil(8)], len }
+    }
+
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        let size = bitfield.bytes.len().min(bytes.len());
+        bitfield.bytes[..size].copy_from_slice(&bytes[..size]);
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        if index >= self.len {
+            return;
+        }
+        match value {
+            true => self.bytes[index / 8] |= 0x80 >> (index % 8),
+            false => self.bytes[index / 8] &= !(0x80 >> (index % 8)),
+        }
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.bytes.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let spare = self.bytes.len() * 8 - self.len;
+        if let Some(last) = self.bytes.last_mut() {
+            *last &= 0xFFu8.checked_shl(spare as u32).unwrap_or(0);
+        }
+    }
+}
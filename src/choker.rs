@@ -0,0 +1,82 @@
+// choker.rs
+
+use rand;
+
+use std::collections::HashSet;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+// how often the unchoked peers are chosen again
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+// how long the optimistic unchoke stays with the same peer
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+// regular upload slots, the optimistic unchoke comes on top
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+// A connected peer as seen by the choker, rates measured since the last rechoke
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    pub addr: SocketAddrV4,
+    pub interested: bool,
+    pub download_rate: f64, // bytes per second the peer sent us
+    pub upload_rate: f64,   // bytes per second we sent the peer
+}
+
+// Tit-for-tat: the interested peers we download fastest from get the upload
+// slots, or the ones we upload fastest to once we seed. One more interested
+// peer is unchoked optimistically so newcomers get a chance to prove themselves.
+pub struct Choker {
+    slots: usize,
+    optimistic: Option<(SocketAddrV4, Instant)>,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Self{ slots, optimistic: None }
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    // The peers to unchoke from now on, every other peer gets choked
+    pub fn rechoke(&mut self, peers: &[PeerStats], seeding: bool, now: Instant) -> HashSet<SocketAddrV4> {
+        let rate = |peer: &PeerStats| match seeding {
+            true => peer.upload_rate,
+            false => peer.download_rate,
+        };
+        let mut interested: Vec<&PeerStats> = peers.iter().filter(|peer| peer.interested).collect();
+        interested.sort_by(|a, b| rate(b).total_cmp(&rate(a)));
+        let mut unchoked: HashSet<SocketAddrV4> = interested.iter().take(self.slots).map(|peer| peer.addr).collect();
+
+        // move the optimistic unchoke on once its time is up, its peer left or
+        // lost interest, or it earned a regular slot
+        let keep = self.optimistic.is_some_and(|(addr, since)| {
+            now.duration_since(since) < OPTIMISTIC_INTERVAL
+                && interested.iter().any(|peer| peer.addr == addr)
+                && !unchoked.contains(&addr)
+        });
+        if !keep {
+            // another peer gets its turn when there is one
+            let previous = self.optimistic.map(|(addr, _)| addr);
+            let mut candidates: Vec<SocketAddrV4> = interested
+                .iter()
+                .map(|peer| peer.addr)
+                .filter(|addr| !unchoked.contains(addr))
+                .collect();
+            if candidates.len() > 1 {
+                candidates.retain(|&addr| Some(addr) != previous);
+            }
+            self.optimistic = match candidates.is_empty() {
+                true => None,
+                false => Some((candidates[rand::random_range(0..candidates.len())], now)),
+            };
+        }
+        if let Some((addr, _)) = self.optimistic {
+            unchoked.insert(addr);
+        }
+        unchoked
+    }
+}
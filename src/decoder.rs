@@ -1,22 +1,73 @@
 // decoder.rs
 
 use bytes::Bytes;
+use serde::de::DeserializeOwned;
 use serde_json;
 use serde_bencode;
 use serde_urlencoded;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use std::{fs, collections};
 
 use crate::magnet::MagnetLink;
 use crate::torrent::TorrentFile;
 use crate::tracker::TrackerResponse;
 
+// deepest nesting of lists and dictionaries accepted, serde_bencode recurses
+// once per level and hostile input would overflow the stack
+pub const MAX_BENCODE_DEPTH: usize = 64;
+
 // Decode bencoded data
 pub fn decode_bencoded_value(encoded_value: &str) -> Result<serde_json::Value> {
-    let value: serde_bencode::value::Value = serde_bencode::from_str(encoded_value)?;
+    let value: serde_bencode::value::Value = from_bencode(encoded_value.as_bytes())?;
     convert(value)
 }
 
+// Deserialize bencoded data, which may come from anyone
+pub fn from_bencode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    check_depth(bytes)?;
+    Ok(serde_bencode::from_bytes(bytes)?)
+}
+
+// Walk the data without recursing and refuse it if it nests deeper than
+// MAX_BENCODE_DEPTH. Anything malformed is left for serde_bencode to report.
+fn check_depth(bytes: &[u8]) -> Result<()> {
+    let mut depth = 0;
+    let mut position = 0;
+    while position < bytes.len() {
+        match bytes[position] {
+            b'l' | b'd' => {
+                depth += 1;
+                if depth > MAX_BENCODE_DEPTH {
+                    bail!("bencoded data nested deeper than {} levels", MAX_BENCODE_DEPTH);
+                }
+                position += 1;
+            },
+            b'e' => {
+                depth = depth.saturating_sub(1);
+                position += 1;
+            },
+            b'i' => match bytes[position..].iter().position(|&byte| byte == b'e') {
+                Some(end) => position += end + 1,
+                None => return Ok(()),
+            },
+            b'0'..=b'9' => {
+                // a string, its contents may look like anything
+                let Some(colon) = bytes[position..].iter().position(|&byte| byte == b':') else {
+                    return Ok(());
+                };
+                let Some(length) = std::str::from_utf8(&bytes[position..position + colon])
+                    .ok()
+                    .and_then(|length| length.parse::<usize>().ok()) else {
+                    return Ok(());
+                };
+                position = (position + colon + 1).saturating_add(length);
+            },
+            _ => position += 1,
+        }
+    }
+    Ok(())
+}
+
 fn convert(value: serde_bencode::value::Value) -> Result<serde_json::Value> {
     match value {
         serde_bencode::value::Value::Int(i) => {
@@ -28,7 +79,7 @@ fn convert(value: serde_bencode::value::Value) -> Result<serde_json::Value> {
         },
         serde_bencode::value::Value::List(l) => {
             let array = l.into_iter()
-                .map(|elem| convert(elem))
+                .map(convert)
                 .collect::<Result<Vec<serde_json::Value>>>()?;
             Ok(serde_json::to_value(array)?)
         },
@@ -49,13 +100,14 @@ fn convert(value: serde_bencode::value::Value) -> Result<serde_json::Value> {
 // Decode torrent file
 pub fn decode_torrent_file(file_name: &str) -> Result<TorrentFile> {
     let content_encoded = fs::read(file_name)?;
-    let content: TorrentFile = serde_bencode::from_bytes(&content_encoded)?;
+    let content: TorrentFile = from_bencode(&content_encoded)?;
+    content.info.validate()?;
     Ok(content)
 }
 
 // Decode tracker response
 pub fn decode_tracker_response(raw_response: &Bytes) -> Result<TrackerResponse> {
-    let content: TrackerResponse = serde_bencode::from_bytes(raw_response)?;
+    let content: TrackerResponse = from_bencode(raw_response)?;
     Ok(content)
 }
 
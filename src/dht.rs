@@ -0,0 +1,704 @@
+// dht.rs
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Serialize, Deserialize};
+use serde_bytes::ByteBuf;
+use serde_bencode;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::decoder;
+use crate::encoder;
+use crate::tracker;
+use crate::utils;
+
+// well known routers to join the mainline DHT through
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+// routing table kept between runs, in the working directory
+pub const DEFAULT_STATE_FILE: &str = "dht.state";
+
+// downloads and seeds announce again this often
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// nodes per bucket, and the closest nodes a lookup settles on
+const K: usize = 8;
+
+// queries a lookup has in flight at once
+const ALPHA: usize = 3;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// a node that missed this many queries in a row may be replaced
+const MAX_NODE_FAILURES: u32 = 2;
+
+// tokens stay valid for one to two rotations
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+// announced peers are forgotten after this long
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+// peers kept per info hash and returned per get_peers answer
+const MAX_PEERS_PER_HASH: usize = 100;
+// info hashes peers are kept for, announces for further ones are ignored
+pub const MAX_INFO_HASHES: usize = 1000;
+const MAX_VALUES: usize = 50;
+
+const MAX_PACKET: usize = 64 * 1024;
+
+pub type NodeId = [u8; 20];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+// Compact node info, 20 bytes id, 4 bytes ip and 2 bytes port per node
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(nodes.len() * 26);
+    for node in nodes {
+        bytes.extend_from_slice(&node.id);
+        bytes.extend_from_slice(&tracker::encode_compact_peers([&node.addr]));
+    }
+    bytes
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<NodeInfo> {
+    bytes.chunks_exact(26)
+        .filter_map(|chunk| {
+            let id = NodeId::try_from(&chunk[..20]).ok()?;
+            let addr = tracker::decode_compact_peers(&chunk[20..]).pop()?;
+            Some(NodeInfo{ id, addr })
+        })
+        .collect()
+}
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0u8; 20];
+    for (index, byte) in distance.iter_mut().enumerate() {
+        *byte = a[index] ^ b[index];
+    }
+    distance
+}
+
+fn to_id(bytes: &[u8]) -> Result<NodeId> {
+    NodeId::try_from(bytes).map_err(|_| anyhow!("expected 20 bytes, got {}", bytes.len()))
+}
+
+fn random_id() -> NodeId {
+    let mut id = [0u8; 20];
+    for byte in id.iter_mut() {
+        *byte = rand::random();
+    }
+    id
+}
+
+struct RoutingEntry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+// Kademlia routing table, one bucket of up to `K` nodes per length of the prefix
+// a node's id shares with ours
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<RoutingEntry>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self{ own_id, buckets: (0..160).map(|_| Vec::new()).collect() }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own_id, id);
+        let leading_zeros = distance.iter()
+            .position(|&byte| byte != 0)
+            .map(|index| index * 8 + distance[index].leading_zeros() as usize)?;
+        Some(leading_zeros)
+    }
+
+    // Record a node we heard from. A full bucket only takes it in place of a node
+    // that stopped answering. Returns whether the node is in the table.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let Some(bucket_index) = self.bucket_index(&node.id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[bucket_index];
+        let entry = RoutingEntry{ node, last_seen: Instant::now(), failures: 0 };
+        if let Some(position) = bucket.iter().position(|entry| entry.node.id == node.id) {
+            bucket.remove(position);
+            bucket.push(entry);
+            return true;
+        }
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+        match bucket.iter().position(|entry| entry.failures >= MAX_NODE_FAILURES) {
+            Some(position) => {
+                bucket.remove(position);
+                bucket.push(entry);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // a query to `addr` went unanswered
+    pub fn failed(&mut self, addr: SocketAddrV4) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.node.addr == addr {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    // the `num` known nodes closest to `target`, answering ones first
+    pub fn closest(&self, target: &NodeId, num: usize) -> Vec<NodeInfo> {
+        let mut entries: Vec<&RoutingEntry> = self.buckets.iter().flatten().collect();
+        entries.sort_by_key(|entry| (entry.failures >= MAX_NODE_FAILURES, distance(&entry.node.id, target)));
+        entries.into_iter().take(num).map(|entry| entry.node).collect()
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets.iter().flatten().map(|entry| entry.node).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // buckets nothing was heard from in a while, as ids that fall into them
+    fn stale_targets(&self, age: Duration) -> Vec<NodeId> {
+        let now = Instant::now();
+        self.buckets.iter()
+            .filter(|bucket| bucket.iter().all(|entry| now.duration_since(entry.last_seen) > age))
+            .filter_map(|bucket| bucket.first())
+            .map(|entry| entry.node.id)
+            .collect()
+    }
+}
+
+// A KRPC message: a query, a response or an error
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Krpc {
+    t: ByteBuf,
+    y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<Arguments>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<Values>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, String)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Arguments {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Values {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+}
+
+// what a get_peers query returned
+pub struct GetPeers {
+    pub token: Option<Vec<u8>>,
+    pub peers: Vec<SocketAddrV4>,
+    pub nodes: Vec<NodeInfo>,
+}
+
+// routing table saved between runs
+#[derive(Serialize, Deserialize)]
+struct DhtState {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    nodes: Vec<u8>,
+}
+
+struct TokenSecrets {
+    current: NodeId,
+    previous: NodeId,
+    rotated: Instant,
+}
+
+type PendingQuery = (SocketAddrV4, oneshot::Sender<Result<Values>>);
+
+// A mainline DHT node (BEP 5) on one UDP socket. It answers other nodes' queries
+// in the background and looks up and announces peers for torrents.
+pub struct Dht {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<HashMap<u16, PendingQuery>>,
+    next_transaction: Mutex<u16>,
+    secrets: Mutex<TokenSecrets>,
+    peers: Mutex<HashMap<NodeId, HashMap<SocketAddrV4, Instant>>>, // announced to us, by info hash
+    receiver: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl fmt::Debug for Dht {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dht")
+            .field("id", &hex::encode(self.id))
+            .field("nodes", &self.node_num())
+            .finish()
+    }
+}
+
+impl Dht {
+    // Start a node on `addr`, with the id and nodes of `state_file` if it exists
+    pub async fn bind(addr: SocketAddrV4, state_file: Option<&Path>) -> Result<Arc<Self>> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let dht = Self::new(socket.clone(), state_file)?;
+        let receiver = tokio::spawn(receive(socket, Arc::downgrade(&dht)));
+        *dht.receiver.lock().unwrap() = Some(receiver);
+        Ok(dht)
+    }
+
+    // Start a node on a socket shared with uTP, which passes on the `packets`
+    // meant for the DHT
+    pub fn bind_shared(
+        socket: Arc<UdpSocket>,
+        packets: mpsc::UnboundedReceiver<(Vec<u8>, SocketAddrV4)>,
+        state_file: Option<&Path>
+    ) -> Result<Arc<Self>> {
+        let dht = Self::new(socket, state_file)?;
+        let receiver = tokio::spawn(receive_shared(packets, Arc::downgrade(&dht)));
+        *dht.receiver.lock().unwrap() = Some(receiver);
+        Ok(dht)
+    }
+
+    fn new(socket: Arc<UdpSocket>, state_file: Option<&Path>) -> Result<Arc<Self>> {
+        let state = match state_file {
+            Some(path) if path.exists() => Some(decoder::from_bencode::<DhtState>(&fs::read(path)?)?),
+            _ => None,
+        };
+        let id = match &state {
+            Some(state) => to_id(&state.id)?,
+            None => random_id(),
+        };
+        let mut table = RoutingTable::new(id);
+        for node in state.map(|state| decode_nodes(&state.nodes)).unwrap_or_default() {
+            table.insert(node);
+        }
+
+        Ok(Arc::new(Self{
+            id,
+            socket,
+            table: Mutex::new(table),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: Mutex::new(rand::random()),
+            secrets: Mutex::new(TokenSecrets{ current: random_id(), previous: random_id(), rotated: Instant::now() }),
+            peers: Mutex::new(HashMap::new()),
+            receiver: Mutex::new(None),
+        }))
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn node_num(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    pub fn save_state(&self, path: &Path) -> Result<()> {
+        let state = DhtState{ id: self.id.to_vec(), nodes: encode_nodes(&self.table.lock().unwrap().nodes()) };
+        utils::write_atomically(path, &serde_bencode::to_bytes(&state)?)
+    }
+
+    // Join the network through `nodes` ("host:port") and the nodes already in the
+    // table, then fill the table with the nodes close to us and refresh buckets
+    // nothing was heard from in a while. Returns the number of nodes known.
+    pub async fn bootstrap(self: &Arc<Self>, nodes: &[String]) -> usize {
+        let mut queries = JoinSet::new();
+        for node in nodes {
+            let Ok(addrs) = lookup_host(node.as_str()).await else {
+                eprintln!("Failed to resolve DHT node {}", node);
+                continue;
+            };
+            for addr in addrs {
+                let SocketAddr::V4(addr) = addr else {
+                    continue;
+                };
+                let dht = self.clone();
+                queries.spawn(async move { dht.find_node(addr, &dht.id).await });
+            }
+        }
+        queries.join_all().await;
+
+        self.lookup(self.id, false).await;
+        let mut refreshes = JoinSet::new();
+        for target in self.table.lock().unwrap().stale_targets(ANNOUNCE_INTERVAL) {
+            let dht = self.clone();
+            refreshes.spawn(async move { dht.lookup(target, false).await });
+        }
+        refreshes.join_all().await;
+        self.node_num()
+    }
+
+    // Peers of a torrent, from the nodes closest to its info hash
+    pub async fn get_peers(self: &Arc<Self>, info_hash: &[u8]) -> Result<Vec<SocketAddrV4>> {
+        let (peers, _) = self.lookup(to_id(info_hash)?, true).await;
+        Ok(peers.into_iter().collect())
+    }
+
+    // Tell the nodes closest to the info hash that we serve it on `port`, returns
+    // the peers the lookup found along the way even if no node took the announce
+    pub async fn announce(self: &Arc<Self>, info_hash: &[u8], port: u16) -> Result<Vec<SocketAddrV4>> {
+        let info_hash = to_id(info_hash)?;
+        let (peers, closest) = self.lookup(info_hash, true).await;
+        let mut announcements = JoinSet::new();
+        for (node, token) in closest {
+            let Some(token) = token else {
+                continue;
+            };
+            let dht = self.clone();
+            announcements.spawn(async move { dht.announce_peer(node.addr, &info_hash, port, token).await });
+        }
+        let announced = announcements.join_all().await.into_iter().filter(Result::is_ok).count();
+        if announced == 0 {
+            eprintln!("No DHT node accepted the announce");
+        }
+        Ok(peers.into_iter().collect())
+    }
+
+    // Iterative lookup converging on the `K` nodes closest to `target`, asking
+    // `ALPHA` nodes at a time. With `get_peers` the nodes are asked for peers of
+    // `target` as an info hash, and answer with a token to announce with.
+    async fn lookup(self: &Arc<Self>, target: NodeId, get_peers: bool) -> (HashSet<SocketAddrV4>, Vec<(NodeInfo, Option<Vec<u8>>)>) {
+        let mut candidates: BTreeMap<NodeId, NodeInfo> = self.table.lock().unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responded: BTreeMap<NodeId, (NodeInfo, Option<Vec<u8>>)> = BTreeMap::new();
+        let mut peers = HashSet::new();
+
+        loop {
+            let batch: Vec<(NodeId, NodeInfo)> = candidates.iter()
+                .filter(|(_, node)| !queried.contains(&node.addr))
+                .take(ALPHA)
+                .map(|(distance, node)| (*distance, *node))
+                .collect();
+            // done once the closest unasked node is farther than K answering ones
+            let settled = responded.len() >= K && batch.first().is_none_or(|(distance, _)| {
+                responded.keys().nth(K - 1).is_some_and(|kth_distance| kth_distance < distance)
+            });
+            if batch.is_empty() || settled {
+                break;
+            }
+
+            let mut queries = JoinSet::new();
+            for (_, node) in batch {
+                queried.insert(node.addr);
+                let dht = self.clone();
+                queries.spawn(async move {
+                    let result = match get_peers {
+                        true => dht.get_peers_from(node.addr, &target).await,
+                        false => dht.find_node(node.addr, &target).await
+                            .map(|nodes| GetPeers{ token: None, peers: Vec::new(), nodes }),
+                    };
+                    (node, result)
+                });
+            }
+            while let Some(joined) = queries.join_next().await {
+                let Ok((node, Ok(result))) = joined else {
+                    continue;
+                };
+                responded.insert(distance(&node.id, &target), (node, result.token));
+                peers.extend(result.peers);
+                for found in result.nodes {
+                    if found.id != self.id {
+                        candidates.insert(distance(&found.id, &target), found);
+                    }
+                }
+            }
+        }
+        (peers, responded.into_values().take(K).collect())
+    }
+
+    pub async fn ping(&self, addr: SocketAddrV4) -> Result<NodeId> {
+        let values = self.query(addr, "ping", Arguments::default()).await?;
+        to_id(&values.id)
+    }
+
+    pub async fn find_node(&self, addr: SocketAddrV4, target: &NodeId) -> Result<Vec<NodeInfo>> {
+        let arguments = Arguments{ target: Some(ByteBuf::from(target.to_vec())), ..Default::default() };
+        let values = self.query(addr, "find_node", arguments).await?;
+        Ok(values.nodes.map(|nodes| decode_nodes(&nodes)).unwrap_or_default())
+    }
+
+    pub async fn get_peers_from(&self, addr: SocketAddrV4, info_hash: &NodeId) -> Result<GetPeers> {
+        let arguments = Arguments{ info_hash: Some(ByteBuf::from(info_hash.to_vec())), ..Default::default() };
+        let values = self.query(addr, "get_peers", arguments).await?;
+        Ok(GetPeers{
+            token: values.token.map(ByteBuf::into_vec),
+            peers: values.values.unwrap_or_default().iter().flat_map(|value| tracker::decode_compact_peers(value)).collect(),
+            nodes: values.nodes.map(|nodes| decode_nodes(&nodes)).unwrap_or_default(),
+        })
+    }
+
+    pub async fn announce_peer(&self, addr: SocketAddrV4, info_hash: &NodeId, port: u16, token: Vec<u8>) -> Result<()> {
+        let arguments = Arguments{
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            port: Some(port),
+            token: Some(ByteBuf::from(token)),
+            ..Default::default()
+        };
+        self.query(addr, "announce_peer", arguments).await?;
+        Ok(())
+    }
+
+    // Send a query and wait for its answer. Nodes that do not answer in time are
+    // marked as failing in the routing table.
+    async fn query(&self, addr: SocketAddrV4, method: &str, mut arguments: Arguments) -> Result<Values> {
+        arguments.id = ByteBuf::from(self.id.to_vec());
+        let transaction = {
+            let mut next_transaction = self.next_transaction.lock().unwrap();
+            *next_transaction = next_transaction.wrapping_add(1);
+            *next_transaction
+        };
+        let message = Krpc{
+            t: ByteBuf::from(transaction.to_be_bytes().to_vec()),
+            y: "q".to_string(),
+            q: Some(method.to_string()),
+            a: Some(arguments),
+            ..Default::default()
+        };
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(transaction, (addr, sender));
+        if let Err(e) = self.socket.send_to(&serde_bencode::to_bytes(&message)?, addr).await {
+            self.pending.lock().unwrap().remove(&transaction);
+            return Err(e.into());
+        }
+
+        match time::timeout(QUERY_TIMEOUT, receiver).await {
+            Ok(Ok(result)) => result,
+            _ => {
+                self.pending.lock().unwrap().remove(&transaction);
+                self.table.lock().unwrap().failed(addr);
+                bail!("DHT node {} did not answer {}", addr, method);
+            },
+        }
+    }
+
+    async fn handle_packet(&self, from: SocketAddrV4, packet: &[u8]) -> Result<()> {
+        let message: Krpc = decoder::from_bencode(packet)?;
+        match message.y.as_str() {
+            "q" => {
+                let reply = match self.answer(from, &message) {
+                    Ok(values) => Krpc{ t: message.t, y: "r".to_string(), r: Some(values), ..Default::default() },
+                    Err((code, text)) => Krpc{ t: message.t, y: "e".to_string(), e: Some((code, text.to_string())), ..Default::default() },
+                };
+                self.socket.send_to(&serde_bencode::to_bytes(&reply)?, from).await?;
+            },
+            "r" | "e" => {
+                let Ok(transaction) = <[u8; 2]>::try_from(message.t.as_slice()).map(u16::from_be_bytes) else {
+                    return Ok(());
+                };
+                let mut pending = self.pending.lock().unwrap();
+                // answers from anyone but the queried node are ignored
+                if pending.get(&transaction).is_none_or(|(addr, _)| *addr != from) {
+                    return Ok(());
+                }
+                let (_, sender) = pending.remove(&transaction).unwrap();
+                drop(pending);
+                let result = match (message.r, message.e) {
+                    (Some(values), _) => {
+                        if let Ok(id) = to_id(&values.id) {
+                            self.table.lock().unwrap().insert(NodeInfo{ id, addr: from });
+                        }
+                        Ok(values)
+                    },
+                    (None, Some((code, text))) => Err(anyhow!("DHT node {} answered with error {}: {}", from, code, text)),
+                    (None, None) => Err(anyhow!("DHT node {} sent an empty answer", from)),
+                };
+                let _ = sender.send(result);
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    // the answer to a query, or a KRPC error code and message
+    fn answer(&self, from: SocketAddrV4, message: &Krpc) -> std::result::Result<Values, (i64, &'static str)> {
+        let Some(arguments) = &message.a else {
+            return Err((203, "missing arguments"));
+        };
+        let Ok(id) = to_id(&arguments.id) else {
+            return Err((203, "bad node id"));
+        };
+        self.table.lock().unwrap().insert(NodeInfo{ id, addr: from });
+
+        let mut values = Values{ id: ByteBuf::from(self.id.to_vec()), ..Default::default() };
+        match message.q.as_deref() {
+            Some("ping") => {},
+            Some("find_node") => {
+                let Some(Ok(target)) = arguments.target.as_deref().map(Vec::as_slice).map(to_id) else {
+                    return Err((203, "missing target"));
+                };
+                values.nodes = Some(ByteBuf::from(encode_nodes(&self.table.lock().unwrap().closest(&target, K))));
+            },
+            Some("get_peers") => {
+                let Some(Ok(info_hash)) = arguments.info_hash.as_deref().map(Vec::as_slice).map(to_id) else {
+                    return Err((203, "missing info_hash"));
+                };
+                values.token = Some(ByteBuf::from(self.token(from.ip(), false)));
+                values.nodes = Some(ByteBuf::from(encode_nodes(&self.table.lock().unwrap().closest(&info_hash, K))));
+                let mut peers = self.peers.lock().unwrap();
+                if let Some(stored) = peers.get_mut(&info_hash) {
+                    stored.retain(|_, announced| announced.elapsed() < PEER_TTL);
+                    let found: Vec<ByteBuf> = stored.keys()
+                        .take(MAX_VALUES)
+                        .map(|peer| ByteBuf::from(tracker::encode_compact_peers([peer])))
+                        .collect();
+                    if !found.is_empty() {
+                        values.values = Some(found);
+                    }
+                }
+            },
+            Some("announce_peer") => {
+                let Some(Ok(info_hash)) = arguments.info_hash.as_deref().map(Vec::as_slice).map(to_id) else {
+                    return Err((203, "missing info_hash"));
+                };
+                let token = arguments.token.as_deref().map(Vec::as_slice).unwrap_or_default();
+                if token != self.token(from.ip(), false) && token != self.token(from.ip(), true) {
+                    return Err((203, "bad token"));
+                }
+                let port = match (arguments.implied_port, arguments.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return Err((203, "missing port")),
+                };
+                let mut peers = self.peers.lock().unwrap();
+                if !peers.contains_key(&info_hash) && peers.len() >= MAX_INFO_HASHES {
+                    return Ok(values);
+                }
+                let stored = peers.entry(info_hash).or_default();
+                if stored.len() < MAX_PEERS_PER_HASH || stored.contains_key(&SocketAddrV4::new(*from.ip(), port)) {
+                    stored.insert(SocketAddrV4::new(*from.ip(), port), Instant::now());
+                }
+            },
+            _ => return Err((204, "method unknown")),
+        }
+        Ok(values)
+    }
+
+    // Tokens are a hash of the querying ip and a secret that changes every
+    // `TOKEN_ROTATION`, the previous secret's tokens are accepted too. Expired
+    // peers are swept out on every rotation.
+    fn token(&self, ip: &Ipv4Addr, previous: bool) -> Vec<u8> {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated.elapsed() >= TOKEN_ROTATION {
+            secrets.previous = secrets.current;
+            secrets.current = random_id();
+            secrets.rotated = Instant::now();
+            self.expire_peers();
+        }
+        let mut data = match previous {
+            true => secrets.previous.to_vec(),
+            false => secrets.current.to_vec(),
+        };
+        data.extend_from_slice(&ip.octets());
+        encoder::encode_sha1(&data).unwrap_or_default()
+    }
+
+    // forget peers announced more than `PEER_TTL` ago, and hashes left without any
+    fn expire_peers(&self) {
+        self.peers.lock().unwrap().retain(|_, stored| {
+            stored.retain(|_, announced| announced.elapsed() < PEER_TTL);
+            !stored.is_empty()
+        });
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            receiver.abort();
+        }
+    }
+}
+
+// answer queries and hand out responses until the node is dropped
+async fn receive(socket: Arc<UdpSocket>, dht: Weak<Dht>) {
+    let mut buffer = vec![0u8; MAX_PACKET];
+    loop {
+        let (length, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("DHT socket error: {}", e);
+                if dht.strong_count() == 0 {
+                    return;
+                }
+                time::sleep(utils::SOCKET_ERROR_BACKOFF).await;
+                continue;
+            },
+        };
+        let Some(dht) = dht.upgrade() else {
+            return;
+        };
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+        // malformed packets are dropped, whoever sent them times out
+        let _ = dht.handle_packet(from, &buffer[..length]).await;
+    }
+}
+
+async fn receive_shared(mut packets: mpsc::UnboundedReceiver<(Vec<u8>, SocketAddrV4)>, dht: Weak<Dht>) {
+    while let Some((packet, from)) = packets.recv().await {
+        let Some(dht) = dht.upgrade() else {
+            return;
+        };
+        let _ = dht.handle_packet(from, &packet).await;
+    }
+}
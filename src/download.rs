@@ -0,0 +1,770 @@
+// download.rs
+
+use anyhow::{bail, Result};
+use tokio::fs::{self, File};
+use tokio::io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::signal;
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{self, Duration};
+
+use std::io::SeekFrom;
+use std::collections::{BTreeSet, HashSet};
+use std::net::SocketAddrV4;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use crate::bitfield::Bitfield;
+use crate::choker::DEFAULT_UPLOAD_SLOTS;
+use crate::dht::{self, Dht};
+use crate::extension::ExtensionRegistry;
+use crate::handshake::HandshakeError;
+use crate::lsd::{self, Lsd};
+use crate::message::{MessageLimits, ProtocolError};
+use crate::mse::EncryptionPolicy;
+use crate::peer::{BlockReceived, PeerConnection, PeerTimeouts};
+use crate::pex::{self, PexListing, PexSwarm};
+use crate::picker::{PiecePicker, Priority};
+use crate::resume::{Progress, ResumeFile};
+use crate::storage::{FileStorage, Storage};
+use crate::torrent::TorrentInfo;
+use crate::tracker;
+use crate::transport::{SocketTransport, Transport};
+use crate::upload::Uploader;
+use crate::verify::{self, PieceStatus};
+
+// how often the fast-resume file is rewritten while downloading
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// a peer is given up after this many pieces failed in a row
+const MAX_PEER_FAILURES: u32 = 3;
+
+// received blocks a connection may fall behind on before rechecking progress
+const BLOCK_ANNOUNCEMENTS: usize = 256;
+
+// peers downloaded from at once, further peers from PEX or the DHT are left out
+const MAX_PEERS: usize = 50;
+
+#[derive(Clone, Debug, Default)]
+pub struct DownloadOptions {
+    // one priority per file, None fetches every file at normal priority
+    pub file_priorities: Option<Vec<Priority>>,
+    // fetch pieces roughly in order, for consuming the data while it downloads
+    pub sequential: bool,
+    // fetch the first and last piece of every file first
+    pub first_last: bool,
+    // accept peers on this address and upload to them while downloading
+    pub listen: Option<SocketAddrV4>,
+    // peers uploaded to at a time besides the optimistic unchoke, None for the default
+    pub upload_slots: Option<usize>,
+    // find peers through this DHT node and announce to it, besides the tracker
+    pub dht: Option<Arc<Dht>>,
+    // find peers on the local network and announce there, keeps waiting for them
+    // while no other peer is left
+    pub lsd: Option<Arc<Lsd>>,
+    // which peer connections, ours and incoming ones, are encrypted
+    pub encryption: EncryptionPolicy,
+    // how peers are connected to and accepted, TCP only if None
+    pub transport: Option<Arc<dyn Transport>>,
+    // largest messages peers may send us
+    pub message_limits: MessageLimits,
+    // how long peers get to connect, handshake, answer requests and say anything
+    pub peer_timeouts: PeerTimeouts,
+}
+
+impl DownloadOptions {
+    pub fn transport(&self) -> Arc<dyn Transport> {
+        self.transport.clone().unwrap_or_else(|| Arc::new(SocketTransport::default()))
+    }
+
+    // there has to be a priority for every file of the torrent, no more and no less
+    fn check(&self, info: &TorrentInfo) -> Result<()> {
+        let file_num = info.get_file_slots(Path::new("")).len();
+        match &self.file_priorities {
+            Some(file_priorities) if file_priorities.len() != file_num => {
+                bail!("{} file priorities for a torrent of {} files", file_priorities.len(), file_num)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn picker(&self, info: &TorrentInfo) -> Result<PiecePicker> {
+        self.check(info)?;
+        let mut picker = match &self.file_priorities {
+            Some(file_priorities) => PiecePicker::from_file_priorities(info, file_priorities),
+            None => PiecePicker::new(info.get_piece_num()),
+        };
+        picker.set_sequential(self.sequential);
+        if self.first_last {
+            picker.prioritize_file_ends(info);
+        }
+        Ok(picker)
+    }
+}
+
+// State shared by the peer tasks of one download
+struct DownloadState {
+    progress: Mutex<Progress>,
+    picker: Mutex<PiecePicker>,
+    changed: Notify,        // a piece finished or went back to the picker
+    finished: AtomicBool,   // no peer task is left
+    blocks: broadcast::Sender<BlockReceived>,
+}
+
+impl DownloadState {
+    fn new(progress: Progress, picker: PiecePicker) -> Self {
+        Self{
+            progress: Mutex::new(progress),
+            picker: Mutex::new(picker),
+            changed: Notify::new(),
+            finished: AtomicBool::new(false),
+            blocks: broadcast::channel(BLOCK_ANNOUNCEMENTS).0,
+        }
+    }
+
+    // A piece the peer has, or in endgame one another peer is already fetching
+    fn pick(&self, available: &Bitfield, suggested: &BTreeSet<u32>) -> Option<u32> {
+        let progress = self.progress.lock().unwrap();
+        let mut picker = self.picker.lock().unwrap();
+        picker.pick(&progress.have, available, suggested).or_else(|| picker.pick_endgame(&progress.have, available))
+    }
+
+    fn release(&self, piece_index: u32) {
+        self.picker.lock().unwrap().release(piece_index);
+        self.changed.notify_waiters();
+    }
+
+    fn is_done(&self) -> bool {
+        let progress = self.progress.lock().unwrap();
+        self.picker.lock().unwrap().missing_pieces(&progress.have).is_empty()
+    }
+}
+
+// A download running in the background. Consumers of the data can wait for
+// pieces and move the ones they need to the front of the queue.
+pub struct DownloadHandle {
+    info: TorrentInfo,
+    storage: Arc<dyn Storage>,
+    state: Arc<DownloadState>,
+    task: Mutex<Option<JoinHandle<Result<()>>>>,
+}
+
+impl DownloadHandle {
+    pub fn info(&self) -> &TorrentInfo {
+        &self.info
+    }
+
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    pub fn has_piece(&self, piece_index: u32) -> bool {
+        self.state.progress.lock().unwrap().have.get(piece_index as usize)
+    }
+
+    // fetch the piece before any other, in earliest-deadline order
+    pub fn set_deadline(&self, piece_index: u32, deadline: Instant) {
+        if (piece_index as usize) < self.info.get_piece_num() {
+            self.state.picker.lock().unwrap().set_deadline(piece_index, deadline);
+            self.state.changed.notify_waiters();
+        }
+    }
+
+    // drop deadlines set earlier, for a reader that seeked away or went away
+    pub fn clear_deadlines(&self, pieces: impl IntoIterator<Item = u32>) {
+        let mut picker = self.state.picker.lock().unwrap();
+        for piece_index in pieces {
+            if (piece_index as usize) < self.info.get_piece_num() {
+                picker.clear_deadline(piece_index);
+            }
+        }
+    }
+
+    // Wait until the piece is verified and in storage
+    pub async fn wait_piece(&self, piece_index: u32) -> Result<()> {
+        loop {
+            let changed = self.state.changed.notified();
+            if self.has_piece(piece_index) {
+                return Ok(());
+            }
+            if self.state.finished.load(Ordering::Acquire) {
+                bail!("download stopped without piece {}", piece_index);
+            }
+            changed.await;
+        }
+    }
+
+    // Wait for the download to end and return its outcome
+    pub async fn finish(&self) -> Result<()> {
+        let task = self.task.lock().unwrap().take();
+        match task {
+            Some(task) => task.await?,
+            None => bail!("download already finished"),
+        }
+    }
+}
+
+// Download the torrent into any storage backend
+pub async fn download(
+    info: &TorrentInfo,
+    announce: &str,
+    storage: Arc<dyn Storage>,
+    options: &DownloadOptions
+) -> Result<()> {
+    start(info, announce, storage, options)?.finish().await
+}
+
+// Start downloading into any storage backend
+pub fn start(
+    info: &TorrentInfo,
+    announce: &str,
+    storage: Arc<dyn Storage>,
+    options: &DownloadOptions
+) -> Result<DownloadHandle> {
+    let progress = Progress::new(info.get_piece_num());
+    Ok(spawn(info, announce, storage, progress, options.picker(info)?, options, None))
+}
+
+// Download to `file_path`, the file itself for a single-file torrent and the root
+// directory for a multi-file one. Pieces already there are kept, and progress is
+// saved to a fast-resume file next to the output periodically, on Ctrl-C and at
+// the end, so an interrupted download restarts without rehashing. Skipped files
+// are never created, their parts in shared pieces go to a `.parts` file instead.
+pub async fn download_file(
+    info: &TorrentInfo,
+    announce: &str,
+    file_path: &str,
+    options: &DownloadOptions
+) -> Result<()> {
+    start_file(info, announce, file_path, options).await?.finish().await
+}
+
+// Start downloading to `file_path`, see `download_file`
+pub async fn start_file(
+    info: &TorrentInfo,
+    announce: &str,
+    file_path: &str,
+    options: &DownloadOptions
+) -> Result<DownloadHandle> {
+    options.check(info)?;
+    let mut storage = FileStorage::new(info, Path::new(file_path));
+    if let Some(file_priorities) = &options.file_priorities {
+        let skipped = file_priorities.iter().map(|&priority| priority == Priority::Skip).collect();
+        storage = storage.with_skipped_files(skipped, PathBuf::from(format!("{file_path}.parts")));
+    }
+    let storage = Arc::new(storage);
+    let resume_file = ResumeFile::new(info, file_path)?;
+    let mut progress = prepare_output(info, storage.clone(), &resume_file).await?;
+    forget_absent_files(info, storage.as_ref(), &mut progress, options);
+    Ok(spawn(info, announce, storage, progress, options.picker(info)?, options, Some(resume_file)))
+}
+
+// Download only the pieces covering `range` of the payload and write just that
+// slice to `out_path`. The covering pieces are kept in `<out_path>.part` meanwhile.
+// Which pieces to fetch is up to the range, `options` only say how.
+pub async fn download_range(
+    info: &TorrentInfo,
+    announce: &str,
+    range: Range<u64>,
+    out_path: &Path,
+    options: &DownloadOptions
+) -> Result<()> {
+    if range.is_empty() || range.end > info.total_length() {
+        bail!("range {}..{} is empty or past the end of the {} byte payload", range.start, range.end, info.total_length());
+    }
+    let piece_length = info.piece_length as u64;
+    let pieces = (range.start / piece_length) as u32..=((range.end - 1) / piece_length) as u32;
+
+    let mut picker = PiecePicker::new(info.get_piece_num());
+    for piece_index in 0..info.get_piece_num() as u32 {
+        if !pieces.contains(&piece_index) {
+            picker.set_priority(piece_index, Priority::Skip);
+        }
+    }
+    let mut part_path = out_path.as_os_str().to_owned();
+    part_path.push(".part");
+    let part_path = PathBuf::from(part_path);
+    let storage = Arc::new(FileStorage::for_pieces(info, pieces.clone(), &part_path));
+    let progress = Progress::new(info.get_piece_num());
+    spawn(info, announce, storage, progress, picker, options, None).finish().await?;
+
+    // cut the requested slice out of the covering pieces
+    let mut part_file = File::open(&part_path).await?;
+    part_file.seek(SeekFrom::Start(range.start - *pieces.start() as u64 * piece_length)).await?;
+    let mut out_file = File::create(out_path).await?;
+    io::copy(&mut part_file.take(range.end - range.start), &mut out_file).await?;
+    out_file.flush().await?;
+    fs::remove_file(&part_path).await?;
+    Ok(())
+}
+
+fn spawn(
+    info: &TorrentInfo,
+    announce: &str,
+    storage: Arc<dyn Storage>,
+    progress: Progress,
+    picker: PiecePicker,
+    options: &DownloadOptions,
+    resume_file: Option<ResumeFile>
+) -> DownloadHandle {
+    let state = Arc::new(DownloadState::new(progress, picker));
+    let task = {
+        let info_clone = info.clone();
+        let announce_clone = announce.to_string();
+        let storage_clone = storage.clone();
+        let state_clone = state.clone();
+        let options_clone = options.clone();
+        tokio::spawn(async move {
+            let result = run(&info_clone, &announce_clone, storage_clone, &state_clone, &options_clone, resume_file).await;
+            state_clone.finished.store(true, Ordering::Release);
+            state_clone.changed.notify_waiters();
+            result
+        })
+    };
+    DownloadHandle{ info: info.clone(), storage, state, task: Mutex::new(Some(task)) }
+}
+
+// Fetch the wanted pieces missing from `progress`, one task per peer pulling pieces
+// from the picker. An empty `announce` skips the tracker, the DHT has to find peers.
+async fn run(
+    info: &TorrentInfo,
+    announce: &str,
+    storage: Arc<dyn Storage>,
+    state: &Arc<DownloadState>,
+    options: &DownloadOptions,
+    resume_file: Option<ResumeFile>
+) -> Result<()> {
+    // announce, falling back to the peers remembered from the last run
+    let info_hash = info.get_hash()?;
+    let (tracker_id, left) = {
+        let progress = state.progress.lock().unwrap();
+        let left = info.total_length().saturating_sub(progress.have.count_ones() as u64 * info.piece_length as u64);
+        (progress.tracker_ids.get(announce).cloned(), left)
+    };
+    let port = options.listen.map_or(tracker::DEFAULT_PORT, |addr| addr.port());
+    let announce_result = match announce.is_empty() {
+        true => None,
+        false => Some(tracker::announce(announce, &info_hash, port, left, tracker_id.as_deref()).await),
+    };
+    let dht_found = match &options.dht {
+        Some(dht) => dht_peers(dht, &info_hash, options.listen).await?,
+        None => Vec::new(),
+    };
+    let peers: Vec<SocketAddrV4> = {
+        let mut progress = state.progress.lock().unwrap();
+        match announce_result {
+            Some(Ok(response)) => {
+                progress.peers.extend(response.peers);
+                if let Some(tracker_id) = response.tracker_id {
+                    progress.tracker_ids.insert(announce.to_string(), tracker_id);
+                }
+            },
+            Some(Err(e)) if !progress.peers.is_empty() || options.dht.is_some() || options.lsd.is_some() => eprintln!("Tracker announce failed: {}", e),
+            Some(Err(e)) => return Err(e),
+            None => {},
+        }
+        progress.peers.extend(dht_found);
+        progress.peers.iter().copied().collect()
+    };
+
+    let resume_file = resume_file.map(Arc::new);
+    if state.is_done() {
+        println!("All {} wanted pieces already downloaded", state.picker.lock().unwrap().wanted().count_ones());
+        return save_resume(storage.as_ref(), resume_file.as_deref(), &state.progress);
+    }
+    if peers.is_empty() && options.lsd.is_none() {
+        bail!("no peers to download from");
+    }
+
+    // peers found after the start, through PEX, the DHT or the local network
+    let (discovered_sender, mut discovered) = mpsc::unbounded_channel();
+    let lsd_announcer = options.lsd.clone().map(|lsd| {
+        lsd.register(&info_hash, discovered_sender.clone());
+        let info_hash = info_hash.clone();
+        let listen = options.listen;
+        tokio::spawn(async move {
+            let Some(addr) = listen else {
+                return;
+            };
+            let mut interval = time::interval(lsd::ANNOUNCE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = lsd.announce(&info_hash, addr.port()).await {
+                    eprintln!("Local peer announce failed: {}", e);
+                }
+            }
+        })
+    });
+    let dht_announcer = options.dht.clone().map(|dht| {
+        let discovered_sender = discovered_sender.clone();
+        let info_hash = info_hash.clone();
+        let listen = options.listen;
+        tokio::spawn(async move {
+            let mut interval = time::interval(dht::ANNOUNCE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                dht.bootstrap(&[]).await;
+                match dht_peers(&dht, &info_hash, listen).await {
+                    Ok(peers) => for peer_addr in peers {
+                        let _ = discovered_sender.send(peer_addr);
+                    },
+                    Err(e) => eprintln!("DHT lookup failed: {}", e),
+                }
+            }
+        })
+    });
+    let pex = PexSwarm::new(discovered_sender);
+
+    // serve the pieces we have to peers that connect to us meanwhile
+    let transport = options.transport();
+    let uploader = match options.listen {
+        Some(addr) => {
+            transport.listen(addr).await?;
+            let have = state.progress.lock().unwrap().have.clone();
+            let slots = options.upload_slots.unwrap_or(DEFAULT_UPLOAD_SLOTS);
+            let uploader = Arc::new(Uploader::new(info, storage.clone(), have, slots, pex.clone(), options.encryption, options.message_limits, options.peer_timeouts)?);
+            Some((uploader.clone(), tokio::spawn(uploader.serve(transport.clone()))))
+        },
+        None => None,
+    };
+
+    // one task per peer, more are started for peers found later
+    let listen_port = options.listen.map(|addr| addr.port());
+    let encryption = options.encryption;
+    let message_limits = options.message_limits;
+    let peer_timeouts = options.peer_timeouts;
+    let mut tasks = JoinSet::new();
+    let mut known = HashSet::new();
+    let spawn_peer = |tasks: &mut JoinSet<()>, peer_addr: SocketAddrV4| {
+        let info_clone = info.clone();
+        let storage_clone = storage.clone();
+        let state_clone = state.clone();
+        let uploader_clone = uploader.as_ref().map(|(uploader, _)| uploader.clone());
+        let pex_clone = pex.clone();
+        let transport_clone = transport.clone();
+        tasks.spawn(async move {
+            let context = PeerContext{
+                uploader: uploader_clone.as_deref(),
+                pex: &pex_clone,
+                listen_port,
+                encryption,
+                message_limits,
+                peer_timeouts,
+                transport: transport_clone.as_ref(),
+            };
+            peer_task(peer_addr, &info_clone, storage_clone.as_ref(), &state_clone, context).await
+        });
+    };
+    for peer_addr in peers {
+        known.insert(peer_addr);
+        spawn_peer(&mut tasks, peer_addr);
+    }
+
+    // save progress periodically while the tasks run
+    let saver = {
+        let resume_file_clone = resume_file.clone();
+        let storage_clone = storage.clone();
+        let state_clone = state.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(RESUME_SAVE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = save_resume(storage_clone.as_ref(), resume_file_clone.as_deref(), &state_clone.progress) {
+                    eprintln!("Failed to save fast-resume file: {}", e);
+                }
+            }
+        })
+    };
+
+    // wait tasks to finish, or save and stop on Ctrl-C
+    let wait_tasks = async {
+        loop {
+            if tasks.is_empty() && (options.lsd.is_none() || state.is_done()) {
+                return Ok::<(), anyhow::Error>(());
+            }
+            tokio::select! {
+                Some(joined) = tasks.join_next() => joined?,
+                Some(peer_addr) = discovered.recv() => {
+                    // dropped by PEX since it was found, the peer left the swarm
+                    if pex.is_dropped(peer_addr) {
+                        continue;
+                    }
+                    if tasks.len() < MAX_PEERS && !state.is_done() && known.insert(peer_addr) {
+                        println!("Found peer {}", peer_addr);
+                        state.progress.lock().unwrap().peers.insert(peer_addr);
+                        spawn_peer(&mut tasks, peer_addr);
+                    }
+                },
+            }
+        }
+    };
+    tokio::select! {
+        result = wait_tasks => result?,
+        _ = signal::ctrl_c() => {
+            saver.abort();
+            if let Some((_, server)) = &uploader {
+                server.abort();
+            }
+            if let Some(dht_announcer) = &dht_announcer {
+                dht_announcer.abort();
+            }
+            stop_lsd(options.lsd.as_deref(), &info_hash, lsd_announcer.as_ref());
+            save_resume(storage.as_ref(), resume_file.as_deref(), &state.progress)?;
+            bail!("download interrupted");
+        }
+    }
+    saver.abort();
+    if let Some((_, server)) = &uploader {
+        server.abort();
+    }
+    if let Some(dht_announcer) = &dht_announcer {
+        dht_announcer.abort();
+    }
+    stop_lsd(options.lsd.as_deref(), &info_hash, lsd_announcer.as_ref());
+    save_resume(storage.as_ref(), resume_file.as_deref(), &state.progress)?;
+
+    let missing_num = {
+        let progress = state.progress.lock().unwrap();
+        state.picker.lock().unwrap().missing_pieces(&progress.have).len()
+    };
+    if missing_num > 0 {
+        bail!("{} pieces could not be downloaded from any peer", missing_num);
+    }
+    Ok(())
+}
+
+// what a peer task shares with the rest of the download
+struct PeerContext<'a> {
+    uploader: Option<&'a Uploader>,
+    pex: &'a Arc<PexSwarm>,
+    listen_port: Option<u16>,
+    encryption: EncryptionPolicy,
+    message_limits: MessageLimits,
+    peer_timeouts: PeerTimeouts,
+    transport: &'a dyn Transport,
+}
+
+// Download pieces from one peer as the picker hands them out, until nothing is
+// left or the peer keeps failing. Failed pieces go back to the picker for others.
+async fn peer_task(
+    peer_addr: SocketAddrV4,
+    info: &TorrentInfo,
+    storage: &dyn Storage,
+    state: &DownloadState,
+    context: PeerContext<'_>
+) {
+    // peers that learn our listen port from the extension handshake can connect back
+    let new_extensions = || {
+        let mut extensions = ExtensionRegistry::new();
+        if let Some(port) = context.listen_port {
+            extensions.set_listen_port(port);
+        }
+        extensions.register(pex::EXTENSION_NAME, Box::new(context.pex.handler(peer_addr, true)));
+        extensions
+    };
+    // the peer is listed to PEX peers only while a connection to it is up
+    let mut connection: Option<(PeerConnection, PexListing)> = None;
+    let mut failures = 0;
+    while failures < MAX_PEER_FAILURES {
+        let changed = state.changed.notified();
+        if state.is_done() {
+            return;
+        }
+        // flags other peers listed this one with: a peer preferring encryption is
+        // not tried again in plaintext, one speaking uTP is tried over it first
+        let hints = context.pex.learned_flags(peer_addr);
+        let encryption = match context.encryption {
+            EncryptionPolicy::Enabled if hints & pex::FLAG_ENCRYPTION != 0 => EncryptionPolicy::Forced,
+            encryption => encryption,
+        };
+        let peer = match &mut connection {
+            Some((peer, _)) => peer,
+            None => match PeerConnection::connect(
+                peer_addr,
+                info,
+                new_extensions(),
+                encryption,
+                context.message_limits,
+                context.peer_timeouts,
+                context.transport,
+                hints & pex::FLAG_UTP != 0
+            ).await {
+                Ok(peer) => {
+                    let listing = context.pex.mark_connected(peer_addr);
+                    if peer.encrypted {
+                        context.pex.add_flags(peer_addr, pex::FLAG_ENCRYPTION);
+                    }
+                    if peer.utp {
+                        context.pex.add_flags(peer_addr, pex::FLAG_UTP);
+                    }
+                    &mut connection.insert((peer, listing)).0
+                },
+                Err(e) => {
+                    eprintln!("Failed to connect to {}: {}", peer_addr, e);
+                    failures += failure_weight(&e);
+                    continue;
+                },
+            },
+        };
+
+        if peer.has.count_ones() == peer.has.len() {
+            context.pex.add_flags(peer_addr, pex::FLAG_SEED);
+        }
+
+        // while the peer chokes us only its allowed fast pieces can be fetched
+        let available = match peer.is_choked() {
+            true => {
+                let mut allowed = Bitfield::new(info.get_piece_num());
+                for &piece_index in &peer.allowed_fast {
+                    allowed.set(piece_index as usize, peer.has.get(piece_index as usize));
+                }
+                allowed
+            },
+            false => peer.has.clone(),
+        };
+        let piece_index = match state.pick(&available, &peer.suggested) {
+            Some(piece_index) => piece_index,
+            None if state.is_done() => return,
+            None if peer.is_choked() => {
+                tokio::select! {
+                    _ = changed => {},
+                    result = peer.wait_unchoke() => if let Err(e) = result {
+                        eprintln!("Giving up on {}: {}", peer_addr, e);
+                        connection = None;
+                        failures += failure_weight(&e);
+                    },
+                }
+                continue;
+            },
+            None => {
+                // everything left is in flight elsewhere, outside the window or not
+                // on this peer, keep up with its announcements meanwhile
+                tokio::select! {
+                    _ = changed => {},
+                    message = peer.next_message() => if let Err(e) = message {
+                        eprintln!("Lost connection to {}: {}", peer_addr, e);
+                        connection = None;
+                        failures += failure_weight(&e);
+                    },
+                }
+                continue;
+            },
+        };
+
+        match peer.download_piece(info, piece_index, storage, &state.progress, &state.blocks).await {
+            Ok(bytes) => {
+                println!("Piece {} downloaded successfully", piece_index);
+                if let Some(uploader) = context.uploader {
+                    uploader.piece_downloaded(&peer.peer_id, piece_index, bytes);
+                }
+                failures = 0;
+            },
+            Err(e) => {
+                eprintln!("Failed to download piece {} from {}: {}", piece_index, peer_addr, e);
+                // the connection may be mid-message, start over with a new one
+                connection = None;
+                failures += failure_weight(&e);
+            },
+        }
+        state.release(piece_index);
+    }
+}
+
+// a peer breaking the protocol, or in another swarm, is given up on right away
+fn failure_weight(e: &anyhow::Error) -> u32 {
+    match e.is::<ProtocolError>() || e.is::<HandshakeError>() {
+        true => MAX_PEER_FAILURES,
+        false => 1,
+    }
+}
+
+// Peers from the DHT, announcing ourselves only if peers can connect to us
+async fn dht_peers(dht: &Arc<Dht>, info_hash: &[u8], listen: Option<SocketAddrV4>) -> Result<Vec<SocketAddrV4>> {
+    match listen {
+        Some(addr) => dht.announce(info_hash, addr.port()).await,
+        None => dht.get_peers(info_hash).await,
+    }
+}
+
+fn stop_lsd(lsd: Option<&Lsd>, info_hash: &[u8], announcer: Option<&JoinHandle<()>>) {
+    if let Some(lsd) = lsd {
+        lsd.unregister(info_hash);
+    }
+    if let Some(announcer) = announcer {
+        announcer.abort();
+    }
+}
+
+// The storage is flushed first, so no piece is recorded before its data is on disk
+fn save_resume(storage: &dyn Storage, resume_file: Option<&ResumeFile>, progress: &Mutex<Progress>) -> Result<()> {
+    storage.flush()?;
+    match resume_file {
+        Some(resume_file) => resume_file.save(&progress.lock().unwrap()),
+        None => Ok(()),
+    }
+}
+
+// A wanted file may be absent although pieces touching it are recorded as done,
+// when it was skipped last time and only its shared pieces were kept in the
+// partfile. Those pieces are fetched again so the file gets its data.
+fn forget_absent_files(info: &TorrentInfo, storage: &dyn Storage, progress: &mut Progress, options: &DownloadOptions) {
+    let piece_length = info.piece_length as u64;
+    for (file_index, slot) in info.get_file_slots(Path::new("")).iter().enumerate() {
+        let skipped = options.file_priorities
+            .as_ref()
+            .is_some_and(|file_priorities| file_priorities[file_index] == Priority::Skip);
+        if skipped || slot.length == 0 || storage.has_file(file_index) {
+            continue;
+        }
+        let first_piece = slot.offset / piece_length;
+        let last_piece = (slot.offset + slot.length - 1) / piece_length;
+        for piece_index in first_piece..=last_piece {
+            progress.have.set(piece_index as usize, false);
+        }
+    }
+}
+
+// Work out what the output already holds: from a matching fast-resume file if
+// there is one, by rechecking the data otherwise.
+async fn prepare_output(
+    info: &TorrentInfo,
+    storage: Arc<dyn Storage>,
+    resume_file: &ResumeFile
+) -> Result<Progress> {
+    let existed = (0..resume_file.slots.len()).any(|file_index| storage.has_file(file_index));
+    let fast_resume = match resume_file.load() {
+        Ok(fast_resume) => fast_resume.filter(|fast_resume| fast_resume.info_hash == resume_file.info_hash),
+        Err(e) => {
+            eprintln!("Ignoring fast-resume file: {}", e);
+            None
+        },
+    };
+
+    let mut progress = Progress::new(info.get_piece_num());
+    if let Some(fast_resume) = fast_resume {
+        if existed && fast_resume.matches(&resume_file.info_hash, &resume_file.slots) {
+            let progress = fast_resume.into_progress(info);
+            println!("Fast resume: {} of {} pieces already downloaded", progress.have.count_ones(), info.get_piece_num());
+            return Ok(progress);
+        }
+        // stale for the data, but the swarm it remembers is still useful
+        let saved = fast_resume.into_progress(info);
+        progress.peers = saved.peers;
+        progress.tracker_ids = saved.tracker_ids;
+    }
+    if !existed {
+        return Ok(progress);
+    }
+
+    let report = verify::verify_storage(info, storage).await?;
+    for (piece_index, status) in report.statuses.iter().enumerate() {
+        progress.have.set(piece_index, *status == PieceStatus::Good);
+    }
+    println!("Resuming: {} of {} pieces already downloaded", progress.have.count_ones(), info.get_piece_num());
+    Ok(progress)
+}
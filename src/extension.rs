@@ -0,0 +1,222 @@
+// extension.rs
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use serde_bytes::ByteBuf;
+use serde_bencode;
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::decoder;
+use crate::message::Message;
+
+// client name and version sent as `v`
+pub const CLIENT_VERSION: &str = concat!("rust-BitTorrent/", env!("CARGO_PKG_VERSION"));
+
+// requests we are happy to have outstanding, sent as `reqq`
+pub const REQUEST_QUEUE: u32 = 250;
+
+// extended message id reserved for the handshake itself
+pub const HANDSHAKE_ID: u8 = 0;
+
+// The extension handshake of BEP 10. Every field is optional, `m` maps the names
+// of the extensions a side supports to the message ids it wants them sent with.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtensionHandshake {
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u64>,
+}
+
+impl ExtensionHandshake {
+    pub fn parse(payload: &[u8]) -> Result<Self> {
+        decoder::from_bencode(payload)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    // the id to send an extension's messages with, if the side enabled it
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).and_then(|&id| u8::try_from(id).ok()).filter(|&id| id != HANDSHAKE_ID)
+    }
+
+    // our address as the other side sees it
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        let bytes: &[u8] = self.yourip.as_ref()?;
+        if let Ok(octets) = <[u8; 4]>::try_from(bytes) {
+            return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+        }
+        let octets = <[u8; 16]>::try_from(bytes).ok()?;
+        Some(IpAddr::V6(Ipv6Addr::from(octets)))
+    }
+}
+
+// Handles the extended messages of one extension on one connection
+pub trait ExtensionHandler: Send {
+    // the peer's extension handshake arrived, peers may send it again later
+    fn on_handshake(&mut self, _handshake: &ExtensionHandshake) -> Result<()> {
+        Ok(())
+    }
+
+    // a message for this extension, a returned payload is sent back to the peer
+    // under the same extension
+    fn on_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    // a message the extension wants to send on its own, asked whenever a message
+    // arrives on the connection
+    fn pending(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+// The extensions of one connection: ours with the ids we assigned them, and the
+// ids the peer wants for the ones it supports
+pub struct ExtensionRegistry {
+    local: Vec<(String, Option<Box<dyn ExtensionHandler>>)>, // local id is the index + 1
+    handshake: ExtensionHandshake,
+    peer_handshake: Option<ExtensionHandshake>,
+    remote: HashMap<String, u8>,
+}
+
+impl Default for ExtensionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        let handshake = ExtensionHandshake{
+            v: Some(CLIENT_VERSION.to_string()),
+            reqq: Some(REQUEST_QUEUE),
+            ..Default::default()
+        };
+        Self{ local: Vec::new(), handshake, peer_handshake: None, remote: HashMap::new() }
+    }
+
+    // Add an extension whose messages go to `handler`, returns its local id
+    pub fn register(&mut self, name: &str, handler: Box<dyn ExtensionHandler>) -> u8 {
+        self.add(name, Some(handler))
+    }
+
+    // Add an extension without a handler, its messages are left to whoever
+    // reads the connection. Returns its local id.
+    pub fn declare(&mut self, name: &str) -> u8 {
+        self.add(name, None)
+    }
+
+    fn add(&mut self, name: &str, handler: Option<Box<dyn ExtensionHandler>>) -> u8 {
+        if let Some(index) = self.local.iter().position(|(local_name, _)| local_name == name) {
+            self.local[index].1 = handler;
+            return index as u8 + 1;
+        }
+        assert!(self.local.len() < u8::MAX as usize, "too many extensions");
+        self.local.push((name.to_string(), handler));
+        let id = self.local.len() as u8;
+        self.handshake.m.insert(name.to_string(), id as i64);
+        id
+    }
+
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.handshake.p = Some(port);
+    }
+
+    pub fn set_metadata_size(&mut self, size: u64) {
+        self.handshake.metadata_size = Some(size);
+    }
+
+    // tell the peer the address we see it connecting from
+    pub fn set_your_ip(&mut self, ip: IpAddr) {
+        let bytes = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        self.handshake.yourip = Some(ByteBuf::from(bytes));
+    }
+
+    pub fn handshake(&self) -> &ExtensionHandshake {
+        &self.handshake
+    }
+
+    pub fn handshake_message(&self) -> Result<Message> {
+        Ok(Message::Extended{ id: HANDSHAKE_ID, payload: self.handshake.to_bytes()? })
+    }
+
+    // the peer's latest extension handshake, if it sent one
+    pub fn peer_handshake(&self) -> Option<&ExtensionHandshake> {
+        self.peer_handshake.as_ref()
+    }
+
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.handshake.extension_id(name)
+    }
+
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote.get(name).copied()
+    }
+
+    // A message for the peer's side of an extension, None if it does not support it
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Option<Message> {
+        Some(Message::Extended{ id: self.remote_id(name)?, payload })
+    }
+
+    // Handle an extended message from the peer. A handshake updates the remote
+    // ids, an id of 0 there disables an extension. Anything else goes to the
+    // handler of the extension with that local id, returns its reply if any.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<Option<Message>> {
+        if id == HANDSHAKE_ID {
+            let handshake = ExtensionHandshake::parse(payload)?;
+            for (name, &remote_id) in &handshake.m {
+                match u8::try_from(remote_id) {
+                    Ok(HANDSHAKE_ID) => {
+                        self.remote.remove(name);
+                    },
+                    Ok(remote_id) => {
+                        self.remote.insert(name.clone(), remote_id);
+                    },
+                    Err(_) => {},
+                }
+            }
+            for (_, handler) in self.local.iter_mut() {
+                if let Some(handler) = handler {
+                    handler.on_handshake(&handshake)?;
+                }
+            }
+            self.peer_handshake = Some(handshake);
+            return Ok(None);
+        }
+
+        let Some((name, Some(handler))) = self.local.get_mut(id as usize - 1) else {
+            return Ok(None);
+        };
+        let reply = handler.on_message(payload)?;
+        let name = name.clone();
+        Ok(reply.and_then(|reply| self.message(&name, reply)))
+    }
+
+    // Messages the handlers want to send unprompted, for extensions the peer supports
+    pub fn pending_messages(&mut self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for (name, handler) in self.local.iter_mut() {
+            let Some(payload) = handler.as_mut().and_then(|handler| handler.pending()) else {
+                continue;
+            };
+            if let Some(&id) = self.remote.get(name.as_str()) {
+                messages.push(Message::Extended{ id, payload });
+            }
+        }
+        messages
+    }
+}
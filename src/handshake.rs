@@ -0,0 +1,132 @@
+// handshake.rs
+
+use anyhow::{bail, Result};
+use thiserror::Error;
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+// pstrlen, pstr, reserved bytes, info hash and peer id
+pub const HANDSHAKE_LENGTH: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
+// A peer's handshake was not one we can talk to, the connection is dropped and
+// not tried again
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("handshake of {0} bytes")]
+    BadLength(usize),
+    #[error("protocol string of {0} bytes")]
+    BadProtocolLength(u8),
+    #[error("unknown protocol {0:?}")]
+    BadProtocol(String),
+    #[error("handshake for torrent {found}, expected {expected}")]
+    InfoHashMismatch{ found: String, expected: String },
+    #[error("peer id {}, expected {}", hex::encode(.found), hex::encode(.expected))]
+    PeerIdMismatch{ found: [u8; 20], expected: [u8; 20] },
+}
+
+// Capabilities announced in the reserved bytes: the extension protocol (BEP 10),
+// the fast extension (BEP 6) and the DHT (BEP 5)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub extension: bool,
+    pub fast: bool,
+    pub dht: bool,
+}
+
+impl Capabilities {
+    pub fn from_reserved(reserved: &[u8; 8]) -> Self {
+        Self{
+            extension: reserved[5] & 0x10 != 0,
+            fast: reserved[7] & 0x04 != 0,
+            dht: reserved[7] & 0x01 != 0,
+        }
+    }
+
+    pub fn to_reserved(&self) -> [u8; 8] {
+        let mut reserved = [0u8; 8];
+        if self.extension {
+            reserved[5] |= 0x10;
+        }
+        if self.fast {
+            reserved[7] |= 0x04;
+        }
+        if self.dht {
+            reserved[7] |= 0x01;
+        }
+        reserved
+    }
+}
+
+// The handshake opening every peer connection. Reserved bits we do not know are
+// kept as they came.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(info_hash: &[u8], peer_id: [u8; 20], capabilities: Capabilities) -> Result<Self> {
+        let Ok(info_hash) = info_hash.try_into() else {
+            bail!("info hash of {} bytes", info_hash.len());
+        };
+        Ok(Self{ reserved: capabilities.to_reserved(), info_hash, peer_id })
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_reserved(&self.reserved)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, HandshakeError> {
+        if bytes.len() != HANDSHAKE_LENGTH {
+            return Err(HandshakeError::BadLength(bytes.len()));
+        }
+        if bytes[0] as usize != PROTOCOL.len() {
+            return Err(HandshakeError::BadProtocolLength(bytes[0]));
+        }
+        if bytes[1..20] != *PROTOCOL {
+            return Err(HandshakeError::BadProtocol(String::from_utf8_lossy(&bytes[1..20]).into_owned()));
+        }
+        let mut handshake = Self{ reserved: [0u8; 8], info_hash: [0u8; 20], peer_id: [0u8; 20] };
+        handshake.reserved.copy_from_slice(&bytes[20..28]);
+        handshake.info_hash.copy_from_slice(&bytes[28..48]);
+        handshake.peer_id.copy_from_slice(&bytes[48..68]);
+        Ok(handshake)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HANDSHAKE_LENGTH);
+        bytes.push(PROTOCOL.len() as u8);
+        bytes.extend_from_slice(PROTOCOL);
+        bytes.extend_from_slice(&self.reserved);
+        bytes.extend_from_slice(&self.info_hash);
+        bytes.extend_from_slice(&self.peer_id);
+        bytes
+    }
+
+    // Read a whole handshake, however the bytes trickle in
+    pub async fn read(reader: &mut (impl AsyncRead + Unpin)) -> Result<Self> {
+        let mut bytes = [0u8; HANDSHAKE_LENGTH];
+        match reader.read_exact(&mut bytes).await {
+            Ok(_) => Ok(Self::parse(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => bail!("connection closed during the handshake"),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Check the handshake is for our torrent, and from the peer we expected if
+    // we know its id
+    pub fn validate(&self, info_hash: &[u8], peer_id: Option<&[u8; 20]>) -> Result<(), HandshakeError> {
+        if self.info_hash != info_hash {
+            return Err(HandshakeError::InfoHashMismatch{ found: hex::encode(self.info_hash), expected: hex::encode(info_hash) });
+        }
+        match peer_id {
+            Some(peer_id) if self.peer_id != *peer_id => {
+                Err(HandshakeError::PeerIdMismatch{ found: self.peer_id, expected: *peer_id })
+            },
+            _ => Ok(()),
+        }
+    }
+}
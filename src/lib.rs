@@ -0,0 +1,28 @@
+// lib.rs
+
+pub mod bitfield;
+pub mod choker;
+pub mod decoder;
+pub mod dht;
+pub mod download;
+pub mod encoder;
+pub mod extension;
+pub mod handshake;
+pub mod lsd;
+pub mod magnet;
+pub mod message;
+pub mod mse;
+pub mod peer;
+pub mod pex;
+pub mod picker;
+pub mod reader;
+pub mod resume;
+pub mod storage;
+pub mod stream;
+pub mod torrent;
+pub mod tracker;
+pub mod transport;
+pub mod upload;
+pub mod utils;
+pub mod utp;
+pub mod verify;
@@ -0,0 +1,170 @@
+// lsd.rs
+
+use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use crate::utils;
+
+// multicast group and port of BEP 14
+pub const LSD_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+
+// each torrent is announced on the LAN this often
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const MAX_PACKET: usize = 1500;
+
+// Local Service Discovery (BEP 14): announces the torrents we serve to the local
+// network by multicast and hands the peers other machines announce to the
+// downloads that registered for their info hash
+pub struct Lsd {
+    group: SocketAddrV4,
+    socket: Arc<UdpSocket>,
+    cookie: String, // sent along so our own announcements are recognised
+    torrents: Mutex<HashMap<Vec<u8>, mpsc::UnboundedSender<SocketAddrV4>>>,
+    receiver: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Lsd {
+    // Join `group`, shared with other clients on this machine
+    pub fn bind(group: SocketAddrV4) -> Result<Arc<Self>> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+        socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_nonblocking(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket.into())?);
+
+        let cookie = format!("{:08x}", rand::random::<u32>());
+        let lsd = Arc::new(Self{
+            group,
+            socket: socket.clone(),
+            cookie,
+            torrents: Mutex::new(HashMap::new()),
+            receiver: Mutex::new(None),
+        });
+        let receiver = tokio::spawn(receive(socket, Arc::downgrade(&lsd)));
+        *lsd.receiver.lock().unwrap() = Some(receiver);
+        Ok(lsd)
+    }
+
+    // Peers announcing `info_hash` go to `discovered` until `unregister`
+    pub fn register(&self, info_hash: &[u8], discovered: mpsc::UnboundedSender<SocketAddrV4>) {
+        self.torrents.lock().unwrap().insert(info_hash.to_vec(), discovered);
+    }
+
+    pub fn unregister(&self, info_hash: &[u8]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    // Tell the local network we serve `info_hash` on `port`
+    pub async fn announce(&self, info_hash: &[u8], port: u16) -> Result<()> {
+        let message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\nInfohash: {}\r\ncookie: {}\r\n\r\n\r\n",
+            self.group,
+            port,
+            hex::encode(info_hash),
+            self.cookie
+        );
+        self.socket.send_to(message.as_bytes(), self.group).await?;
+        Ok(())
+    }
+
+    fn handle_packet(&self, from: SocketAddrV4, packet: &[u8]) {
+        let Some(announcement) = parse_announcement(packet, &self.cookie) else {
+            return;
+        };
+        let peer_addr = SocketAddrV4::new(*from.ip(), announcement.port);
+        let torrents = self.torrents.lock().unwrap();
+        for info_hash in announcement.info_hashes {
+            if let Some(discovered) = torrents.get(&info_hash) {
+                let _ = discovered.send(peer_addr);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Lsd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lsd")
+            .field("group", &self.group)
+            .field("torrents", &self.torrents.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Drop for Lsd {
+    fn drop(&mut self) {
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            receiver.abort();
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Announcement {
+    pub port: u16,
+    pub info_hashes: Vec<Vec<u8>>,
+}
+
+// A BT-SEARCH request, header names are case-insensitive and Infohash may repeat.
+// None for anything else, and for our own announcements carrying `own_cookie`.
+pub fn parse_announcement(packet: &[u8], own_cookie: &str) -> Option<Announcement> {
+    let text = std::str::from_utf8(packet).ok()?;
+    let mut lines = text.split("\r\n");
+    if !lines.next()?.starts_with("BT-SEARCH * HTTP/1.1") {
+        return None;
+    }
+    let mut port = None;
+    let mut info_hashes = Vec::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => port = value.parse().ok(),
+            "infohash" => match hex::decode(value) {
+                Ok(info_hash) if info_hash.len() == 20 => info_hashes.push(info_hash),
+                _ => {},
+            },
+            "cookie" if value == own_cookie => return None,
+            _ => {},
+        }
+    }
+    Some(Announcement{ port: port.filter(|&port| port != 0)?, info_hashes })
+}
+
+// hand out announcements until the service is dropped
+async fn receive(socket: Arc<UdpSocket>, lsd: Weak<Lsd>) {
+    let mut buffer = vec![0u8; MAX_PACKET];
+    loop {
+        let (length, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("LSD socket error: {}", e);
+                if lsd.strong_count() == 0 {
+                    return;
+                }
+                time::sleep(utils::SOCKET_ERROR_BACKOFF).await;
+                continue;
+            },
+        };
+        let Some(lsd) = lsd.upgrade() else {
+            return;
+        };
+        if let SocketAddr::V4(from) = from {
+            lsd.handle_packet(from, &buffer[..length]);
+        }
+    }
+}
@@ -1,55 +1,43 @@
 // magnet.rs
-use anyhow::Result;
+use anyhow::{bail, Result};
 use reqwest;
 use serde::{Serialize, Deserialize};
 
-use crate::encoder;
-use crate::peer;
+use crate::tracker;
 
 // struct magnet link
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MagnetLink {
     pub xt: String,
     pub dn: String,
-    pub tr: String,
+    // trackerless magnets find peers through the DHT
+    #[serde(default)]
+    pub tr: Option<String>,
 }
 
 impl MagnetLink {
     pub fn get_hex_hash(&self) -> String {
-        // xt starts with "urn:btih:", 9 characters
-        self.xt[9..].to_string()
+        self.xt.strip_prefix("urn:btih:").unwrap_or_default().to_string()
     }
 
     pub fn get_hash(&self) -> Result<Vec<u8>> {
-        let info_hash = hex::decode(self.xt[9..].to_string())?;
+        let Some(hex_hash) = self.xt.strip_prefix("urn:btih:") else {
+            bail!("magnet link topic {:?} is not a BitTorrent info hash", self.xt);
+        };
+        let info_hash = hex::decode(hex_hash)?;
+        if info_hash.len() != 20 {
+            bail!("magnet link info hash has {} bytes instead of 20", info_hash.len());
+        }
         Ok(info_hash)
     }
 
-    pub fn track_request(&self) -> Result<reqwest::blocking::Response> {
-        let url = {
-            let tr = &self.tr;
-            let length = 999; // a made up value
-            let info_hash = {
-                let info_hash_bytes = self.get_hash()?;
-                encoder::encode_percent(&info_hash_bytes)
-            };
-            let peer_id = {
-                let peer_id_bytes = peer::Peer::gen_peer_id().to_vec();
-                encoder::encode_percent(&peer_id_bytes)
-            };
-            
-            format!("{tr}?\
-                    info_hash={info_hash}&\
-                    peer_id={peer_id}&\
-                    port=6881&\
-                    uploaded=0&\
-                    downloaded=0&\
-                    left={length}&\
-                    compact=1"
-            )
+    pub async fn track_request(&self) -> Result<reqwest::Response> {
+        let Some(tr) = &self.tr else {
+            bail!("the magnet link has no tracker");
         };
-
-        let raw_response = reqwest::blocking::get(url)?;
+        // left is a made up value, the size is unknown before the metadata arrives
+        let url = tracker::announce_url(tr, &self.get_hash()?, tracker::DEFAULT_PORT, 999, None);
+        let raw_response = reqwest::get(url).await?;
         Ok(raw_response)
     }
 }
@@ -1,21 +1,25 @@
 // main.rs
 
 use anyhow::Result;
-use std::{env, str::FromStr};
-use tokio;
+use std::{env, path::{Path, PathBuf}, str::FromStr, sync::{Arc, Mutex}};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
 use tokio::fs::File;
+use tokio::net::TcpListener;
 
-mod decoder;
-mod encoder;
-mod peer;
-mod magnet;
-mod message;
-mod torrent;
-mod tracker;
-mod utils;
+use glob::Pattern;
 
-use peer::Peer;
-use torrent::TorrentFile;
+use bittorrent::{decoder, dht, download, peer, picker, stream, tracker, upload, utils, verify};
+use bittorrent::dht::Dht;
+use bittorrent::download::DownloadOptions;
+use bittorrent::lsd::{self, Lsd};
+use bittorrent::magnet::MagnetLink;
+use bittorrent::peer::Peer;
+use bittorrent::resume::Progress;
+use bittorrent::storage::FileStorage;
+use bittorrent::torrent::{TorrentFile, TorrentInfo};
+use bittorrent::transport::{SocketTransport, Transport, TransportPreference};
+use bittorrent::utp::UtpSocket;
 
 // Usage: your_program.sh "command" para1 para2 ...
 #[tokio::main]
@@ -26,7 +30,7 @@ async fn main() -> Result<()> {
     if command == "decode" {
         let encoded_value = &args[2];
         let decoded_value = decoder::decode_bencoded_value(encoded_value)?;
-        println!("{}", decoded_value.to_string());
+        println!("{}", decoded_value);
     } else if command == "info" {
         let torrent_file_name = &args[2];
         let torrent = decoder::decode_torrent_file(torrent_file_name)?;
@@ -34,7 +38,7 @@ async fn main() -> Result<()> {
     } else if command == "peers" {
         let torrent_file_name = &args[2];
         let torrent = decoder::decode_torrent_file(torrent_file_name)?;
-        let raw_response = torrent.track_request()?.bytes()?;
+        let raw_response = torrent.track_request().await?.bytes().await?;
         let response = decoder::decode_tracker_response(&raw_response)?;
         utils::print_peers(&response);
     } else if command == "handshake" {
@@ -43,65 +47,94 @@ async fn main() -> Result<()> {
         let peer_addr = std::net::SocketAddrV4::from_str(peer_addr_str)?;
         let torrent = decoder::decode_torrent_file(torrent_file_name)?;
         let peer = Peer::new(peer_addr, torrent);
-        let raw_response = peer.handshake(false).await?;
-        println!("Peer ID: {}", hex::encode(&raw_response[48..]));
+        let handshake = peer.handshake(false, &SocketTransport::default()).await?;
+        println!("Peer ID: {}", hex::encode(handshake.peer_id));
     } else if command == "download_piece" {
         let file_path = &args[3];
         let torrent_file_name = &args[4];
-        let piece_index: u32 = (&args[5]).parse()?;
+        let piece_index: u32 = args[5].parse()?;
         let torrent = decoder::decode_torrent_file(torrent_file_name)?; // parse torrent
 
-        let raw_response = torrent.track_request()?.bytes()?;  // get peer info
+        let raw_response = torrent.track_request().await?.bytes().await?;  // get peer info
         let response = decoder::decode_tracker_response(&raw_response)?; // decode tracker response
         let peer_addr = response.peers[0];   // get the first peer
 
         let file = File::create(file_path).await?;
         file.set_len(torrent.get_piece_length_real(piece_index) as u64).await?;
-        peer::download_piece(peer_addr, &torrent.info, piece_index, file_path, 0).await?;    
+        let storage = FileStorage::for_piece(&torrent.info, piece_index, Path::new(file_path));
+        let progress = Mutex::new(Progress::new(torrent.get_piece_num()));
+        peer::download_piece(peer_addr, &torrent.info, piece_index, &storage, &progress, &SocketTransport::default()).await?;
     } else if command == "download" {
         let file_path = &args[3];
         let torrent_file_name = &args[4];
         let torrent = decoder::decode_torrent_file(torrent_file_name)?;
 
-        let raw_response = torrent.track_request()?.bytes()?;
-        let response = decoder::decode_tracker_response(&raw_response)?;
-        let piece_num = torrent.get_piece_num();
-        let piece_per_peer = piece_num.div_ceil(response.peers.len());
+        let mut options = download_options(&args, &torrent.info)?;
+        let (transport, utp) = start_transport(&args, options.listen).await?;
+        options.transport = Some(transport);
+        options.dht = start_dht(&args, options.listen, utp.as_deref(), false).await?;
+        download::download_file(&torrent.info, &torrent.announce, file_path, &options).await?;
+        save_dht(&args, options.dht.as_deref())?;
+    } else if command == "download_range" {
+        let torrent_file_name = &args[2];
+        let offset: u64 = args[3].parse()?;
+        let length: u64 = args[4].parse()?;
+        let usage = "usage: download_range <torrent> <offset> <length> -o <output>";
+        let (Some(file_path), Some(end)) = (flag_values(&args, "-o").pop(), offset.checked_add(length)) else {
+            anyhow::bail!(usage);
+        };
+        let torrent = decoder::decode_torrent_file(torrent_file_name)?;
 
-        let mut tasks = Vec::new();
-        let file = File::create(file_path).await?;
-        file.set_len(torrent.info.length).await?;
-        for (peer_index, peer_addr) in response.peers.iter().enumerate() {
-            let start_piece = peer_index * piece_per_peer;
-            let end_piece = ((peer_index + 1)*piece_per_peer).min(piece_num);
-            
-            let info_clone = torrent.info.clone();
-            let file_path_clone = file_path.to_string();
-            let peer_addr_clone = *peer_addr;
-            
-            let task = tokio::spawn(async move {
-                for piece_index  in start_piece..end_piece {
-                    let piece_offset = piece_index as u64 * info_clone.piece_length as u64;
-                    match peer::download_piece(
-                        peer_addr_clone,
-                        &info_clone,
-                        piece_index as u32,
-                        &file_path_clone,
-                        piece_offset
-                    ).await {
-                        Ok(_) => println!("Piece {} downloaded successfully", piece_index),
-                        Err(e) => eprintln!("Failed to download piece {}: {}", piece_index, e),
-                    }
-                }
-            });
-            tasks.push(task);
-        }
+        download::download_range(&torrent.info, &torrent.announce, offset..end, Path::new(&file_path), &DownloadOptions::default()).await?;
+    } else if command == "stream" {
+        let file_path = &args[3];
+        let torrent_file_name = &args[4];
+        let torrent = decoder::decode_torrent_file(torrent_file_name)?;
+        let listen_addr = match flag_values(&args, "--listen").last() {
+            Some(addr) => SocketAddr::from_str(addr)?,
+            None => SocketAddr::from(([127, 0, 0, 1], 8080)),
+        };
 
-        // wait tasks to finish
-        for task in tasks {
-            task.await?;
+        // players read from the start, so always fetch in order
+        let mut options = download_options(&args, &torrent.info)?;
+        options.sequential = true;
+        let (transport, utp) = start_transport(&args, options.listen).await?;
+        options.transport = Some(transport);
+        options.dht = start_dht(&args, options.listen, utp.as_deref(), false).await?;
+        let listener = TcpListener::bind(listen_addr).await?;
+        let handle = Arc::new(download::start_file(&torrent.info, &torrent.announce, file_path, &options).await?);
+        println!("Streaming on http://{}/", listener.local_addr()?);
+        let server = tokio::spawn(stream::serve(handle.clone(), listener));
+
+        handle.finish().await?;
+        println!("Download complete, still serving until Ctrl-C");
+        tokio::select! {
+            result = server => result??,
+            result = tokio::signal::ctrl_c() => result?,
         }
+    } else if command == "seed" {
+        let torrent_file_name = &args[2];
+        let data_path = &args[3];
+        let torrent = decoder::decode_torrent_file(torrent_file_name)?;
 
+        let mut options = download_options(&args, &torrent.info)?;
+        let listen = options.listen.unwrap_or(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, tracker::DEFAULT_PORT));
+        options.listen = Some(listen);
+        let (transport, utp) = start_transport(&args, Some(listen)).await?;
+        options.transport = Some(transport);
+        options.dht = start_dht(&args, Some(listen), utp.as_deref(), false).await?;
+        upload::seed(&torrent.info, &torrent.announce, Path::new(data_path), listen, &options).await?;
+        save_dht(&args, options.dht.as_deref())?;
+    } else if command == "verify" {
+        let torrent_file_name = &args[2];
+        let data_path = &args[3];
+        let torrent = decoder::decode_torrent_file(torrent_file_name)?;
+
+        let report = verify::verify(&torrent.info, Path::new(data_path)).await?;
+        utils::print_verify(&report);
+        if report.count(verify::PieceStatus::Good) != torrent.get_piece_num() {
+            std::process::exit(1);
+        }
     } else if command == "magnet_parse" {
         let raw_link = &args[2];
         let magnet_link = decoder::decode_magnet_link(raw_link)?;
@@ -110,90 +143,215 @@ async fn main() -> Result<()> {
         let raw_link = &args[2];
 
         let magnet_link = decoder::decode_magnet_link(raw_link)?;
-        let raw_response = magnet_link.track_request()?.bytes()?;  // get peer info
-        let response = decoder::decode_tracker_response(&raw_response)?; // decode tracker response
-        let peer_addr = response.peers[0];   // get the first peer
+        let dht = start_dht(&args, None, None, magnet_link.tr.is_none()).await?;
+        let peers = magnet_peers(&magnet_link, dht.as_ref()).await?;
+        let peer_addr = peers[0];   // get the first peer
         let info_hash = magnet_link.get_hash()?;
 
-        peer::magnet_handshake(peer_addr, &info_hash, true).await?;
+        peer::magnet_handshake(peer_addr, &info_hash, true, &SocketTransport::default()).await?;
     } else if command == "magnet_info" {
         let raw_link = &args[2];
 
         let magnet_link = decoder::decode_magnet_link(raw_link)?;
-        let raw_response = magnet_link.track_request()?.bytes()?;  // get peer info
-        let response = decoder::decode_tracker_response(&raw_response)?; // decode tracker response
-        let peer_addr = response.peers[0];   // get the first peer
+        let dht = start_dht(&args, None, None, magnet_link.tr.is_none()).await?;
+        let peers = magnet_peers(&magnet_link, dht.as_ref()).await?;
+        let peer_addr = peers[0];   // get the first peer
         let info_hash = magnet_link.get_hash()?;
 
-        let info = peer::magnet_request_info(peer_addr, &info_hash, true).await?;
-        let torrent = TorrentFile{ announce: magnet_link.tr, info: info };
+        let info = peer::magnet_request_info(peer_addr, &info_hash, true, &SocketTransport::default()).await?;
+        let torrent = TorrentFile{ announce: magnet_link.tr.unwrap_or_default(), info };
         utils::print_torrent(&torrent)?;
     } else if command == "magnet_download_piece" {
         let file_path = &args[3];
         let raw_link = &args[4];
-        let piece_index: u32 = (&args[5]).parse()?;
+        let piece_index: u32 = args[5].parse()?;
 
         let magnet_link = decoder::decode_magnet_link(raw_link)?;
-        let raw_response = magnet_link.track_request()?.bytes()?;  // get peer info
-        let response = decoder::decode_tracker_response(&raw_response)?; // decode tracker response
-        let peer_addr = response.peers[0];   // get the first peer
+        let dht = start_dht(&args, None, None, magnet_link.tr.is_none()).await?;
+        let peers = magnet_peers(&magnet_link, dht.as_ref()).await?;
+        let peer_addr = peers[0];   // get the first peer
         let info_hash = magnet_link.get_hash()?;
 
-        let info = peer::magnet_request_info(peer_addr, &info_hash, true).await?;
+        let info = peer::magnet_request_info(peer_addr, &info_hash, true, &SocketTransport::default()).await?;
         let file = File::create(file_path).await?;
         file.set_len(info.get_piece_length_real(piece_index) as u64).await?;
-        peer::download_piece(peer_addr, &info, piece_index, file_path, 0).await?;
+        let storage = FileStorage::for_piece(&info, piece_index, Path::new(file_path));
+        let progress = Mutex::new(Progress::new(info.get_piece_num()));
+        peer::download_piece(peer_addr, &info, piece_index, &storage, &progress, &SocketTransport::default()).await?;
     } else if command == "magnet_download" {
         let file_path = &args[3];
         let raw_link = &args[4];
 
+        // the uTP socket comes first so the DHT can share its port
         let magnet_link = decoder::decode_magnet_link(raw_link)?;
-        let raw_response = magnet_link.track_request()?.bytes()?;  // get peer info
-        let response = decoder::decode_tracker_response(&raw_response)?; // decode tracker response
-        let peer_addr = response.peers[0];   // get the first peer
+        let listen = listen_addr(&args)?;
+        let (transport, utp) = start_transport(&args, listen).await?;
+        let dht = start_dht(&args, listen, utp.as_deref(), magnet_link.tr.is_none()).await?;
+        let peers = magnet_peers(&magnet_link, dht.as_ref()).await?;
+        let peer_addr = peers[0];   // get the first peer
         let info_hash = magnet_link.get_hash()?;
 
-        let info = peer::magnet_request_info(peer_addr, &info_hash, true).await?;
-        let piece_num = info.get_piece_num();
-        let piece_per_peer = piece_num.div_ceil(response.peers.len());
-
-        let mut tasks = Vec::new();
-        let file = File::create(file_path).await?;
-        file.set_len(info.length).await?;
-        for (peer_index, peer_addr) in response.peers.iter().enumerate() {
-            let start_piece = peer_index * piece_per_peer;
-            let end_piece = ((peer_index + 1)*piece_per_peer).min(piece_num);
-            
-            let info_clone = info.clone();
-            let file_path_clone = file_path.to_string();
-            let peer_addr_clone = *peer_addr;
-            
-            let task = tokio::spawn(async move {
-                for piece_index  in start_piece..end_piece {
-                    let piece_offset = piece_index as u64 * info_clone.piece_length as u64;
-                    match peer::download_piece(
-                        peer_addr_clone,
-                        &info_clone,
-                        piece_index as u32,
-                        &file_path_clone,
-                        piece_offset
-                    ).await {
-                        Ok(_) => println!("Piece {} downloaded successfully", piece_index),
-                        Err(e) => eprintln!("Failed to download piece {}: {}", piece_index, e),
-                    }
-                }
-            });
-            tasks.push(task);
-        }
-
-        // wait tasks to finish
-        for task in tasks {
-            task.await?;
-        }
-
+        let info = peer::magnet_request_info(peer_addr, &info_hash, true, transport.as_ref()).await?;
+        let mut options = download_options(&args, &info)?;
+        options.transport = Some(transport);
+        options.dht = dht;
+        download::download_file(&info, magnet_link.tr.as_deref().unwrap_or_default(), file_path, &options).await?;
+        save_dht(&args, options.dht.as_deref())?;
     } else {
         println!("unknown command: {}", args[1]);
     }
 
     Ok(())
 }
+
+// values of every `--name value` pair on the command line
+fn flag_values(args: &[String], name: &str) -> Vec<String> {
+    args.windows(2)
+        .filter(|pair| pair[0] == name)
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+// Start a DHT node if asked for with --dht or --dht-bootstrap, or if `required`.
+// It listens on the port peers connect to, if any, sharing the socket with `utp`:
+// --dht                    find peers through the DHT as well
+// --dht-bootstrap <node>   host:port to join the DHT through, may be repeated,
+//                          well known routers by default
+// --dht-state <path>       routing table kept between runs, "dht.state" by default
+async fn start_dht(
+    args: &[String],
+    listen: Option<SocketAddrV4>,
+    utp: Option<&UtpSocket>,
+    required: bool
+) -> Result<Option<Arc<Dht>>> {
+    let mut bootstrap = flag_values(args, "--dht-bootstrap");
+    if !required && !args.iter().any(|arg| arg == "--dht") && bootstrap.is_empty() {
+        return Ok(None);
+    }
+    if bootstrap.is_empty() {
+        bootstrap = dht::DEFAULT_BOOTSTRAP_NODES.iter().map(|node| node.to_string()).collect();
+    }
+    let dht = match utp {
+        Some(utp) => {
+            let (socket, packets) = utp.share();
+            Dht::bind_shared(socket, packets, Some(&dht_state_path(args)))?
+        },
+        None => {
+            let port = listen.map_or(0, |addr| addr.port());
+            Dht::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port), Some(&dht_state_path(args))).await?
+        },
+    };
+    let node_num = dht.bootstrap(&bootstrap).await;
+    println!("DHT node {} knows {} nodes", dht.local_addr()?, node_num);
+    Ok(Some(dht))
+}
+
+// Set up the transport for peer connections, with a uTP socket on the port peers
+// connect to unless only TCP is wanted. The socket is returned for the DHT.
+// --transport <name> tcp (the default), utp, prefer-tcp or prefer-utp
+async fn start_transport(args: &[String], listen: Option<SocketAddrV4>) -> Result<(Arc<dyn Transport>, Option<Arc<UtpSocket>>)> {
+    let preference: TransportPreference = match flag_values(args, "--transport").last() {
+        Some(transport) => transport.parse()?,
+        None => TransportPreference::Tcp,
+    };
+    let utp = match preference {
+        TransportPreference::Tcp => None,
+        _ => {
+            let port = listen.map_or(0, |addr| addr.port());
+            Some(UtpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).await?)
+        },
+    };
+    Ok((Arc::new(SocketTransport::new(preference, utp.clone())), utp))
+}
+
+fn dht_state_path(args: &[String]) -> PathBuf {
+    PathBuf::from(flag_values(args, "--dht-state").pop().unwrap_or(dht::DEFAULT_STATE_FILE.to_string()))
+}
+
+fn save_dht(args: &[String], dht: Option<&Dht>) -> Result<()> {
+    match dht {
+        Some(dht) => dht.save_state(&dht_state_path(args)),
+        None => Ok(()),
+    }
+}
+
+// Peers for a magnet link, from its tracker or from the DHT if it has none
+async fn magnet_peers(magnet_link: &MagnetLink, dht: Option<&Arc<Dht>>) -> Result<Vec<SocketAddrV4>> {
+    let peers = match (&magnet_link.tr, dht) {
+        (Some(_), _) => {
+            let raw_response = magnet_link.track_request().await?.bytes().await?;
+            decoder::decode_tracker_response(&raw_response)?.peers
+        },
+        (None, Some(dht)) => dht.get_peers(&magnet_link.get_hash()?).await?,
+        (None, None) => anyhow::bail!("the magnet link has no tracker and the DHT is off"),
+    };
+    if peers.is_empty() {
+        anyhow::bail!("no peers for the magnet link");
+    }
+    Ok(peers)
+}
+
+// the address peers connect to from --port, if given
+fn listen_addr(args: &[String]) -> Result<Option<SocketAddrV4>> {
+    match flag_values(args, "--port").last() {
+        Some(port) => Ok(Some(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port.parse()?))),
+        None => Ok(None),
+    }
+}
+
+// Options of the "download", "stream" and "seed" commands:
+// --only <glob>     fetch only files matching the glob, may be repeated
+// --exclude <glob>  skip files matching the glob, may be repeated
+// --sequential      fetch pieces roughly in order
+// --first-last      fetch the first and last piece of every file first
+// --listen <addr>   address the "stream" command serves on, 127.0.0.1:8080 by default
+// --port <port>     accept peers on this port and upload to them, "seed" uses 6881 by default
+// --upload-slots <n> peers uploaded to at a time besides the optimistic unchoke
+// --lsd             find and announce peers on the local network by multicast
+// --encryption <policy> disabled, enabled (the default) or forced encryption of peer connections
+// --connect-timeout <secs>, --handshake-timeout <secs>, --encryption-timeout <secs>,
+// --request-timeout <secs>, --idle-timeout <secs>
+//                   how long peers get to connect, handshake, exchange MSE keys, send a
+//                   requested block and say anything
+fn download_options(args: &[String], info: &TorrentInfo) -> Result<DownloadOptions> {
+    let only = flag_values(args, "--only")
+        .iter()
+        .map(|glob| Pattern::new(glob))
+        .collect::<Result<Vec<Pattern>, _>>()?;
+    let exclude = flag_values(args, "--exclude")
+        .iter()
+        .map(|glob| Pattern::new(glob))
+        .collect::<Result<Vec<Pattern>, _>>()?;
+
+    let mut options = DownloadOptions{
+        sequential: args.iter().any(|arg| arg == "--sequential"),
+        first_last: args.iter().any(|arg| arg == "--first-last"),
+        ..Default::default()
+    };
+    options.listen = listen_addr(args)?;
+    if let Some(slots) = flag_values(args, "--upload-slots").last() {
+        options.upload_slots = Some(slots.parse()?);
+    }
+    if let Some(encryption) = flag_values(args, "--encryption").last() {
+        options.encryption = encryption.parse()?;
+    }
+    let timeouts = [
+        ("--connect-timeout", &mut options.peer_timeouts.connect),
+        ("--handshake-timeout", &mut options.peer_timeouts.handshake),
+        ("--encryption-timeout", &mut options.peer_timeouts.encryption),
+        ("--request-timeout", &mut options.peer_timeouts.request),
+        ("--idle-timeout", &mut options.peer_timeouts.idle),
+    ];
+    for (flag, timeout) in timeouts {
+        if let Some(secs) = flag_values(args, flag).last() {
+            *timeout = Duration::from_secs(secs.parse()?);
+        }
+    }
+    if args.iter().any(|arg| arg == "--lsd") {
+        options.lsd = Some(Lsd::bind(lsd::LSD_GROUP)?);
+    }
+    if !only.is_empty() || !exclude.is_empty() {
+        options.file_priorities = Some(picker::select_files(info, &only, &exclude));
+    }
+    Ok(options)
+}
@@ -1,36 +1,66 @@
 use anyhow::Result;
 use serde::{Serialize, Deserialize, };
 use serde_bencode;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-#[derive(Serialize, Deserialize)]
-pub struct ExtensionHandshakeDict {
-    // extension handshake dict
-    pub m: MDict, 
+// largest block a request may ask for and a piece message may carry by default
+pub const DEFAULT_MAX_BLOCK: u32 = 128 * 1024;
+
+// largest extended message by default, metadata pieces are 16 KiB
+pub const DEFAULT_MAX_EXTENDED: u32 = 1024 * 1024;
+
+// largest message of a type we do not know by default
+pub const DEFAULT_MAX_UNKNOWN: u32 = 64 * 1024;
+
+// A peer broke the wire protocol, the connection is dropped and not tried again
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("message {id} with a {length} byte payload, at most {limit} allowed")]
+    MessageTooLong{ id: u8, length: u32, limit: u32 },
+    #[error("message {id} with a {length} byte payload")]
+    BadPayloadLength{ id: u8, length: usize },
+    #[error("piece {index} is not in the torrent")]
+    BadPiece{ index: u32 },
+    #[error("block of {length} bytes at {begin} is not in piece {index}")]
+    BadBlock{ index: u32, begin: u32, length: u32 },
+    #[error("bitfield of {length} bytes for {piece_num} pieces")]
+    BadBitfield{ length: usize, piece_num: usize },
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct MDict {
-    pub ut_metadata: u8,
+// Largest payloads a peer may send, by message type. Messages of a fixed size
+// have to be exactly that size, a bitfield has to fit the torrent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageLimits {
+    pub block: u32,    // requested and delivered blocks
+    pub extended: u32,
+    pub unknown: u32,
 }
 
-pub fn handshake_message(
-    info_hash: &[u8],
-    peer_id: &[u8],
-    enable_extension: bool
-) -> Vec<u8> {
-    // basic handshake message
-    let reserved_bytes: [u8; 8] = match enable_extension {
-        true => [0, 0, 0, 0, 0, 16, 0, 0],
-        false => [0u8; 8],
-    };
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self{ block: DEFAULT_MAX_BLOCK, extended: DEFAULT_MAX_EXTENDED, unknown: DEFAULT_MAX_UNKNOWN }
+    }
+}
 
-    let mut message: Vec<u8> = Vec::new();
-    message.push(19); // Protocol string length, 0
-    message.extend_from_slice(b"BitTorrent protocol"); // Protocol string, 1 ~ 19
-    message.extend_from_slice(&reserved_bytes); // Reserved bytes, 20 ~ 27
-    message.extend_from_slice(info_hash); // 28 ~ 47
-    message.extend_from_slice(peer_id); // 48 ~ 67
-    message
+impl MessageLimits {
+    // payload bytes allowed after the id of message `id`
+    pub fn max_payload(&self, id: u8, piece_num: usize) -> u32 {
+        match id {
+            0..=3 | 14 | 15 => 0,
+            4 | 13 | 17 => 4,
+            5 => u32::try_from(piece_num.div_ceil(8)).unwrap_or(u32::MAX),
+            6 | 8 | 16 => 12,
+            7 => self.block.saturating_add(8),
+            20 => self.extended,
+            _ => self.unknown,
+        }
+    }
+
+    // the most any message may carry, for when the torrent is not known yet
+    pub fn largest(&self) -> u32 {
+        self.block.saturating_add(8).max(self.extended).max(self.unknown)
+    }
 }
 
 pub fn piece_request_message(piece_index: u32, offset: u32, length: u32) -> Vec<u8> {
@@ -43,19 +73,10 @@ pub fn piece_request_message(piece_index: u32, offset: u32, length: u32) -> Vec<
     message
 }
 
-pub fn extension_handshake_message() -> Result<Vec<u8>> {
-    let m_dict = MDict{ ut_metadata: 1 };
-    let eh_dict = ExtensionHandshakeDict{ m: m_dict };
-    let eh_bytes = serde_bencode::to_bytes(&eh_dict)?;
-    let length = eh_bytes.len() as u32 + 2; // including 2 ids
-    let length_bytes = length.to_be_bytes();
-
-    let mut message: Vec<u8> = Vec::new();
-    message.extend_from_slice(&length_bytes);
-    message.push(20); // message id
-    message.push(0); // extension id for extension handshake
-    message.extend_from_slice(&eh_bytes);
-    Ok(message)
+pub fn cancel_message(piece_index: u32, offset: u32, length: u32) -> Vec<u8> {
+    let mut message = piece_request_message(piece_index, offset, length);
+    message[4] = 8; // message type, 8 -> cancel type, same payload as request
+    message
 }
 
 #[derive(Serialize, Deserialize)]
@@ -66,6 +87,13 @@ pub struct ExtensionRequestDict {
     total_size: Option<u32>
 }
 
+impl ExtensionRequestDict {
+    // size of the whole info dict, only sent along with data
+    pub fn total_size(&self) -> Option<u32> {
+        self.total_size
+    }
+}
+
 pub fn extension_request_message(metadata_id: u8) -> Result<Vec<u8>> {
     let er_dict = ExtensionRequestDict{ msg_type: 0, piece: 0, total_size: None };
     let er_bytes = serde_bencode::to_bytes(&er_dict)?;
@@ -79,3 +107,136 @@ pub fn extension_request_message(metadata_id: u8) -> Result<Vec<u8>> {
     message.extend_from_slice(&er_bytes);
     Ok(message)
 }
+
+// A peer wire message after the handshake
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request{ index: u32, begin: u32, length: u32 },
+    Piece{ index: u32, begin: u32, block: Vec<u8> },
+    Cancel{ index: u32, begin: u32, length: u32 },
+    // fast extension
+    Suggest(u32),
+    HaveAll,
+    HaveNone,
+    Reject{ index: u32, begin: u32, length: u32 },
+    AllowedFast(u32),
+    Extended{ id: u8, payload: Vec<u8> },
+    Unknown{ id: u8, payload: Vec<u8> },
+}
+
+impl Message {
+    // Parse the body following the length prefix, an empty body is a keep-alive
+    pub fn parse(body: &[u8]) -> Result<Self> {
+        let Some((&id, payload)) = body.split_first() else {
+            return Ok(Message::KeepAlive);
+        };
+        let message = match (id, payload.len()) {
+            (0, 0) => Message::Choke,
+            (1, 0) => Message::Unchoke,
+            (2, 0) => Message::Interested,
+            (3, 0) => Message::NotInterested,
+            (4, 4) => Message::Have(read_u32(payload, 0)),
+            (5, _) => Message::Bitfield(payload.to_vec()),
+            (6, 12) => Message::Request{ index: read_u32(payload, 0), begin: read_u32(payload, 4), length: read_u32(payload, 8) },
+            (7, 8..) => Message::Piece{ index: read_u32(payload, 0), begin: read_u32(payload, 4), block: payload[8..].to_vec() },
+            (8, 12) => Message::Cancel{ index: read_u32(payload, 0), begin: read_u32(payload, 4), length: read_u32(payload, 8) },
+            (13, 4) => Message::Suggest(read_u32(payload, 0)),
+            (14, 0) => Message::HaveAll,
+            (15, 0) => Message::HaveNone,
+            (16, 12) => Message::Reject{ index: read_u32(payload, 0), begin: read_u32(payload, 4), length: read_u32(payload, 8) },
+            (17, 4) => Message::AllowedFast(read_u32(payload, 0)),
+            (20, 1..) => Message::Extended{ id: payload[0], payload: payload[1..].to_vec() },
+            (0..=8 | 13..=17 | 20, length) => return Err(ProtocolError::BadPayloadLength{ id, length }.into()),
+            _ => Message::Unknown{ id, payload: payload.to_vec() },
+        };
+        Ok(message)
+    }
+
+    // the message with its length prefix, ready to send
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (id, payload) = match self {
+            Message::KeepAlive => return vec![0u8; 4],
+            Message::Choke => (0, Vec::new()),
+            Message::Unchoke => (1, Vec::new()),
+            Message::Interested => (2, Vec::new()),
+            Message::NotInterested => (3, Vec::new()),
+            Message::Have(index) => (4, index.to_be_bytes().to_vec()),
+            Message::Bitfield(bytes) => (5, bytes.clone()),
+            Message::Request{ index, begin, length } => return piece_request_message(*index, *begin, *length),
+            Message::Piece{ index, begin, block } => {
+                let mut payload = Vec::with_capacity(8 + block.len());
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(block);
+                (7, payload)
+            },
+            Message::Cancel{ index, begin, length } => return cancel_message(*index, *begin, *length),
+            Message::Suggest(index) => (13, index.to_be_bytes().to_vec()),
+            Message::HaveAll => (14, Vec::new()),
+            Message::HaveNone => (15, Vec::new()),
+            Message::Reject{ index, begin, length } => {
+                let mut message = piece_request_message(*index, *begin, *length);
+                message[4] = 16; // same payload as request
+                return message;
+            },
+            Message::AllowedFast(index) => (17, index.to_be_bytes().to_vec()),
+            Message::Extended{ id, payload } => {
+                let mut extended = vec![*id];
+                extended.extend_from_slice(payload);
+                (20, extended)
+            },
+            Message::Unknown{ id, payload } => (*id, payload.clone()),
+        };
+        let mut message = Vec::with_capacity(5 + payload.len());
+        message.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+        message.push(id);
+        message.extend_from_slice(&payload);
+        message
+    }
+}
+
+// Read one length-prefixed message of a torrent with `piece_num` pieces. The
+// length is checked against `limits` before anything is allocated.
+pub async fn read_message(reader: &mut (impl AsyncRead + Unpin), limits: &MessageLimits, piece_num: usize) -> Result<Message> {
+    let length = reader.read_u32().await?;
+    if length == 0 {
+        return Ok(Message::KeepAlive);
+    }
+    let id = reader.read_u8().await?;
+    let limit = limits.max_payload(id, piece_num);
+    if length - 1 > limit {
+        return Err(ProtocolError::MessageTooLong{ id, length: length - 1, limit }.into());
+    }
+    let mut body = vec![0u8; length as usize];
+    body[0] = id;
+    reader.read_exact(&mut body[1..]).await?;
+    Message::parse(&body)
+}
+
+// Read one length-prefixed message without parsing it, for when the torrent is
+// not known yet. Any message `limits` allow is accepted.
+pub async fn read_body(reader: &mut (impl AsyncRead + Unpin), limits: &MessageLimits) -> Result<Vec<u8>> {
+    let length = reader.read_u32().await?;
+    if length == 0 {
+        return Ok(Vec::new());
+    }
+    let id = reader.read_u8().await?;
+    if length - 1 > limits.largest() {
+        return Err(ProtocolError::MessageTooLong{ id, length: length - 1, limit: limits.largest() }.into());
+    }
+    let mut body = vec![0u8; length as usize];
+    body[0] = id;
+    reader.read_exact(&mut body[1..]).await?;
+    Ok(body)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
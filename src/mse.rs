@@ -0,0 +1,418 @@
+// mse.rs
+
+use anyhow::{anyhow, bail, Result};
+use num_bigint::BigUint;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::{self, Duration};
+
+use std::fmt;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+
+use crate::encoder;
+
+// the 768 bit prime of the key exchange, the generator is 2
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+
+// public keys and the shared secret are sent as this many bytes
+const KEY_LENGTH: usize = 96;
+
+// random padding after the public keys, and the most padding a side may send
+const MAX_PADDING: usize = 512;
+
+// the start of the RC4 keystream is dropped, it leaks the key
+const RC4_DISCARD: usize = 1024;
+
+// verification constant both sides encrypt to find the start of the encrypted part
+const VC: [u8; 8] = [0; 8];
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+// how the plaintext handshake starts, incoming connections not starting so use MSE
+const PROTOCOL_HEADER: &[u8; 20] = b"\x13BitTorrent protocol";
+
+// Which connections go through Message Stream Encryption
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    // plaintext only, encrypted peers are turned away
+    Disabled,
+    // connect encrypted falling back to plaintext, accept both
+    #[default]
+    Enabled,
+    // RC4 encrypted connections only
+    Forced,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "disabled" => Ok(Self::Disabled),
+            "enabled" => Ok(Self::Enabled),
+            "forced" => Ok(Self::Forced),
+            _ => bail!("unknown encryption policy {:?}, expected disabled, enabled or forced", s),
+        }
+    }
+}
+
+// The RC4 stream cipher, encrypting and decrypting are the same
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (index, value) in state.iter_mut().enumerate() {
+            *value = index as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self{ state, i: 0, j: 0 }
+    }
+
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+
+    // the cipher for one direction, past the discarded start of the keystream
+    fn for_direction(name: &[u8], secret: &[u8], info_hash: &[u8]) -> Self {
+        let mut cipher = Self::new(&hash(&[name, secret, info_hash]));
+        cipher.apply(&mut [0u8; RC4_DISCARD]);
+        cipher
+    }
+}
+
+impl fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Rc4")
+    }
+}
+
+// A peer connection after the MSE handshake, plaintext when the sides settled
+// on it. Bytes read past the handshake are handed out first.
+#[derive(Debug)]
+pub struct MseStream<S> {
+    inner: S,
+    prefix: Vec<u8>,          // decrypted bytes read ahead
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    unwritten: Vec<u8>,       // encrypted bytes the stream did not take yet
+}
+
+impl<S> MseStream<S> {
+    // a stream that is not encrypted at all
+    pub fn plaintext(inner: S) -> Self {
+        Self{ inner, prefix: Vec::new(), read_cipher: None, write_cipher: None, unwritten: Vec::new() }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_write_unwritten(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.unwritten.is_empty() {
+            let length = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.unwritten))?;
+            if length == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.unwritten.drain(..length);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.prefix.is_empty() {
+            let length = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix[..length]);
+            this.prefix.drain(..length);
+            return Poll::Ready(Ok(()));
+        }
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.read_cipher {
+            cipher.apply(&mut buf.filled_mut()[start..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Encrypted bytes are taken whole and written out on later calls, a flush
+// makes sure they all went out
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+        ready!(this.poll_write_unwritten(cx))?;
+        let mut encrypted = data.to_vec();
+        if let Some(cipher) = &mut this.write_cipher {
+            cipher.apply(&mut encrypted);
+        }
+        this.unwritten = encrypted;
+        if let Poll::Ready(Err(e)) = this.poll_write_unwritten(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_unwritten(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_unwritten(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+// Reads of the handshake, some bytes may have been read ahead while looking for
+// a marker
+struct HandshakeReader<'a, S> {
+    stream: &'a mut S,
+    buffered: Vec<u8>,
+}
+
+impl<S: AsyncRead + Unpin> HandshakeReader<'_, S> {
+    async fn read(&mut self, length: usize) -> Result<Vec<u8>> {
+        if self.buffered.len() < length {
+            let start = self.buffered.len();
+            self.buffered.resize(length, 0);
+            self.stream.read_exact(&mut self.buffered[start..]).await?;
+        }
+        Ok(self.buffered.drain(..length).collect())
+    }
+
+    // Skip up to `max_skip` bytes of padding until `marker`, and past it
+    async fn skip_to(&mut self, marker: &[u8], max_skip: usize) -> Result<()> {
+        let mut chunk = [0u8; 256];
+        loop {
+            if let Some(position) = self.buffered.windows(marker.len()).position(|window| window == marker) {
+                self.buffered.drain(..position + marker.len());
+                return Ok(());
+            }
+            if self.buffered.len() >= max_skip + marker.len() {
+                bail!("no MSE handshake marker within {} bytes", max_skip);
+            }
+            let length = self.stream.read(&mut chunk).await?;
+            if length == 0 {
+                bail!("connection closed during the MSE handshake");
+            }
+            self.buffered.extend_from_slice(&chunk[..length]);
+        }
+    }
+}
+
+// Open an MSE connection over `stream` for the torrent `info_hash`. The peer
+// picks RC4 or plaintext among what `policy` offers. A peer that does not finish
+// the key exchange within `timeout` may not speak MSE at all.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    info_hash: &[u8],
+    policy: EncryptionPolicy,
+    timeout: Duration
+) -> Result<MseStream<S>> {
+    let crypto_provide = match policy {
+        EncryptionPolicy::Disabled => return Ok(MseStream::plaintext(stream)),
+        EncryptionPolicy::Enabled => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        EncryptionPolicy::Forced => CRYPTO_RC4,
+    };
+    time::timeout(timeout, handshake_outgoing(stream, info_hash, crypto_provide))
+        .await
+        .map_err(|_| anyhow!("MSE handshake timed out after {:?}", timeout))?
+}
+
+async fn handshake_outgoing<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: &[u8],
+    crypto_provide: u32
+) -> Result<MseStream<S>> {
+    let (private_key, public_key) = key_pair();
+    stream.write_all(&[public_key, padding()].concat()).await?;
+
+    let mut reader = HandshakeReader{ stream: &mut stream, buffered: Vec::new() };
+    let peer_key = reader.read(KEY_LENGTH).await?;
+    let secret = shared_secret(&private_key, &peer_key);
+    let mut encrypt = Rc4::for_direction(b"keyA", &secret, info_hash);
+    let mut decrypt = Rc4::for_direction(b"keyB", &secret, info_hash);
+
+    // the peer finds its torrent by the info hash we hash with the secret
+    let mut request = hash(&[b"req1", &secret]);
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    request.extend(req2.iter().zip(&req3).map(|(a, b)| a ^ b));
+    let mut header = VC.to_vec();
+    header.extend_from_slice(&crypto_provide.to_be_bytes());
+    header.extend_from_slice(&0u16.to_be_bytes()); // no padding
+    header.extend_from_slice(&0u16.to_be_bytes()); // no initial payload
+    encrypt.apply(&mut header);
+    request.extend(header);
+    reader.stream.write_all(&request).await?;
+
+    // the peer's padding ends where its encrypted verification constant starts
+    let mut marker = VC;
+    decrypt.apply(&mut marker);
+    reader.skip_to(&marker, MAX_PADDING).await?;
+    let mut answer = reader.read(6).await?;
+    decrypt.apply(&mut answer);
+    let crypto_select = u32::from_be_bytes([answer[0], answer[1], answer[2], answer[3]]);
+    let padding_length = u16::from_be_bytes([answer[4], answer[5]]) as usize;
+    if padding_length > MAX_PADDING {
+        bail!("MSE padding of {} bytes is too long", padding_length);
+    }
+    let mut padding = reader.read(padding_length).await?;
+    decrypt.apply(&mut padding);
+
+    let mut prefix = std::mem::take(&mut reader.buffered);
+    let (read_cipher, write_cipher) = match crypto_select {
+        CRYPTO_RC4 if crypto_provide & CRYPTO_RC4 != 0 => {
+            decrypt.apply(&mut prefix);
+            (Some(decrypt), Some(encrypt))
+        },
+        CRYPTO_PLAINTEXT if crypto_provide & CRYPTO_PLAINTEXT != 0 => (None, None),
+        _ => bail!("peer selected encryption method {:#x} we did not offer", crypto_select),
+    };
+    Ok(MseStream{ inner: stream, prefix, read_cipher, write_cipher, unwritten: Vec::new() })
+}
+
+// Take an incoming connection for the torrent `info_hash`, encrypted or not as
+// `policy` allows, giving the key exchange `timeout`
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: &[u8],
+    policy: EncryptionPolicy,
+    timeout: Duration
+) -> Result<MseStream<S>> {
+    let mut start = [0u8; PROTOCOL_HEADER.len()];
+    stream.read_exact(&mut start).await?;
+    match (&start == PROTOCOL_HEADER, policy) {
+        (true, EncryptionPolicy::Forced) => bail!("plaintext connections are not accepted"),
+        (false, EncryptionPolicy::Disabled) => bail!("encrypted connections are not accepted"),
+        (true, _) => {
+            let mut connection = MseStream::plaintext(stream);
+            connection.prefix = start.to_vec();
+            return Ok(connection);
+        },
+        (false, _) => {},
+    }
+    time::timeout(timeout, handshake_incoming(stream, &start, info_hash, policy))
+        .await
+        .map_err(|_| anyhow!("MSE handshake timed out after {:?}", timeout))?
+}
+
+// the rest of an incoming MSE handshake, after the `start` of the peer's public key
+async fn handshake_incoming<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    start: &[u8],
+    info_hash: &[u8],
+    policy: EncryptionPolicy
+) -> Result<MseStream<S>> {
+    let mut reader = HandshakeReader{ stream: &mut stream, buffered: start.to_vec() };
+    let peer_key = reader.read(KEY_LENGTH).await?;
+    let (private_key, public_key) = key_pair();
+    reader.stream.write_all(&[public_key, padding()].concat()).await?;
+    let secret = shared_secret(&private_key, &peer_key);
+
+    reader.skip_to(&hash(&[b"req1", &secret]), MAX_PADDING).await?;
+    let torrent = reader.read(20).await?;
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    if torrent.iter().zip(&req3).map(|(a, b)| a ^ b).ne(req2) {
+        bail!("encrypted connection for a torrent we do not serve");
+    }
+    let mut decrypt = Rc4::for_direction(b"keyA", &secret, info_hash);
+    let mut encrypt = Rc4::for_direction(b"keyB", &secret, info_hash);
+
+    let mut header = reader.read(14).await?;
+    decrypt.apply(&mut header);
+    if header[..8] != VC {
+        bail!("MSE verification constant does not match");
+    }
+    let crypto_provide = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    let padding_length = u16::from_be_bytes([header[12], header[13]]) as usize;
+    if padding_length > MAX_PADDING {
+        bail!("MSE padding of {} bytes is too long", padding_length);
+    }
+    let mut padding = reader.read(padding_length + 2).await?;
+    decrypt.apply(&mut padding);
+    let initial_length = u16::from_be_bytes([padding[padding_length], padding[padding_length + 1]]) as usize;
+    let mut initial_payload = reader.read(initial_length).await?;
+    decrypt.apply(&mut initial_payload);
+
+    let crypto_select = match policy {
+        _ if crypto_provide & CRYPTO_RC4 != 0 => CRYPTO_RC4,
+        EncryptionPolicy::Enabled if crypto_provide & CRYPTO_PLAINTEXT != 0 => CRYPTO_PLAINTEXT,
+        _ => bail!("peer offered no encryption method we accept"),
+    };
+    let mut answer = VC.to_vec();
+    answer.extend_from_slice(&crypto_select.to_be_bytes());
+    answer.extend_from_slice(&0u16.to_be_bytes()); // no padding
+    encrypt.apply(&mut answer);
+    reader.stream.write_all(&answer).await?;
+
+    let mut rest = std::mem::take(&mut reader.buffered);
+    let (read_cipher, write_cipher) = match crypto_select {
+        CRYPTO_RC4 => {
+            decrypt.apply(&mut rest);
+            (Some(decrypt), Some(encrypt))
+        },
+        _ => (None, None),
+    };
+    initial_payload.extend(rest);
+    Ok(MseStream{ inner: stream, prefix: initial_payload, read_cipher, write_cipher, unwritten: Vec::new() })
+}
+
+// a random private key of 160 bits and the public key it gives
+fn key_pair() -> (BigUint, Vec<u8>) {
+    let private_key = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+    let public_key = BigUint::from(GENERATOR).modpow(&private_key, &prime());
+    (private_key, to_key_bytes(&public_key))
+}
+
+fn shared_secret(private_key: &BigUint, peer_key: &[u8]) -> Vec<u8> {
+    to_key_bytes(&BigUint::from_bytes_be(peer_key).modpow(private_key, &prime()))
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap()
+}
+
+fn to_key_bytes(value: &BigUint) -> Vec<u8> {
+    let bytes = value.to_bytes_be();
+    let mut key = vec![0u8; KEY_LENGTH - bytes.len()];
+    key.extend(bytes);
+    key
+}
+
+fn padding() -> Vec<u8> {
+    (0..rand::random_range(0..=MAX_PADDING)).map(|_| rand::random()).collect()
+}
+
+fn hash(parts: &[&[u8]]) -> Vec<u8> {
+    encoder::encode_sha1(&parts.concat()).unwrap_or_default()
+}
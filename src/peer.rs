@@ -1,15 +1,77 @@
 // peer.rs
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use rand;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
-use tokio::fs::File;
-use tokio::net::TcpStream;
+use serde::de::IgnoredAny;
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::bitfield::Bitfield;
+use crate::decoder;
+use crate::encoder;
+use crate::extension::{ExtensionHandshake, ExtensionRegistry};
+use crate::handshake::{Capabilities, Handshake};
+use crate::message::{self, ExtensionRequestDict, Message, MessageLimits, ProtocolError};
+use crate::mse::{self, EncryptionPolicy};
+use crate::resume::Progress;
+use crate::storage::Storage;
+use crate::torrent::{TorrentFile, TorrentInfo};
+use crate::transport::{PeerStream, Transport};
 
-use std::net::SocketAddrV4;
+pub const BLOCK_SIZE: u32 = 16384;
 
-use crate::message::{self, ExtensionHandshakeDict, ExtensionRequestDict};
-use crate::torrent::{TorrentFile, TorrentInfo};
+// messages read ahead of the connection's consumer
+const MESSAGE_QUEUE: usize = 64;
+
+// what we announce on connections pieces are exchanged over
+const CAPABILITIES: Capabilities = Capabilities{ extension: true, fast: true, dht: false };
+
+// a peer that keeps us choked this long is given up on
+pub const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// we send a keep-alive after this much silence by default
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+
+// How long a peer gets for each step before the connection is given up on. The
+// idle timeout has to be longer than the keep-alive interval of the other side.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerTimeouts {
+    pub connect: Duration,
+    pub handshake: Duration,  // MSE and BitTorrent handshakes together
+    pub encryption: Duration, // the MSE key exchange alone, before falling back to plaintext
+    pub request: Duration,    // for a block we requested to arrive
+    pub idle: Duration,       // without any message, keep-alives included
+    pub keep_alive: Duration, // our silence before we send a keep-alive
+}
+
+impl Default for PeerTimeouts {
+    fn default() -> Self {
+        Self{
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(30),
+            encryption: Duration::from_secs(10),
+            request: Duration::from_secs(60),
+            idle: Duration::from_secs(180),
+            keep_alive: KEEP_ALIVE_INTERVAL,
+        }
+    }
+}
+
+// Run one step of talking to a peer, giving up after `limit`
+async fn within<T>(limit: Duration, step: impl Future<Output = Result<T>>, what: &str, peer_addr: SocketAddrV4) -> Result<T> {
+    match time::timeout(limit, step).await {
+        Ok(result) => result,
+        Err(_) => bail!("{} {} timed out after {:?}", what, peer_addr, limit),
+    }
+}
 
 // peer struct
 pub struct Peer {
@@ -22,161 +84,531 @@ impl Peer {
         Self{ peer_addr, torrent}
     }
 
-    // Handshake with peer
-    pub async fn handshake(&self, enable_extension: bool) -> Result<Vec<u8>> {
-        // Setup tcp connection
-        let mut stream = TcpStream::connect(self.peer_addr).await?;
-
-        // Construct handshake message
-        let handshake_message = {
-            let reserved_bytes: [u8; 8] = match enable_extension {
-                true => [0, 0, 0, 0, 0, 16, 0, 0],
-                false => [0u8; 8],
-            };
-
-            let mut message: Vec<u8> = Vec::new();
-            message.push(19); // Protocol string length
-            message.extend_from_slice(b"BitTorrent protocol"); // Protocol string
-            message.extend_from_slice(&reserved_bytes); // Reserved bytes
-            message.extend_from_slice(&self.torrent.get_hash()?);
-            message.extend_from_slice(&Self::gen_peer_id());
-            message
+    // Handshake with peer, returns the peer's handshake
+    pub async fn handshake(&self, enable_extension: bool, transport: &dyn Transport) -> Result<Handshake> {
+        let info_hash = self.torrent.get_hash()?;
+        let timeouts = PeerTimeouts::default();
+        let mut stream = within(timeouts.connect, transport.connect(self.peer_addr), "connecting to", self.peer_addr).await?;
+        let capabilities = Capabilities{ extension: enable_extension, ..Default::default() };
+        let handshake = Handshake::new(&info_hash, Self::gen_peer_id(), capabilities)?;
+        let exchange = async {
+            stream.write_all(&handshake.to_bytes()).await?;
+            Handshake::read(&mut stream).await
         };
-        
-        // Send handshake message and read response
-        stream.write_all(&handshake_message).await?;
-        let mut buffer = [0u8; 68];
-        stream.read(&mut buffer).await?;
-        Ok(buffer.to_vec())
+        let peer_handshake = within(timeouts.handshake, exchange, "handshake with", self.peer_addr).await?;
+        peer_handshake.validate(&info_hash, None)?;
+        Ok(peer_handshake)
     }
 
     // randomly generate a peer id
     pub fn gen_peer_id() -> [u8; 20] {
         let mut peer_id = [0u8; 20];
-        for idx in 0..peer_id.len() {
-            peer_id[idx] = rand::random_range(0..255);
+        for byte in peer_id.iter_mut() {
+            *byte = rand::random_range(0..255);
         }
         peer_id
     }
 }
 
 
+// A block was written to storage, announced so other connections fetching the
+// same piece in endgame can cancel their request for it
+#[derive(Clone, Copy, Debug)]
+pub struct BlockReceived {
+    pub piece_index: u32,
+    pub block_index: u32,
+}
 
-// Download a piece from peer
+// Download a single piece from the peer over a connection of its own
 pub async fn download_piece(
     peer_addr: SocketAddrV4,
     info: &TorrentInfo,
     piece_index: u32,
-    file_path: &str,
-    piece_offset: u64
+    storage: &dyn Storage,
+    progress: &Mutex<Progress>,
+    transport: &dyn Transport
 ) -> Result<()> {
-    // Step1 connect peer
-    let mut stream = TcpStream::connect(peer_addr).await?;
-    let peer_id = Peer::gen_peer_id();
-    let info_hash = info.get_hash()?;
-
-    // Step2 send handshake message
-    let handshake_message = message::handshake_message(&info_hash, &peer_id, false);
-    stream.write_all(&handshake_message).await?;
-
-    // Step3 read handshake response
-    let mut buffer = [0u8; 68];
-    stream.read_exact(&mut buffer).await?;
-    
-    // Step4 read bitfield message
-    let mut length_bytes = [0u8; 4];
-    stream.read_exact(&mut length_bytes).await?;
-    let length = u32::from_be_bytes(length_bytes);
-    let mut buffer = vec![0u8; length as usize];
-    stream.read_exact(&mut buffer).await?;
-
-    // Step5 send interest message
-    let message: [u8; 5] = [0, 0, 0, 1, 2];
-    stream.write_all(&message).await?;
-
-    // Step6 recive unchocked message
-    let mut length_bytes = [0u8; 4];
-    stream.read_exact(&mut length_bytes).await?;
-    let length = u32::from_be_bytes(length_bytes);
-    let mut buffer = vec![0u8; length as usize];
-    stream.read_exact(&mut buffer).await?;
-
-    // Step7 send piece requests
-    let piece_length = info.get_piece_length_real(piece_index);
-    let block_size: u32 = 16384;
-    let blocks = piece_length.div_ceil(block_size);
-
-    for block_idx in 0..blocks {
-        let offset = block_idx * block_size;
-        let length = 16384u32.min(piece_length - offset);
-        let request_message = message::piece_request_message(piece_index, offset, length);
-        stream.write_all(&request_message).await?;
-    }
-
-    // Step8 read piece data
-    let mut piece_buffer = vec![0u8; piece_length as usize];
-    for _ in 0..blocks {
-        let mut length_bytes = [0u8; 4];
-        stream.read_exact(&mut length_bytes).await?;
-        let length = u32::from_be_bytes(length_bytes);
-        let mut buffer = vec![0u8; length as usize];
-        stream.read_exact(&mut buffer).await?;
-
-        let offset = u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]) as usize;
-        let block_data = &buffer[9..];
-        let (start, end) = (offset, offset + block_data.len());
-        piece_buffer[start..end].copy_from_slice(block_data);
-    }
-
-    // Step9 save piece file
-    let mut file = File::options().write(true).open(file_path).await?;
-    file.seek(SeekFrom::Start(piece_offset)).await?;
-    file.write_all(&piece_buffer).await?;
-    file.flush().await?;
-
+    let mut connection = PeerConnection::connect(
+        peer_addr,
+        info,
+        ExtensionRegistry::new(),
+        EncryptionPolicy::default(),
+        MessageLimits::default(),
+        PeerTimeouts::default(),
+        transport,
+        false
+    ).await?;
+    let (blocks, _) = broadcast::channel(1);
+    connection.download_piece(info, piece_index, storage, progress, &blocks).await?;
     Ok(())
 }
 
-// magnet handshake (support extension) 
+// A connection pieces are downloaded over one after another. Messages are read
+// by a task of their own so waiting for them can be combined with other events.
+pub struct PeerConnection {
+    pub peer_addr: SocketAddrV4,
+    pub peer_id: [u8; 20],
+    pub has: Bitfield,  // pieces the peer announced
+    pub fast: bool,     // both sides support the fast extension
+    pub allowed_fast: BTreeSet<u32>, // pieces the peer serves us even while choking us
+    pub suggested: BTreeSet<u32>,
+    pub extended: bool, // both sides support the extension protocol
+    pub extensions: ExtensionRegistry,
+    pub encrypted: bool, // the connection is RC4 encrypted
+    pub utp: bool, // the connection runs over uTP
+    piece_length: u32,
+    total_length: u64,
+    limits: MessageLimits,
+    timeouts: PeerTimeouts,
+    choked_since: Option<Instant>,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    messages: mpsc::Receiver<Result<Message>>,
+    reader_task: JoinHandle<()>,
+    writer_task: JoinHandle<()>,
+}
+
+impl PeerConnection {
+    // Handshake and declare interest, the peer unchokes us later if at all.
+    // `extensions` are offered to the peer if it supports the extension protocol.
+    // With encryption enabled a peer failing the MSE handshake is connected to
+    // again in plaintext. Messages beyond `limits` or outside the torrent drop
+    // the connection with a `ProtocolError`, so does a peer exceeding `timeouts`.
+    // With `utp_first` the transport tries uTP before TCP if it uses both.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn connect(
+        peer_addr: SocketAddrV4,
+        info: &TorrentInfo,
+        extensions: ExtensionRegistry,
+        encryption: EncryptionPolicy,
+        limits: MessageLimits,
+        timeouts: PeerTimeouts,
+        transport: &dyn Transport,
+        utp_first: bool
+    ) -> Result<Self> {
+        let info_hash = &info.get_hash()?;
+        let open = || match utp_first {
+            true => transport.connect_utp_first(peer_addr),
+            false => transport.connect(peer_addr),
+        };
+        let stream = within(timeouts.connect, open(), "connecting to", peer_addr).await?;
+        let exchange = async {
+            let mut utp = stream.is_utp();
+            let mut stream = match mse::connect(stream, info_hash, encryption, timeouts.encryption).await {
+                Ok(stream) => stream,
+                Err(_) if encryption == EncryptionPolicy::Enabled => {
+                    let stream = within(timeouts.connect, open(), "connecting to", peer_addr).await?;
+                    utp = stream.is_utp();
+                    mse::MseStream::plaintext(stream)
+                },
+                Err(e) => return Err(e),
+            };
+            let handshake = Handshake::new(info_hash, Peer::gen_peer_id(), CAPABILITIES)?;
+            stream.write_all(&handshake.to_bytes()).await?;
+            stream.flush().await?;
+            let peer_handshake = Handshake::read(&mut stream).await?;
+            Ok((stream, utp, peer_handshake))
+        };
+        let (stream, utp, peer_handshake) = within(timeouts.handshake, exchange, "handshake with", peer_addr).await?;
+        peer_handshake.validate(info_hash, None)?;
+
+        let mut connection = Self::from_stream(stream, peer_addr, &peer_handshake, info, extensions, limits, timeouts);
+        connection.utp = utp;
+        connection.send_extension_handshake().await?;
+        connection.send(&Message::Interested).await?;
+        Ok(connection)
+    }
+
+    // Answer the handshake of a peer that connected to us, over MSE if it starts
+    // one and `encryption` allows. The bitfield has to be the first message, the
+    // extension handshake is left to the caller to send after it.
+    pub async fn accept(
+        stream: PeerStream,
+        info: &TorrentInfo,
+        extensions: ExtensionRegistry,
+        encryption: EncryptionPolicy,
+        limits: MessageLimits,
+        timeouts: PeerTimeouts
+    ) -> Result<Self> {
+        let info_hash = &info.get_hash()?;
+        let SocketAddr::V4(peer_addr) = stream.peer_addr()? else {
+            bail!("only IPv4 peers are supported");
+        };
+        let utp = stream.is_utp();
+        let exchange = async {
+            let mut stream = mse::accept(stream, info_hash, encryption, timeouts.encryption).await?;
+            let peer_handshake = Handshake::read(&mut stream).await?;
+            peer_handshake.validate(info_hash, None)?;
+            let handshake = Handshake::new(info_hash, Peer::gen_peer_id(), CAPABILITIES)?;
+            stream.write_all(&handshake.to_bytes()).await?;
+            stream.flush().await?;
+            Ok((stream, peer_handshake))
+        };
+        let (stream, peer_handshake) = within(timeouts.handshake, exchange, "handshake with", peer_addr).await?;
+        let mut connection = Self::from_stream(stream, peer_addr, &peer_handshake, info, extensions, limits, timeouts);
+        connection.utp = utp;
+        Ok(connection)
+    }
+
+    fn from_stream<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        stream: mse::MseStream<S>,
+        peer_addr: SocketAddrV4,
+        handshake: &Handshake,
+        info: &TorrentInfo,
+        mut extensions: ExtensionRegistry,
+        limits: MessageLimits,
+        timeouts: PeerTimeouts
+    ) -> Self {
+        let piece_num = info.get_piece_num();
+        let encrypted = stream.is_encrypted();
+        let (mut reader, mut writer) = io::split(stream);
+        let (sender, messages) = mpsc::channel(MESSAGE_QUEUE);
+        // a peer saying nothing at all, not even keep-alives, is gone
+        let reader_task = tokio::spawn(async move {
+            loop {
+                let message = match time::timeout(timeouts.idle, message::read_message(&mut reader, &limits, piece_num)).await {
+                    Ok(message) => message,
+                    Err(_) => Err(anyhow!("{} sent nothing for {:?}", peer_addr, timeouts.idle)),
+                };
+                let failed = message.is_err();
+                if sender.send(message).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        // writes go through a task too, so sending from `next_message` cannot be
+        // cut short by a select dropping it. It fills our silences with keep-alives,
+        // and gives up on a peer that stops taking our bytes.
+        let (outgoing, mut outgoing_receiver) = mpsc::channel::<Vec<u8>>(MESSAGE_QUEUE);
+        let writer_task = tokio::spawn(async move {
+            loop {
+                let bytes = match time::timeout(timeouts.keep_alive, outgoing_receiver.recv()).await {
+                    Ok(Some(bytes)) => bytes,
+                    Ok(None) => return,
+                    Err(_) => Message::KeepAlive.to_bytes(),
+                };
+                let write = async {
+                    writer.write_all(&bytes).await?;
+                    writer.flush().await
+                };
+                if !matches!(time::timeout(timeouts.idle, write).await, Ok(Ok(()))) {
+                    return;
+                }
+            }
+        });
+        let capabilities = handshake.capabilities();
+        extensions.set_your_ip(IpAddr::V4(*peer_addr.ip()));
+        Self{
+            peer_addr,
+            peer_id: handshake.peer_id,
+            has: Bitfield::new(piece_num),
+            fast: capabilities.fast,
+            allowed_fast: BTreeSet::new(),
+            suggested: BTreeSet::new(),
+            extended: capabilities.extension,
+            extensions,
+            encrypted,
+            utp: false,
+            piece_length: info.piece_length,
+            total_length: info.total_length(),
+            limits,
+            timeouts,
+            choked_since: Some(Instant::now()),
+            outgoing,
+            messages,
+            reader_task,
+            writer_task,
+        }
+    }
+
+    pub fn is_choked(&self) -> bool {
+        self.choked_since.is_some()
+    }
+
+    // Wait until the peer unchokes us, giving up once it kept us choked for `UNCHOKE_TIMEOUT`
+    pub async fn wait_unchoke(&mut self) -> Result<()> {
+        while let Some(choked_since) = self.choked_since {
+            let deadline = time::Instant::from_std(choked_since + UNCHOKE_TIMEOUT);
+            match time::timeout_at(deadline, self.next_message()).await {
+                Ok(message) => message?,
+                Err(_) => bail!("{} kept us choked for {:?}", self.peer_addr, UNCHOKE_TIMEOUT),
+            };
+        }
+        Ok(())
+    }
+
+    pub async fn send(&mut self, message: &Message) -> Result<()> {
+        if self.outgoing.send(message.to_bytes()).await.is_err() {
+            bail!("connection to {} closed", self.peer_addr);
+        }
+        Ok(())
+    }
+
+    pub async fn send_extension_handshake(&mut self) -> Result<()> {
+        if self.extended {
+            let handshake = self.extensions.handshake_message()?;
+            self.send(&handshake).await?;
+        }
+        Ok(())
+    }
+
+    // Send a message of an extension, false if the peer does not support it
+    pub async fn send_extended(&mut self, name: &str, payload: Vec<u8>) -> Result<bool> {
+        let Some(message) = self.extensions.message(name, payload) else {
+            return Ok(false);
+        };
+        self.send(&message).await?;
+        Ok(true)
+    }
+
+    // Wait for the next message, keeping track of choking, the pieces the peer has
+    // and what it offers through the fast extension. Extended messages are passed
+    // to the registered extension handlers first.
+    pub async fn next_message(&mut self) -> Result<Message> {
+        let message = match self.messages.recv().await {
+            Some(message) => message?,
+            None => bail!("connection to {} closed", self.peer_addr),
+        };
+        self.check(&message)?;
+        match &message {
+            Message::Choke => {
+                self.choked_since.get_or_insert_with(Instant::now);
+            },
+            Message::Unchoke => self.choked_since = None,
+            Message::Have(piece_index) => {
+                self.has.set(*piece_index as usize, true);
+            },
+            Message::Bitfield(bytes) => self.has = Bitfield::from_bytes(bytes, self.has.len()),
+            Message::HaveAll | Message::HaveNone if !self.fast => bail!("{} sent a fast extension message without negotiating it", self.peer_addr),
+            Message::HaveAll => {
+                for piece_index in 0..self.has.len() {
+                    self.has.set(piece_index, true);
+                }
+            },
+            Message::HaveNone => self.has = Bitfield::new(self.has.len()),
+            Message::AllowedFast(piece_index) => {
+                self.allowed_fast.insert(*piece_index);
+            },
+            Message::Suggest(piece_index) => {
+                self.suggested.insert(*piece_index);
+            },
+            Message::Extended{ .. } if !self.extended => bail!("{} sent an extended message without negotiating it", self.peer_addr),
+            Message::Extended{ id, payload } => {
+                if let Some(reply) = self.extensions.handle(*id, payload)? {
+                    self.send(&reply).await?;
+                }
+            },
+            _ => {},
+        }
+        if self.extended {
+            for extension_message in self.extensions.pending_messages() {
+                self.send(&extension_message).await?;
+            }
+        }
+        Ok(message)
+    }
+
+    // Refuse messages naming pieces or blocks outside the torrent, and bitfields
+    // not sized for it
+    fn check(&self, message: &Message) -> Result<(), ProtocolError> {
+        match *message {
+            Message::Have(index) | Message::Suggest(index) | Message::AllowedFast(index) => self.check_block(index, 0, 0),
+            Message::Request{ index, begin, length }
+            | Message::Cancel{ index, begin, length }
+            | Message::Reject{ index, begin, length } => match length <= self.limits.block {
+                true => self.check_block(index, begin, length),
+                false => Err(ProtocolError::BadBlock{ index, begin, length }),
+            },
+            Message::Piece{ index, begin, ref block } => self.check_block(index, begin, block.len() as u32),
+            Message::Bitfield(ref bytes) if bytes.len() != self.has.len().div_ceil(8) => {
+                Err(ProtocolError::BadBitfield{ length: bytes.len(), piece_num: self.has.len() })
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn check_block(&self, index: u32, begin: u32, length: u32) -> Result<(), ProtocolError> {
+        if index as usize >= self.has.len() {
+            return Err(ProtocolError::BadPiece{ index });
+        }
+        // a torrent with more hashes than data would have pieces starting past the end
+        let piece_start = index as u64 * self.piece_length as u64;
+        let Some(remaining) = self.total_length.checked_sub(piece_start) else {
+            return Err(ProtocolError::BadPiece{ index });
+        };
+        let piece_length = remaining.min(self.piece_length as u64);
+        if begin as u64 + length as u64 > piece_length {
+            return Err(ProtocolError::BadBlock{ index, begin, length });
+        }
+        Ok(())
+    }
+
+    // Download a piece. Blocks are written to storage as they arrive, recorded in
+    // `progress` and announced on `blocks`. Blocks recorded there already, by an
+    // earlier attempt or another connection, are not requested, and requests for
+    // blocks another connection delivers first are cancelled. Returns the number of
+    // bytes this connection delivered.
+    pub async fn download_piece(
+        &mut self,
+        info: &TorrentInfo,
+        piece_index: u32,
+        storage: &dyn Storage,
+        progress: &Mutex<Progress>,
+        blocks: &broadcast::Sender<BlockReceived>
+    ) -> Result<u64> {
+        // subscribe before looking at progress so no block is missed in between
+        let mut announcements = blocks.subscribe();
+        let piece_length = info.get_piece_length_real(piece_index);
+        let block_length = |block_index: u32| BLOCK_SIZE.min(piece_length - block_index * BLOCK_SIZE);
+
+        // Blocks still needed from this peer and the ones requested. A choke drops
+        // every request unless the fast extension is on, then the peer rejects the
+        // ones it drops, and keeps serving allowed fast pieces while choking us.
+        let received = progress.lock().unwrap().received_blocks(info, piece_index);
+        let mut needed: BTreeSet<u32> = (0..piece_length.div_ceil(BLOCK_SIZE))
+            .filter(|&block_index| !received.get(block_index as usize))
+            .collect();
+        let mut requested = BTreeSet::new();
+        let mut delivered_bytes = 0;
+        // since the last block arrived, or requests went out with none outstanding
+        let mut waiting_since = time::Instant::now();
+
+        while !needed.is_empty() {
+            if !self.is_choked() || self.allowed_fast.contains(&piece_index) {
+                let unrequested: Vec<u32> = needed.difference(&requested).copied().collect();
+                if requested.is_empty() {
+                    waiting_since = time::Instant::now();
+                }
+                for block_index in unrequested {
+                    let request = Message::Request{ index: piece_index, begin: block_index * BLOCK_SIZE, length: block_length(block_index) };
+                    self.send(&request).await?;
+                    requested.insert(block_index);
+                }
+            }
+            // only a choke that stalls the piece counts against the peer
+            let unchoke_deadline = match requested.is_empty() {
+                true => self.choked_since.map(|choked_since| time::Instant::from_std(choked_since + UNCHOKE_TIMEOUT)),
+                false => None,
+            };
+            let request_deadline = match requested.is_empty() {
+                true => None,
+                false => Some(waiting_since + self.timeouts.request),
+            };
+
+            tokio::select! {
+                message = self.next_message() => match message? {
+                    Message::Piece{ index, begin, block } if index == piece_index && begin % BLOCK_SIZE == 0 => {
+                        let block_index = begin / BLOCK_SIZE;
+                        if !needed.contains(&block_index) {
+                            continue; // a late answer to a cancelled request
+                        }
+                        if block.len() != block_length(block_index) as usize {
+                            bail!("block {} of piece {} has {} bytes", block_index, piece_index, block.len());
+                        }
+                        needed.remove(&block_index);
+                        requested.remove(&block_index);
+                        waiting_since = time::Instant::now();
+                        delivered_bytes += block.len() as u64;
+                        storage.write_block(piece_index, begin, &block)?;
+                        progress.lock().unwrap().block_received(info, piece_index, block_index as usize);
+                        let _ = blocks.send(BlockReceived{ piece_index, block_index });
+                    },
+                    // the peer discards our requests, they are sent again once it unchokes us
+                    Message::Choke if !self.fast => requested.clear(),
+                    Message::Reject{ index, begin, .. } if index == piece_index && requested.remove(&(begin / BLOCK_SIZE)) => {
+                        if !self.is_choked() {
+                            bail!("{} rejected block {} of piece {}", self.peer_addr, begin / BLOCK_SIZE, piece_index);
+                        }
+                        // dropped by a choke, the piece is not (or no longer) allowed
+                        // fast, so the block is asked for again after the unchoke
+                        self.allowed_fast.remove(&piece_index);
+                    },
+                    _ => {},
+                },
+                announcement = announcements.recv() => {
+                    let delivered: Vec<u32> = match announcement {
+                        Ok(BlockReceived{ piece_index: index, block_index }) if index == piece_index => vec![block_index],
+                        Ok(_) => continue,
+                        // missed some announcements, fall back to what progress says
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            let received = progress.lock().unwrap().received_blocks(info, piece_index);
+                            needed.iter().copied().filter(|&block_index| received.get(block_index as usize)).collect()
+                        },
+                        Err(broadcast::error::RecvError::Closed) => continue,
+                    };
+                    for block_index in delivered {
+                        needed.remove(&block_index);
+                        if requested.remove(&block_index) {
+                            let cancel = Message::Cancel{ index: piece_index, begin: block_index * BLOCK_SIZE, length: block_length(block_index) };
+                            self.send(&cancel).await?;
+                        }
+                    }
+                },
+                _ = time::sleep_until(unchoke_deadline.unwrap_or_else(time::Instant::now)), if unchoke_deadline.is_some() => {
+                    bail!("{} kept us choked for {:?} in the middle of piece {}", self.peer_addr, UNCHOKE_TIMEOUT, piece_index);
+                },
+                _ = time::sleep_until(request_deadline.unwrap_or_else(time::Instant::now)), if request_deadline.is_some() => {
+                    bail!("{} sent no block of piece {} for {:?}", self.peer_addr, piece_index, self.timeouts.request);
+                },
+            }
+        }
+        // every block is in storage now, ours or from another connection
+        if progress.lock().unwrap().have.get(piece_index as usize) {
+            return Ok(delivered_bytes);
+        }
+        let piece = storage.read_block(piece_index, 0, piece_length)?;
+        if encoder::encode_sha1(&piece)? != info.get_piece_hash(piece_index) {
+            progress.lock().unwrap().piece_failed(piece_index);
+            bail!("piece {} failed the hash check", piece_index);
+        }
+        progress.lock().unwrap().piece_verified(piece_index);
+        Ok(delivered_bytes)
+    }
+}
+
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        self.reader_task.abort();
+        self.writer_task.abort();
+    }
+}
+
 pub async fn magnet_handshake(
     peer_addr: SocketAddrV4,
     info_hash: &[u8],
     enable_extension: bool,
+    transport: &dyn Transport,
 ) -> Result<()> {
-    let mut stream = TcpStream::connect(peer_addr).await?;
-    let peer_id = Peer::gen_peer_id();
+    let timeouts = PeerTimeouts::default();
+    let mut stream = within(timeouts.connect, transport.connect(peer_addr), "connecting to", peer_addr).await?;
+    let capabilities = Capabilities{ extension: enable_extension, ..Default::default() };
 
     // Create handshake message and send
-    let handshake_message = message::handshake_message(info_hash, &peer_id, enable_extension);
-    stream.write_all(&handshake_message).await?;
+    let handshake = Handshake::new(info_hash, Peer::gen_peer_id(), capabilities)?;
+    stream.write_all(&handshake.to_bytes()).await?;
     
     // Read handshake response
-    let mut buffer = [0u8; 68];
-    stream.read(&mut buffer).await?;
-    println!("Peer ID: {}", hex::encode(&buffer[48..]));
+    let peer_handshake = within(timeouts.handshake, Handshake::read(&mut stream), "handshake with", peer_addr).await?;
+    peer_handshake.validate(info_hash, None)?;
+    println!("Peer ID: {}", hex::encode(peer_handshake.peer_id));
     
     // if support extension
-    if buffer[25] == 16 {
+    if enable_extension && peer_handshake.capabilities().extension {
         // Read bitfield message
-        let mut length_bytes = [0u8; 4];
-        stream.read_exact(&mut length_bytes).await?;
-        let length = u32::from_be_bytes(length_bytes);
-        let mut buffer = vec![0u8; length as usize];
-        stream.read_exact(&mut buffer).await?;
+        within(timeouts.request, message::read_body(&mut stream, &MessageLimits::default()), "reading from", peer_addr).await?;
 
         // Create extension message and send
-        let extension_message = message::extension_handshake_message()?;
-        stream.write_all(&extension_message).await?;
+        let mut extensions = ExtensionRegistry::new();
+        extensions.declare("ut_metadata");
+        stream.write_all(&extensions.handshake_message()?.to_bytes()).await?;
 
         // Read extension response
-        let mut length_bytes = [0u8; 4];
-        stream.read_exact(&mut length_bytes).await?;
-        let length = u32::from_be_bytes(length_bytes);
-        let mut buffer = vec![0u8; length as usize];
-        stream.read_exact(&mut buffer).await?;
+        let buffer = within(timeouts.request, message::read_body(&mut stream, &MessageLimits::default()), "reading from", peer_addr).await?;
 
-        let eh_dict: ExtensionHandshakeDict = serde_bencode::from_bytes(&buffer[2..])?;
-        println!("Peer Metadata Extension ID: {}", eh_dict.m.ut_metadata);
+        let handshake = ExtensionHandshake::parse(extended_payload(&buffer, peer_addr)?)?;
+        let Some(metadata_id) = handshake.extension_id("ut_metadata") else {
+            bail!("{} does not support ut_metadata", peer_addr);
+        };
+        println!("Peer Metadata Extension ID: {}", metadata_id);
     }
 
     Ok(())
@@ -187,53 +619,70 @@ pub async fn magnet_request_info(
     peer_addr: SocketAddrV4,
     info_hash: &[u8],
     enable_extension: bool,
+    transport: &dyn Transport,
 ) -> Result<TorrentInfo> {
-    let mut stream = TcpStream::connect(peer_addr).await?;
-    let peer_id = Peer::gen_peer_id();
+    let timeouts = PeerTimeouts::default();
+    let mut stream = within(timeouts.connect, transport.connect(peer_addr), "connecting to", peer_addr).await?;
+    let capabilities = Capabilities{ extension: enable_extension, ..Default::default() };
 
     // Create handshake message and send
-    let handshake_message = message::handshake_message(info_hash, &peer_id, enable_extension);
-    stream.write_all(&handshake_message).await?;
+    let handshake = Handshake::new(info_hash, Peer::gen_peer_id(), capabilities)?;
+    stream.write_all(&handshake.to_bytes()).await?;
     
     // Read handshake response
-    let mut buffer = [0u8; 68];
-    stream.read(&mut buffer).await?;
+    let peer_handshake = within(timeouts.handshake, Handshake::read(&mut stream), "handshake with", peer_addr).await?;
+    peer_handshake.validate(info_hash, None)?;
+    if !peer_handshake.capabilities().extension {
+        bail!("{} does not support the extension protocol", peer_addr);
+    }
     
     // Read bitfield message
-    let mut length_bytes = [0u8; 4];
-    stream.read_exact(&mut length_bytes).await?;
-    let length = u32::from_be_bytes(length_bytes);
-    let mut buffer = vec![0u8; length as usize];
-    stream.read_exact(&mut buffer).await?;
+    within(timeouts.request, message::read_body(&mut stream, &MessageLimits::default()), "reading from", peer_addr).await?;
 
     // Create extension handshake message and send
-    let eh_message = message::extension_handshake_message()?;
-    stream.write_all(&eh_message).await?;
+    let mut extensions = ExtensionRegistry::new();
+    extensions.declare("ut_metadata");
+    stream.write_all(&extensions.handshake_message()?.to_bytes()).await?;
 
     // Read extension handshake response
-    let mut length_bytes = [0u8; 4];
-    stream.read_exact(&mut length_bytes).await?;
-    let length = u32::from_be_bytes(length_bytes);
-    let mut buffer = vec![0u8; length as usize];
-    stream.read_exact(&mut buffer).await?;
-    let ehr_dict: ExtensionHandshakeDict = serde_bencode::from_bytes(&buffer[2..])?;
-    let metadata_id = ehr_dict.m.ut_metadata;
+    let buffer = within(timeouts.request, message::read_body(&mut stream, &MessageLimits::default()), "reading from", peer_addr).await?;
+    let handshake = ExtensionHandshake::parse(extended_payload(&buffer, peer_addr)?)?;
+    let Some(metadata_id) = handshake.extension_id("ut_metadata") else {
+        bail!("{} does not support ut_metadata", peer_addr);
+    };
 
     // Create extension request message and send
     let er_message = message::extension_request_message(metadata_id)?;
     stream.write_all(&er_message).await?;
 
     // Read extension request response
-    let mut length_bytes = [0u8; 4];
-    stream.read_exact(&mut length_bytes).await?;
-    let length = u32::from_be_bytes(length_bytes);
-    let mut buffer = vec![0u8; length as usize];
-    stream.read_exact(&mut buffer).await?;
+    let buffer = within(timeouts.request, message::read_body(&mut stream, &MessageLimits::default()), "reading from", peer_addr).await?;
 
-    type ERR = (ExtensionRequestDict, TorrentInfo);
+    // the reply dict is followed by the raw info dict, which must hash to
+    // info_hash before anything in it is trusted
+    let payload = extended_payload(&buffer, peer_addr)?;
     let mut completed_bytes = vec![b'l'];
-    completed_bytes.extend_from_slice(&buffer[2..]);
+    completed_bytes.extend_from_slice(payload);
     completed_bytes.push(b'e');
-    let err_tuple: ERR = serde_bencode::from_bytes(&completed_bytes)?;
-    Ok(err_tuple.1)
-}
\ No newline at end of file
+    let (reply, _): (ExtensionRequestDict, IgnoredAny) = decoder::from_bencode(&completed_bytes)?;
+    let Some(total_size) = reply.total_size() else {
+        bail!("{} sent no metadata", peer_addr);
+    };
+    let Some(raw_info) = payload.len().checked_sub(total_size as usize).map(|start| &payload[start..]) else {
+        bail!("{} sent {} bytes of metadata out of {}", peer_addr, payload.len(), total_size);
+    };
+    if encoder::encode_sha1(raw_info)? != info_hash {
+        bail!("metadata from {} does not match the info hash", peer_addr);
+    }
+    let info: TorrentInfo = decoder::from_bencode(raw_info)?;
+    info.validate()?;
+    Ok(info)
+}
+
+// an extended message after its message id and extension id
+fn extended_payload(body: &[u8], peer_addr: SocketAddrV4) -> Result<&[u8]> {
+    match body.get(2..) {
+        Some(payload) => Ok(payload),
+        None => bail!("{} sent a truncated extended message", peer_addr),
+    }
+}
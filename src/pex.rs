@@ -0,0 +1,267 @@
+// pex.rs
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use serde_bytes::ByteBuf;
+use serde_bencode;
+use tokio::sync::mpsc;
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::decoder;
+use crate::extension::{ExtensionHandler, ExtensionHandshake};
+use crate::tracker;
+
+pub const EXTENSION_NAME: &str = "ut_pex";
+
+// BEP 11 asks for at most one message a minute per connection
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+// peers listed as added or dropped in one message, more wait for the next one
+const MAX_PEX_PEERS: usize = 50;
+
+// a peer sending faster than this has its extra messages ignored
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+
+// peers remembered from what others listed, further ones are not remembered
+const MAX_LEARNED_PEERS: usize = 1000;
+
+// flags sent along with each added peer
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+pub const FLAG_HOLEPUNCH: u8 = 0x08;
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+// The ut_pex payload, IPv4 peers only. `added.f` has one flag byte per added peer.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    pub added_flags: ByteBuf,
+    #[serde(default)]
+    pub dropped: ByteBuf,
+}
+
+impl PexMessage {
+    pub fn new(added: &[(SocketAddrV4, u8)], dropped: &[SocketAddrV4]) -> Self {
+        Self{
+            added: ByteBuf::from(tracker::encode_compact_peers(added.iter().map(|(addr, _)| addr))),
+            added_flags: ByteBuf::from(added.iter().map(|(_, flags)| *flags).collect::<Vec<u8>>()),
+            dropped: ByteBuf::from(tracker::encode_compact_peers(dropped)),
+        }
+    }
+
+    pub fn parse(payload: &[u8]) -> Result<Self> {
+        decoder::from_bencode(payload)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    // added peers with their flags, 0 where the peer sent none
+    pub fn added_peers(&self) -> Vec<(SocketAddrV4, u8)> {
+        tracker::decode_compact_peers(&self.added)
+            .into_iter()
+            .enumerate()
+            .map(|(index, addr)| (addr, self.added_flags.get(index).copied().unwrap_or(0)))
+            .collect()
+    }
+
+    pub fn dropped_peers(&self) -> Vec<SocketAddrV4> {
+        tracker::decode_compact_peers(&self.dropped)
+    }
+}
+
+// A peer we are connected to, possibly more than once
+struct Listed {
+    flags: u8,
+    connections: usize,
+}
+
+// The peers we are connected to, shared by every connection of a torrent so each
+// can tell its peer about the others. Peers learned from PEX go to `discovered`,
+// along with the ones other sources like the DHT find.
+pub struct PexSwarm {
+    connected: Mutex<HashMap<SocketAddrV4, Listed>>,
+    learned: Mutex<HashMap<SocketAddrV4, Option<u8>>>, // flags others listed peers with, None once dropped
+    discovered: mpsc::UnboundedSender<SocketAddrV4>,
+}
+
+impl PexSwarm {
+    pub fn new(discovered: mpsc::UnboundedSender<SocketAddrV4>) -> Arc<Self> {
+        Arc::new(Self{ connected: Mutex::new(HashMap::new()), learned: Mutex::new(HashMap::new()), discovered })
+    }
+
+    // the peers we are connected to and their flags
+    pub fn connected(&self) -> HashMap<SocketAddrV4, u8> {
+        self.connected.lock().unwrap().iter().map(|(&addr, listed)| (addr, listed.flags)).collect()
+    }
+
+    // the flags other peers last listed `peer_addr` with, 0 if none did
+    pub fn learned_flags(&self, peer_addr: SocketAddrV4) -> u8 {
+        self.learned.lock().unwrap().get(&peer_addr).copied().flatten().unwrap_or(0)
+    }
+
+    // whether the last peer to mention `peer_addr` listed it as dropped
+    pub fn is_dropped(&self, peer_addr: SocketAddrV4) -> bool {
+        matches!(self.learned.lock().unwrap().get(&peer_addr), Some(None))
+    }
+
+    fn list(&self, addr: SocketAddrV4, flags: u8) {
+        let mut connected = self.connected.lock().unwrap();
+        let listed = connected.entry(addr).or_insert(Listed{ flags: 0, connections: 0 });
+        listed.flags |= flags;
+        listed.connections += 1;
+    }
+
+    // a peer stays listed until its last connection closes
+    fn unlist(&self, addr: SocketAddrV4) {
+        if let Entry::Occupied(mut entry) = self.connected.lock().unwrap().entry(addr) {
+            entry.get_mut().connections -= 1;
+            if entry.get().connections == 0 {
+                entry.remove();
+            }
+        }
+    }
+
+    // The handler for one connection. Peers we connected to are reachable at
+    // `peer_addr` but listed only by `mark_connected` once the handshake is done,
+    // ones that connected to us are listed once they tell their port.
+    pub fn handler(self: &Arc<Self>, peer_addr: SocketAddrV4, outgoing: bool) -> PexHandler {
+        PexHandler{
+            swarm: self.clone(),
+            peer_addr,
+            listed: outgoing.then_some(peer_addr),
+            owns_listing: false,
+            enabled: false,
+            sent: HashMap::new(),
+            next_send: Instant::now(),
+            last_received: None,
+        }
+    }
+
+    // List a peer we connected to as reachable, until the returned listing is
+    // dropped along with the connection
+    pub fn mark_connected(self: &Arc<Self>, peer_addr: SocketAddrV4) -> PexListing {
+        self.list(peer_addr, FLAG_REACHABLE);
+        PexListing{ swarm: self.clone(), peer_addr }
+    }
+
+    pub fn add_flags(&self, peer_addr: SocketAddrV4, flags: u8) {
+        if let Some(listed) = self.connected.lock().unwrap().get_mut(&peer_addr) {
+            listed.flags |= flags;
+        }
+    }
+}
+
+// ut_pex on one connection: every `PEX_INTERVAL` the peer hears which peers we
+// connected to or dropped since the last message, and the peers it lists are
+// handed to the swarm along with their flags, or marked as gone when it drops them
+pub struct PexHandler {
+    swarm: Arc<PexSwarm>,
+    peer_addr: SocketAddrV4,
+    listed: Option<SocketAddrV4>, // this peer in the swarm's connected set
+    owns_listing: bool,           // listed by this handler, which unlists it
+    enabled: bool,                // the peer supports ut_pex
+    sent: HashMap<SocketAddrV4, u8>,
+    next_send: Instant,
+    last_received: Option<Instant>,
+}
+
+impl ExtensionHandler for PexHandler {
+    fn on_handshake(&mut self, handshake: &ExtensionHandshake) -> Result<()> {
+        self.enabled = handshake.extension_id(EXTENSION_NAME).is_some();
+        // a peer that connected to us is listed under the port it listens on
+        if let (None, Some(port)) = (self.listed, handshake.p.filter(|&port| port != 0)) {
+            let addr = SocketAddrV4::new(*self.peer_addr.ip(), port);
+            self.swarm.list(addr, 0);
+            self.listed = Some(addr);
+            self.owns_listing = true;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        let now = Instant::now();
+        if self.last_received.is_some_and(|last_received| now < last_received + MIN_RECEIVE_INTERVAL) {
+            return Ok(None);
+        }
+        self.last_received = Some(now);
+        let message = PexMessage::parse(payload)?;
+        let mut learned = self.swarm.learned.lock().unwrap();
+        for addr in message.dropped_peers().into_iter().take(MAX_PEX_PEERS) {
+            if let Some(flags) = learned.get_mut(&addr) {
+                *flags = None;
+            }
+        }
+        for (addr, flags) in message.added_peers().into_iter().take(MAX_PEX_PEERS) {
+            if learned.len() < MAX_LEARNED_PEERS || learned.contains_key(&addr) {
+                learned.insert(addr, Some(flags));
+            }
+            let _ = self.swarm.discovered.send(addr);
+        }
+        Ok(None)
+    }
+
+    fn pending(&mut self) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        if !self.enabled || now < self.next_send {
+            return None;
+        }
+        self.next_send = now + PEX_INTERVAL;
+
+        let mut connected = self.swarm.connected();
+        if let Some(addr) = self.listed {
+            connected.remove(&addr);
+        }
+        let added: Vec<(SocketAddrV4, u8)> = connected
+            .iter()
+            .filter(|(addr, flags)| self.sent.get(addr) != Some(flags))
+            .map(|(&addr, &flags)| (addr, flags))
+            .take(MAX_PEX_PEERS)
+            .collect();
+        let dropped: Vec<SocketAddrV4> = self.sent
+            .keys()
+            .filter(|addr| !connected.contains_key(addr))
+            .copied()
+            .take(MAX_PEX_PEERS)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        for &(addr, flags) in &added {
+            self.sent.insert(addr, flags);
+        }
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        PexMessage::new(&added, &dropped).to_bytes().ok()
+    }
+}
+
+impl Drop for PexHandler {
+    fn drop(&mut self) {
+        if let (true, Some(addr)) = (self.owns_listing, self.listed) {
+            self.swarm.unlist(addr);
+        }
+    }
+}
+
+// A peer we connected to, listed while this lives
+pub struct PexListing {
+    swarm: Arc<PexSwarm>,
+    peer_addr: SocketAddrV4,
+}
+
+impl Drop for PexListing {
+    fn drop(&mut self) {
+        self.swarm.unlist(self.peer_addr);
+    }
+}
@@ -0,0 +1,234 @@
+// picker.rs
+
+use glob::Pattern;
+use rand;
+
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::bitfield::Bitfield;
+use crate::torrent::TorrentInfo;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+// how far past the first missing piece sequential mode may hand out pieces
+pub const SEQUENTIAL_WINDOW: u32 = 16;
+
+// Decides which pieces to fetch and in which order. Peer tasks pick a piece at a
+// time and release it again if the download fails.
+pub struct PiecePicker {
+    priorities: Vec<Priority>,
+    order: BTreeSet<(Reverse<Priority>, u32)>, // wanted pieces, highest priority first
+    in_flight: HashMap<u32, u32>, // peers fetching each piece, more than one in endgame
+    sequential: bool,
+    deadlines: HashMap<u32, Instant>,
+}
+
+impl PiecePicker {
+    pub fn new(piece_num: usize) -> Self {
+        Self::with_priorities(vec![Priority::Normal; piece_num])
+    }
+
+    fn with_priorities(priorities: Vec<Priority>) -> Self {
+        let order = priorities
+            .iter()
+            .enumerate()
+            .filter(|(_, &priority)| priority != Priority::Skip)
+            .map(|(piece_index, &priority)| (Reverse(priority), piece_index as u32))
+            .collect();
+        Self{ priorities, order, in_flight: HashMap::new(), sequential: false, deadlines: HashMap::new() }
+    }
+
+    // A piece takes the highest priority of the files it overlaps, so pieces
+    // shared between a skipped and a wanted file are still fetched
+    pub fn from_file_priorities(info: &TorrentInfo, file_priorities: &[Priority]) -> Self {
+        let mut priorities = vec![Priority::Skip; info.get_piece_num()];
+        let piece_length = info.piece_length as u64;
+        for (slot, &priority) in info.get_file_slots(Path::new("")).iter().zip(file_priorities) {
+            if slot.length == 0 {
+                continue;
+            }
+            let first_piece = slot.offset / piece_length;
+            let last_piece = (slot.offset + slot.length - 1) / piece_length;
+            for piece_index in first_piece..=last_piece {
+                let piece_priority = &mut priorities[piece_index as usize];
+                *piece_priority = (*piece_priority).max(priority);
+            }
+        }
+        Self::with_priorities(priorities)
+    }
+
+    // Fetch the lowest missing pieces first, at most `SEQUENTIAL_WINDOW` pieces
+    // ahead of the first missing one, so data completes roughly in order
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    // Raise the first and last piece of every wanted file to high priority,
+    // media containers keep their index at either end
+    pub fn prioritize_file_ends(&mut self, info: &TorrentInfo) {
+        let piece_length = info.piece_length as u64;
+        for slot in info.get_file_slots(Path::new("")) {
+            if slot.length == 0 {
+                continue;
+            }
+            let first_piece = (slot.offset / piece_length) as u32;
+            let last_piece = ((slot.offset + slot.length - 1) / piece_length) as u32;
+            let wanted = (first_piece..=last_piece).all(|piece_index| self.priority(piece_index) != Priority::Skip);
+            if wanted {
+                self.set_priority(first_piece, Priority::High);
+                self.set_priority(last_piece, Priority::High);
+            }
+        }
+    }
+
+    pub fn priority(&self, piece_index: u32) -> Priority {
+        self.priorities[piece_index as usize]
+    }
+
+    pub fn set_priority(&mut self, piece_index: u32, priority: Priority) {
+        let previous = std::mem::replace(&mut self.priorities[piece_index as usize], priority);
+        self.order.remove(&(Reverse(previous), piece_index));
+        if priority != Priority::Skip {
+            self.order.insert((Reverse(priority), piece_index));
+        }
+    }
+
+    // A reader needs the piece by `deadline`. Pieces with a deadline are picked
+    // before any other, earliest first. Skipped pieces are left alone.
+    pub fn set_deadline(&mut self, piece_index: u32, deadline: Instant) {
+        if self.priority(piece_index) == Priority::Skip {
+            return;
+        }
+        let entry = self.deadlines.entry(piece_index).or_insert(deadline);
+        *entry = (*entry).min(deadline);
+    }
+
+    // the reader moved on, the piece goes back to its priority
+    pub fn clear_deadline(&mut self, piece_index: u32) {
+        self.deadlines.remove(&piece_index);
+    }
+
+    // pieces not skipped
+    pub fn wanted(&self) -> Bitfield {
+        let mut wanted = Bitfield::new(self.priorities.len());
+        for (piece_index, &priority) in self.priorities.iter().enumerate() {
+            wanted.set(piece_index, priority != Priority::Skip);
+        }
+        wanted
+    }
+
+    // Hand out the next piece to fetch among those the peer has (`available`): by
+    // deadline, then by priority, then in order in sequential mode, otherwise one
+    // the peer suggested or else at random so peers spread over the payload.
+    // None when nothing is left to pick right now.
+    pub fn pick(&mut self, have: &Bitfield, available: &Bitfield, suggested: &BTreeSet<u32>) -> Option<u32> {
+        self.deadlines.retain(|&piece_index, _| !have.get(piece_index as usize));
+        let urgent = self.deadlines
+            .iter()
+            .filter(|(&piece_index, _)| !self.is_in_flight(piece_index) && available.get(piece_index as usize))
+            .min_by_key(|(&piece_index, &deadline)| (deadline, piece_index))
+            .map(|(&piece_index, _)| piece_index);
+        if let Some(piece_index) = urgent {
+            *self.in_flight.entry(piece_index).or_insert(0) += 1;
+            return Some(piece_index);
+        }
+
+        let mut candidates = self.missing(have)
+            .filter(|&piece_index| !self.is_in_flight(piece_index) && available.get(piece_index as usize))
+            .peekable();
+        let best_priority = self.priority(*candidates.peek()?);
+        let piece_index = match self.sequential {
+            true => {
+                let piece_index = candidates.next()?;
+                drop(candidates);
+                let first_missing = (0..self.priorities.len() as u32)
+                    .find(|&piece_index| self.priority(piece_index) != Priority::Skip && !have.get(piece_index as usize))?;
+                if best_priority < Priority::High && piece_index >= first_missing + SEQUENTIAL_WINDOW {
+                    return None;
+                }
+                piece_index
+            },
+            false => {
+                let best: Vec<u32> = candidates
+                    .take_while(|&piece_index| self.priority(piece_index) == best_priority)
+                    .collect();
+                match best.iter().find(|piece_index| suggested.contains(piece_index)) {
+                    Some(&piece_index) => piece_index,
+                    None => best[rand::random_range(0..best.len())],
+                }
+            },
+        };
+        *self.in_flight.entry(piece_index).or_insert(0) += 1;
+        Some(piece_index)
+    }
+
+    // Endgame: every missing piece is already being fetched, so hand out the one
+    // the peer has with the fewest peers on it, to be fetched a second time.
+    // None outside endgame.
+    pub fn pick_endgame(&mut self, have: &Bitfield, available: &Bitfield) -> Option<u32> {
+        if self.missing(have).any(|piece_index| !self.is_in_flight(piece_index)) {
+            return None;
+        }
+        let piece_index = self.missing(have)
+            .filter(|&piece_index| available.get(piece_index as usize))
+            .min_by_key(|piece_index| self.in_flight[piece_index])?;
+        *self.in_flight.entry(piece_index).or_insert(0) += 1;
+        Some(piece_index)
+    }
+
+    // one peer is done with the piece or failed it
+    pub fn release(&mut self, piece_index: u32) {
+        if let Some(peers) = self.in_flight.get_mut(&piece_index) {
+            *peers -= 1;
+            if *peers == 0 {
+                self.in_flight.remove(&piece_index);
+            }
+        }
+    }
+
+    fn is_in_flight(&self, piece_index: u32) -> bool {
+        self.in_flight.contains_key(&piece_index)
+    }
+
+    // Wanted pieces we do not have, highest priority first and in order within
+    // a priority
+    pub fn missing_pieces(&self, have: &Bitfield) -> Vec<u32> {
+        self.missing(have).collect()
+    }
+
+    fn missing<'a>(&'a self, have: &'a Bitfield) -> impl Iterator<Item = u32> + 'a {
+        self.order
+            .iter()
+            .map(|&(_, piece_index)| piece_index)
+            .filter(|&piece_index| !have.get(piece_index as usize))
+    }
+}
+
+// File priorities from `--only` / `--exclude` globs, matched against each file's
+// path inside the torrent. With no `only` patterns every file is wanted.
+pub fn select_files(info: &TorrentInfo, only: &[Pattern], exclude: &[Pattern]) -> Vec<Priority> {
+    info.get_file_slots(Path::new(""))
+        .iter()
+        .map(|slot| {
+            let path = match slot.path.as_os_str().is_empty() {
+                true => Path::new(&info.name),
+                false => slot.path.as_path(),
+            };
+            let included = only.is_empty() || only.iter().any(|pattern| pattern.matches_path(path));
+            let excluded = exclude.iter().any(|pattern| pattern.matches_path(path));
+            match included && !excluded {
+                true => Priority::Normal,
+                false => Priority::Skip,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,206 @@
+// reader.rs
+
+use anyhow::{bail, Result};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::download::DownloadHandle;
+
+// pieces past the read position requested ahead of time
+const DEFAULT_READAHEAD: u32 = 4;
+
+// verified pieces kept in memory so small reads do not hit storage every time
+const DEFAULT_CACHE_PIECES: usize = 8;
+
+// how much later each readahead piece is due than the one before it
+const READAHEAD_SPACING: Duration = Duration::from_secs(1);
+
+type PieceFuture = Pin<Box<dyn Future<Output = Result<Arc<Vec<u8>>>> + Send>>;
+
+// Reads the torrent's payload as one byte stream while it downloads. A read
+// blocks until its piece is verified, the piece is due immediately and the next
+// few pieces get later deadlines, so the picker fetches them ahead of the rest.
+pub struct TorrentReader {
+    handle: Arc<DownloadHandle>,
+    range: Range<u64>,   // bytes of the torrent's stream this reader covers
+    position: u64,       // relative to range.start
+    readahead: u32,
+    cache: PieceCache,
+    pending: Option<(u32, PieceFuture)>,
+    deadlines: Vec<u32>, // pieces this reader set a deadline on
+}
+
+impl TorrentReader {
+    // the whole payload, files back to back
+    pub fn new(handle: Arc<DownloadHandle>) -> Self {
+        let length = handle.info().total_length();
+        Self::with_range(handle, 0..length)
+    }
+
+    // a single file of the torrent, by its index in the file list
+    pub fn for_file(handle: Arc<DownloadHandle>, file_index: usize) -> Result<Self> {
+        let slots = handle.info().get_file_slots(Path::new(""));
+        let Some(slot) = slots.get(file_index) else {
+            bail!("file index {} out of range, the torrent has {} files", file_index, slots.len());
+        };
+        let range = slot.offset..slot.offset + slot.length;
+        Ok(Self::with_range(handle, range))
+    }
+
+    fn with_range(handle: Arc<DownloadHandle>, range: Range<u64>) -> Self {
+        Self{
+            handle,
+            range,
+            position: 0,
+            readahead: DEFAULT_READAHEAD,
+            cache: PieceCache::new(DEFAULT_CACHE_PIECES),
+            pending: None,
+            deadlines: Vec::new(),
+        }
+    }
+
+    pub fn set_readahead(&mut self, pieces: u32) {
+        self.readahead = pieces;
+    }
+
+    pub fn len(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+
+    // Deadlines for the piece being read and the readahead after it, never past
+    // the end of the range. The previous ones go first so pieces left behind do
+    // not keep their earlier deadlines.
+    fn request_pieces(&mut self, piece_index: u32) {
+        self.clear_deadlines();
+        let piece_length = self.handle.info().piece_length as u64;
+        let last_piece = ((self.range.end - 1) / piece_length) as u32;
+        let now = Instant::now();
+        for (ahead, readahead_index) in (piece_index..=last_piece.min(piece_index + self.readahead)).enumerate() {
+            self.handle.set_deadline(readahead_index, now + READAHEAD_SPACING * ahead as u32);
+            self.deadlines.push(readahead_index);
+        }
+    }
+
+    fn clear_deadlines(&mut self) {
+        self.handle.clear_deadlines(self.deadlines.drain(..));
+    }
+
+    fn fetch_piece(&self, piece_index: u32) -> PieceFuture {
+        let handle = self.handle.clone();
+        Box::pin(async move {
+            handle.wait_piece(piece_index).await?;
+            let length = handle.info().get_piece_length_real(piece_index);
+            Ok(Arc::new(handle.storage().read_block(piece_index, 0, length)?))
+        })
+    }
+}
+
+impl AsyncRead for TorrentReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.position >= this.len() || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let piece_length = this.handle.info().piece_length as u64;
+        let offset = this.range.start + this.position;
+        let piece_index = (offset / piece_length) as u32;
+
+        let data = match this.cache.get(piece_index) {
+            Some(data) => data,
+            None => {
+                if this.pending.as_ref().is_none_or(|(pending_index, _)| *pending_index != piece_index) {
+                    this.request_pieces(piece_index);
+                    this.pending = Some((piece_index, this.fetch_piece(piece_index)));
+                }
+                let (_, future) = this.pending.as_mut().unwrap();
+                let result = match future.as_mut().poll(cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => return Poll::Pending,
+                };
+                this.pending = None;
+                let data = result.map_err(io::Error::other)?;
+                this.cache.insert(piece_index, data.clone());
+                data
+            },
+        };
+
+        let offset_in_piece = (offset - piece_index as u64 * piece_length) as usize;
+        let available = (data.len() - offset_in_piece).min((this.len() - this.position) as usize);
+        let length = available.min(buf.remaining());
+        buf.put_slice(&data[offset_in_piece..offset_in_piece + length]);
+        this.position += length as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for TorrentReader {
+    // seeking past the end is allowed, reads there return end of file
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        match target {
+            Some(target) => {
+                self.position = target;
+                // the readahead around the old position is not needed first
+                // anymore, the next read sets new deadlines
+                self.clear_deadlines();
+                self.pending = None;
+                Ok(())
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+impl Drop for TorrentReader {
+    fn drop(&mut self) {
+        self.clear_deadlines();
+    }
+}
+
+// the most recently used verified pieces
+struct PieceCache {
+    capacity: usize,
+    pieces: VecDeque<(u32, Arc<Vec<u8>>)>,
+}
+
+impl PieceCache {
+    fn new(capacity: usize) -> Self {
+        Self{ capacity, pieces: VecDeque::with_capacity(capacity) }
+    }
+
+    fn get(&mut self, piece_index: u32) -> Option<Arc<Vec<u8>>> {
+        let position = self.pieces.iter().position(|(cached_index, _)| *cached_index == piece_index)?;
+        let entry = self.pieces.remove(position)?;
+        let data = entry.1.clone();
+        self.pieces.push_front(entry);
+        Some(data)
+    }
+
+    fn insert(&mut self, piece_index: u32, data: Arc<Vec<u8>>) {
+        if self.pieces.len() == self.capacity {
+            self.pieces.pop_back();
+        }
+        self.pieces.push_front((piece_index, data));
+    }
+}
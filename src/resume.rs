@@ -0,0 +1,189 @@
+// resume.rs
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use serde_bencode;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::bitfield::Bitfield;
+use crate::decoder;
+use crate::peer::BLOCK_SIZE;
+use crate::torrent::{FileSlot, TorrentInfo};
+use crate::tracker;
+use crate::utils;
+
+// Live download progress, shared by the piece tasks and snapshotted into fast-resume files
+pub struct Progress {
+    pub have: Bitfield,                     // verified pieces
+    pub unfinished: HashMap<u32, Bitfield>, // blocks already written for pieces in progress
+    pub peers: BTreeSet<SocketAddrV4>,
+    pub tracker_ids: BTreeMap<String, String>,
+}
+
+impl Progress {
+    pub fn new(piece_num: usize) -> Self {
+        Self{
+            have: Bitfield::new(piece_num),
+            unfinished: HashMap::new(),
+            peers: BTreeSet::new(),
+            tracker_ids: BTreeMap::new(),
+        }
+    }
+
+    // blocks of the piece already on disk
+    pub fn received_blocks(&self, info: &TorrentInfo, piece_index: u32) -> Bitfield {
+        match self.unfinished.get(&piece_index) {
+            Some(blocks) => blocks.clone(),
+            None => Bitfield::new(block_num(info, piece_index)),
+        }
+    }
+
+    pub fn block_received(&mut self, info: &TorrentInfo, piece_index: u32, block_index: usize) {
+        self.unfinished
+            .entry(piece_index)
+            .or_insert_with(|| Bitfield::new(block_num(info, piece_index)))
+            .set(block_index, true);
+    }
+
+    pub fn piece_verified(&mut self, piece_index: u32) {
+        self.unfinished.remove(&piece_index);
+        self.have.set(piece_index as usize, true);
+    }
+
+    // the written blocks failed the hash check, fetch the whole piece again
+    pub fn piece_failed(&mut self, piece_index: u32) {
+        self.unfinished.remove(&piece_index);
+    }
+}
+
+fn block_num(info: &TorrentInfo, piece_index: u32) -> usize {
+    info.get_piece_length_real(piece_index).div_ceil(BLOCK_SIZE) as usize
+}
+
+// Bencoded fast-resume file. It is trusted on restart only when the info hash
+// and every file's size and mtime still match, otherwise the data is rechecked.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FastResume {
+    #[serde(rename = "info-hash", with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    pub unfinished: Vec<UnfinishedPiece>,
+    pub files: Vec<FileState>,
+    #[serde(with = "serde_bytes")]
+    pub peers: Vec<u8>,
+    #[serde(rename = "tracker ids")]
+    pub tracker_ids: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnfinishedPiece {
+    pub piece: u32,
+    #[serde(with = "serde_bytes")]
+    pub blocks: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime: u64, // nanoseconds, writes within the same second still change it
+}
+
+impl FastResume {
+    // Snapshot the progress, files are stat'ed after the blocks they hold were recorded
+    pub fn capture(info_hash: &[u8], progress: &Progress, slots: &[FileSlot]) -> Result<Self> {
+        let mut unfinished: Vec<UnfinishedPiece> = progress.unfinished
+            .iter()
+            .map(|(&piece, blocks)| UnfinishedPiece{ piece, blocks: blocks.as_bytes().to_vec() })
+            .collect();
+        unfinished.sort_by_key(|unfinished_piece| unfinished_piece.piece);
+
+        Ok(Self{
+            info_hash: info_hash.to_vec(),
+            pieces: progress.have.as_bytes().to_vec(),
+            unfinished,
+            files: file_states(slots)?,
+            peers: tracker::encode_compact_peers(&progress.peers),
+            tracker_ids: progress.tracker_ids.clone(),
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read(path)?;
+        Ok(Some(decoder::from_bencode(&content)?))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        utils::write_atomically(path, &serde_bencode::to_bytes(self)?)
+    }
+
+    pub fn matches(&self, info_hash: &[u8], slots: &[FileSlot]) -> bool {
+        self.info_hash == info_hash && file_states(slots).is_ok_and(|files| files == self.files)
+    }
+
+    pub fn into_progress(self, info: &TorrentInfo) -> Progress {
+        let mut progress = Progress::new(info.get_piece_num());
+        progress.have = Bitfield::from_bytes(&self.pieces, info.get_piece_num());
+        for unfinished_piece in self.unfinished {
+            if unfinished_piece.piece as usize >= info.get_piece_num() {
+                continue;
+            }
+            let blocks = Bitfield::from_bytes(&unfinished_piece.blocks, block_num(info, unfinished_piece.piece));
+            progress.unfinished.insert(unfinished_piece.piece, blocks);
+        }
+        progress.peers = tracker::decode_compact_peers(&self.peers).into_iter().collect();
+        progress.tracker_ids = self.tracker_ids;
+        progress
+    }
+}
+
+// Fast-resume file kept next to a download on disk
+pub struct ResumeFile {
+    pub path: PathBuf,
+    pub info_hash: Vec<u8>,
+    pub slots: Vec<FileSlot>,
+}
+
+impl ResumeFile {
+    pub fn new(info: &TorrentInfo, file_path: &str) -> Result<Self> {
+        Ok(Self{
+            path: PathBuf::from(format!("{file_path}.fastresume")),
+            info_hash: info.get_hash()?,
+            slots: info.get_file_slots(Path::new(file_path)),
+        })
+    }
+
+    pub fn save(&self, progress: &Progress) -> Result<()> {
+        FastResume::capture(&self.info_hash, progress, &self.slots)?.save(&self.path)
+    }
+
+    pub fn load(&self) -> Result<Option<FastResume>> {
+        FastResume::load(&self.path)
+    }
+}
+
+// files not created yet are recorded with zero size and mtime
+fn file_states(slots: &[FileSlot]) -> Result<Vec<FileState>> {
+    let mut states = Vec::new();
+    for slot in slots {
+        let state = match fs::metadata(&slot.path) {
+            Ok(metadata) => {
+                let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+                FileState{ size: metadata.len(), mtime }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => FileState{ size: 0, mtime: 0 },
+            Err(e) => return Err(e.into()),
+        };
+        states.push(state);
+    }
+    Ok(states)
+}
@@ -0,0 +1,367 @@
+// storage.rs
+
+use anyhow::{bail, Result};
+use memmap2::MmapMut;
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::torrent::{FileSlot, TorrentInfo};
+
+// Where downloaded content lives. Blocks are addressed by piece and offset in the
+// piece, backends map them onto the torrent's files themselves.
+pub trait Storage: Send + Sync {
+    fn read_block(&self, piece_index: u32, offset: u32, length: u32) -> Result<Vec<u8>>;
+    fn write_block(&self, piece_index: u32, offset: u32, data: &[u8]) -> Result<()>;
+    fn flush(&self) -> Result<()>;
+    // whether the file already existed in the backend
+    fn has_file(&self, file_index: usize) -> bool;
+}
+
+// Part of a block that falls into one file
+struct Span {
+    file_index: usize,
+    file_offset: u64,
+    buffer_range: Range<usize>,
+}
+
+// Split the byte range [offset, offset + length) of the payload along file boundaries
+fn file_spans(slots: &[FileSlot], offset: u64, length: usize) -> Vec<Span> {
+    let end = offset + length as u64;
+    let mut spans = Vec::new();
+    for (file_index, slot) in slots.iter().enumerate() {
+        let (start, stop) = (offset.max(slot.offset), end.min(slot.offset + slot.length));
+        if start >= stop {
+            continue;
+        }
+        spans.push(Span{
+            file_index,
+            file_offset: start - slot.offset,
+            buffer_range: (start - offset) as usize..(stop - offset) as usize,
+        });
+    }
+    spans
+}
+
+fn check_range(slots: &[FileSlot], offset: u64, length: usize) -> Result<()> {
+    let total_length = slots.iter().map(|slot| slot.offset + slot.length).max().unwrap_or(0);
+    let first_offset = slots.iter().map(|slot| slot.offset).min().unwrap_or(0);
+    if offset < first_offset || offset + length as u64 > total_length {
+        bail!("block at {} with length {} is outside the storage", offset, length);
+    }
+    Ok(())
+}
+
+// Files on disk, opened lazily and created at their full size on first write
+pub struct FileStorage {
+    piece_length: u32,
+    slots: Vec<FileSlot>,
+    files: Vec<Mutex<Option<File>>>,
+    skipped: Vec<bool>,
+    partfile: Option<PartFile>,
+}
+
+// Side file holding the parts of skipped files that share a piece with a wanted
+// file. Every such piece gets a piece-sized slot, in piece order.
+struct PartFile {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+    piece_slots: HashMap<u32, u64>,
+}
+
+impl FileStorage {
+    pub fn new(info: &TorrentInfo, root: &Path) -> Self {
+        Self::with_slots(info.piece_length, info.get_file_slots(root))
+    }
+
+    // A single piece saved on its own at `path`
+    pub fn for_piece(info: &TorrentInfo, piece_index: u32, path: &Path) -> Self {
+        Self::for_pieces(info, piece_index..=piece_index, path)
+    }
+
+    // A run of consecutive pieces saved back to back at `path`
+    pub fn for_pieces(info: &TorrentInfo, pieces: RangeInclusive<u32>, path: &Path) -> Self {
+        let offset = *pieces.start() as u64 * info.piece_length as u64;
+        let end = *pieces.end() as u64 * info.piece_length as u64 + info.get_piece_length_real(*pieces.end()) as u64;
+        let slot = FileSlot{ path: path.to_path_buf(), offset, length: end - offset };
+        Self::with_slots(info.piece_length, vec![slot])
+    }
+
+    // Storage over any placement of files on the payload
+    pub fn with_slots(piece_length: u32, slots: Vec<FileSlot>) -> Self {
+        let files = slots.iter().map(|_| Mutex::new(None)).collect();
+        let skipped = vec![false; slots.len()];
+        Self{ piece_length, slots, files, skipped, partfile: None }
+    }
+
+    // Never create or touch the skipped files, data of theirs that shares a piece
+    // with a wanted file goes to the partfile at `partfile_path`
+    pub fn with_skipped_files(mut self, skipped: Vec<bool>, partfile_path: PathBuf) -> Self {
+        let piece_length = self.piece_length as u64;
+        let mut boundary_pieces = BTreeSet::new();
+        for (file_index, slot) in self.slots.iter().enumerate() {
+            if !skipped[file_index] || slot.length == 0 {
+                continue;
+            }
+            let first_piece = slot.offset / piece_length;
+            let last_piece = (slot.offset + slot.length - 1) / piece_length;
+            for piece_index in first_piece..=last_piece {
+                let (piece_start, piece_end) = (piece_index * piece_length, (piece_index + 1) * piece_length);
+                let shared = self.slots.iter().enumerate().any(|(other_index, other)| {
+                    !skipped[other_index] && other.offset < piece_end && other.offset + other.length > piece_start
+                });
+                if shared {
+                    boundary_pieces.insert(piece_index as u32);
+                }
+            }
+        }
+
+        let piece_slots = boundary_pieces.into_iter().zip(0u64..).collect();
+        self.partfile = Some(PartFile{ path: partfile_path, file: Mutex::new(None), piece_slots });
+        self.skipped = skipped;
+        self
+    }
+
+    // offset in the partfile of a byte of a skipped file, None if its piece has no slot
+    fn partfile_offset(&self, piece_index: u32, offset_in_piece: u64) -> Option<u64> {
+        let partfile = self.partfile.as_ref()?;
+        let slot = partfile.piece_slots.get(&piece_index)?;
+        Some(slot * self.piece_length as u64 + offset_in_piece)
+    }
+
+    fn with_partfile<T>(&self, action: impl FnOnce(&mut File) -> Result<T>) -> Result<T> {
+        let partfile = self.partfile.as_ref().unwrap();
+        let mut file = partfile.file.lock().unwrap();
+        if file.is_none() {
+            *file = Some(File::options().read(true).write(true).create(true).truncate(false).open(&partfile.path)?);
+        }
+        action(file.as_mut().unwrap())
+    }
+
+    fn with_file<T>(
+        &self,
+        file_index: usize,
+        create: bool,
+        action: impl FnOnce(&mut File) -> Result<T>
+    ) -> Result<T> {
+        let mut file = self.files[file_index].lock().unwrap();
+        if file.is_none() {
+            let slot = &self.slots[file_index];
+            let opened = match create {
+                true => {
+                    if let Some(parent) = slot.path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    let opened = File::options().read(true).write(true).create(true).truncate(false).open(&slot.path)?;
+                    if opened.metadata()?.len() != slot.length {
+                        opened.set_len(slot.length)?;
+                    }
+                    opened
+                },
+                false => File::options().read(true).write(true).open(&slot.path)?,
+            };
+            *file = Some(opened);
+        }
+        action(file.as_mut().unwrap())
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_block(&self, piece_index: u32, offset: u32, length: u32) -> Result<Vec<u8>> {
+        let block_offset = piece_index as u64 * self.piece_length as u64 + offset as u64;
+        check_range(&self.slots, block_offset, length as usize)?;
+
+        let mut buffer = vec![0u8; length as usize];
+        for span in file_spans(&self.slots, block_offset, length as usize) {
+            let mut read = |file: &mut File, file_offset: u64| -> Result<()> {
+                file.seek(SeekFrom::Start(file_offset))?;
+                file.read_exact(&mut buffer[span.buffer_range.clone()])?;
+                Ok(())
+            };
+            if !self.skipped[span.file_index] {
+                self.with_file(span.file_index, false, |file| read(file, span.file_offset))?;
+                continue;
+            }
+            match self.partfile_offset(piece_index, offset as u64 + span.buffer_range.start as u64) {
+                Some(partfile_offset) => self.with_partfile(|file| read(file, partfile_offset))?,
+                None => bail!("piece {} only holds data of skipped files", piece_index),
+            }
+        }
+        Ok(buffer)
+    }
+
+    fn write_block(&self, piece_index: u32, offset: u32, data: &[u8]) -> Result<()> {
+        let block_offset = piece_index as u64 * self.piece_length as u64 + offset as u64;
+        check_range(&self.slots, block_offset, data.len())?;
+
+        for span in file_spans(&self.slots, block_offset, data.len()) {
+            let write = |file: &mut File, file_offset: u64| -> Result<()> {
+                file.seek(SeekFrom::Start(file_offset))?;
+                file.write_all(&data[span.buffer_range.clone()])?;
+                Ok(())
+            };
+            if !self.skipped[span.file_index] {
+                self.with_file(span.file_index, true, |file| write(file, span.file_offset))?;
+                continue;
+            }
+            match self.partfile_offset(piece_index, offset as u64 + span.buffer_range.start as u64) {
+                Some(partfile_offset) => self.with_partfile(|file| write(file, partfile_offset))?,
+                None => bail!("piece {} only holds data of skipped files", piece_index),
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        for (file_index, slot) in self.slots.iter().enumerate() {
+            // empty files never receive a block, create them here instead
+            if slot.length == 0 && !self.skipped[file_index] {
+                self.with_file(file_index, true, |_| Ok(()))?;
+            }
+            // written blocks reach the disk before the resume file counts them
+            if let Some(file) = self.files[file_index].lock().unwrap().as_mut() {
+                file.sync_data()?;
+            }
+        }
+        if let Some(partfile) = self.partfile.as_ref() {
+            if let Some(file) = partfile.file.lock().unwrap().as_mut() {
+                file.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn has_file(&self, file_index: usize) -> bool {
+        let skipped = self.skipped.get(file_index).copied().unwrap_or(false);
+        !skipped && self.slots.get(file_index).is_some_and(|slot| slot.path.exists())
+    }
+}
+
+// Whole payload kept in memory, for tests and short-lived consumers
+pub struct MemoryStorage {
+    piece_length: u32,
+    slots: Vec<FileSlot>,
+    data: Mutex<Vec<u8>>,
+    written: Mutex<Vec<bool>>,
+}
+
+impl MemoryStorage {
+    pub fn new(info: &TorrentInfo) -> Self {
+        let slots = info.get_file_slots(Path::new(""));
+        let written = Mutex::new(vec![false; slots.len()]);
+        let data = Mutex::new(vec![0u8; info.total_length() as usize]);
+        Self{ piece_length: info.piece_length, slots, data, written }
+    }
+
+    // copy of the whole payload
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(&self, piece_index: u32, offset: u32, length: u32) -> Result<Vec<u8>> {
+        let start = piece_index as u64 * self.piece_length as u64 + offset as u64;
+        check_range(&self.slots, start, length as usize)?;
+        let data = self.data.lock().unwrap();
+        Ok(data[start as usize..start as usize + length as usize].to_vec())
+    }
+
+    fn write_block(&self, piece_index: u32, offset: u32, data: &[u8]) -> Result<()> {
+        let start = piece_index as u64 * self.piece_length as u64 + offset as u64;
+        check_range(&self.slots, start, data.len())?;
+        self.data.lock().unwrap()[start as usize..start as usize + data.len()].copy_from_slice(data);
+
+        let mut written = self.written.lock().unwrap();
+        for span in file_spans(&self.slots, start, data.len()) {
+            written[span.file_index] = true;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn has_file(&self, file_index: usize) -> bool {
+        self.written.lock().unwrap().get(file_index).copied().unwrap_or(false)
+    }
+}
+
+// Files mapped into memory, created at their full size when the storage is opened
+pub struct MmapStorage {
+    piece_length: u32,
+    slots: Vec<FileSlot>,
+    maps: Vec<Mutex<Option<MmapMut>>>, // None for empty files, which cannot be mapped
+    existed: Vec<bool>,
+}
+
+impl MmapStorage {
+    pub fn open(info: &TorrentInfo, root: &Path) -> Result<Self> {
+        let slots = info.get_file_slots(root);
+        let mut maps = Vec::new();
+        let mut existed = Vec::new();
+        for slot in slots.iter() {
+            existed.push(slot.path.exists());
+            if let Some(parent) = slot.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = File::options().read(true).write(true).create(true).truncate(false).open(&slot.path)?;
+            file.set_len(slot.length)?;
+            let map = match slot.length {
+                0 => None,
+                // safety: the file stays open for the map's lifetime, and concurrent
+                // modification by other processes is outside what we support
+                _ => Some(unsafe { MmapMut::map_mut(&file)? }),
+            };
+            maps.push(Mutex::new(map));
+        }
+        Ok(Self{ piece_length: info.piece_length, slots, maps, existed })
+    }
+}
+
+impl Storage for MmapStorage {
+    fn read_block(&self, piece_index: u32, offset: u32, length: u32) -> Result<Vec<u8>> {
+        let block_offset = piece_index as u64 * self.piece_length as u64 + offset as u64;
+        check_range(&self.slots, block_offset, length as usize)?;
+
+        let mut buffer = vec![0u8; length as usize];
+        for span in file_spans(&self.slots, block_offset, length as usize) {
+            let map = self.maps[span.file_index].lock().unwrap();
+            let map = map.as_ref().unwrap();
+            let start = span.file_offset as usize;
+            buffer[span.buffer_range.clone()].copy_from_slice(&map[start..start + span.buffer_range.len()]);
+        }
+        Ok(buffer)
+    }
+
+    fn write_block(&self, piece_index: u32, offset: u32, data: &[u8]) -> Result<()> {
+        let block_offset = piece_index as u64 * self.piece_length as u64 + offset as u64;
+        check_range(&self.slots, block_offset, data.len())?;
+
+        for span in file_spans(&self.slots, block_offset, data.len()) {
+            let mut map = self.maps[span.file_index].lock().unwrap();
+            let map = map.as_mut().unwrap();
+            let start = span.file_offset as usize;
+            map[start..start + span.buffer_range.len()].copy_from_slice(&data[span.buffer_range.clone()]);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        for map in self.maps.iter() {
+            if let Some(map) = map.lock().unwrap().as_ref() {
+                map.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn has_file(&self, file_index: usize) -> bool {
+        self.existed.get(file_index).copied().unwrap_or(false)
+    }
+}
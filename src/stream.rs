@@ -0,0 +1,293 @@
+// stream.rs
+
+use anyhow::{bail, Result};
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::download::DownloadHandle;
+use crate::reader::TorrentReader;
+use crate::torrent::FileSlot;
+
+// request line plus headers, larger requests are refused
+const MAX_REQUEST_HEAD: u64 = 16 * 1024;
+
+// Serve the torrent's files over HTTP while it downloads. A single-file torrent
+// is served at "/", a multi-file one lists its files there. Readers block until
+// the pieces they asked for arrive, and those pieces get deadlines so they jump
+// the download queue until the response is done.
+pub async fn serve(handle: Arc<DownloadHandle>, listener: TcpListener) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &handle).await {
+                eprintln!("Stream client error: {}", e);
+            }
+        });
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+}
+
+enum RangeRequest {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+// one request per connection, the response always closes it
+async fn handle_connection(mut stream: TcpStream, handle: &Arc<DownloadHandle>) -> Result<()> {
+    let request = match read_request(&mut stream).await {
+        Ok(request) => request,
+        Err(e) => {
+            write_head(&mut stream, "400 Bad Request", &[("Content-Length", "0".to_string())]).await?;
+            return Err(e);
+        },
+    };
+    if request.method != "GET" && request.method != "HEAD" {
+        let headers = [("Allow", "GET, HEAD".to_string()), ("Content-Length", "0".to_string())];
+        return write_head(&mut stream, "405 Method Not Allowed", &headers).await;
+    }
+    let head_only = request.method == "HEAD";
+
+    let slots = handle.info().get_file_slots(Path::new(""));
+    let single_file = handle.info().files.is_none();
+    if request.path == "/" && !single_file {
+        let body = index_page(&handle.info().name, &slots);
+        let headers = [
+            ("Content-Type", "text/html; charset=utf-8".to_string()),
+            ("Content-Length", body.len().to_string()),
+        ];
+        write_head(&mut stream, "200 OK", &headers).await?;
+        if !head_only {
+            stream.write_all(body.as_bytes()).await?;
+        }
+        return Ok(());
+    }
+
+    let file_index = slots.iter().position(|slot| match single_file {
+        true => request.path == "/" || request.path == format!("/{}", handle.info().name),
+        false => request.path == format!("/{}", slot_path(slot)),
+    });
+    let Some(file_index) = file_index else {
+        return write_head(&mut stream, "404 Not Found", &[("Content-Length", "0".to_string())]).await;
+    };
+    let slot = &slots[file_index];
+    let file_name = match single_file {
+        true => handle.info().name.clone(),
+        false => slot_path(slot),
+    };
+
+    let range = match &request.range {
+        Some(value) => parse_range(value, slot.length),
+        None => RangeRequest::Full,
+    };
+    let mut headers = vec![
+        ("Accept-Ranges", "bytes".to_string()),
+        ("Content-Type", content_type(&file_name).to_string()),
+    ];
+    let (status, range) = match range {
+        RangeRequest::Full => ("200 OK", 0..slot.length),
+        RangeRequest::Partial(range) => {
+            headers.push(("Content-Range", format!("bytes {}-{}/{}", range.start, range.end - 1, slot.length)));
+            ("206 Partial Content", range)
+        },
+        RangeRequest::Unsatisfiable => {
+            headers.push(("Content-Range", format!("bytes */{}", slot.length)));
+            headers.push(("Content-Length", "0".to_string()));
+            return write_head(&mut stream, "416 Range Not Satisfiable", &headers).await;
+        },
+    };
+    headers.push(("Content-Length", (range.end - range.start).to_string()));
+    write_head(&mut stream, status, &headers).await?;
+    if !head_only {
+        send_range(&mut stream, handle, file_index, range).await?;
+    }
+    Ok(())
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST_HEAD);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next()) else {
+        bail!("malformed request line: {:?}", line.trim_end());
+    };
+    let target = target.split('?').next().unwrap_or_default();
+    let mut request = Request{ method: method.to_string(), path: percent_decode(target)?, range: None };
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            bail!("request head truncated");
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                request.range = Some(value.trim().to_string());
+            }
+        }
+    }
+    Ok(request)
+}
+
+async fn write_head(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: &str,
+    headers: &[(&str, String)]
+) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+// Send a range of a file through a reader, which waits for each piece and
+// gives it and the next few deadlines. They are dropped with the reader once the
+// range is sent or the client goes away, so nothing stays boosted.
+async fn send_range(
+    stream: &mut (impl AsyncWrite + Unpin),
+    handle: &Arc<DownloadHandle>,
+    file_index: usize,
+    range: Range<u64>
+) -> Result<()> {
+    let mut reader = TorrentReader::for_file(handle.clone(), file_index)?;
+    reader.seek(SeekFrom::Start(range.start)).await?;
+    io::copy(&mut reader.take(range.end - range.start), stream).await?;
+    Ok(())
+}
+
+// Single `bytes=` ranges only: `a-b`, `a-` and the suffix form `-n`. Anything
+// else is ignored and the whole file is sent, as HTTP allows.
+fn parse_range(value: &str, length: u64) -> RangeRequest {
+    let Some(spec) = value.strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return RangeRequest::Unsatisfiable,
+            Ok(suffix) => length.saturating_sub(suffix)..length,
+            Err(_) => return RangeRequest::Full,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => start..length,
+            Err(_) => return RangeRequest::Full,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(length),
+            _ => return RangeRequest::Full,
+        },
+    };
+    match range.start < length {
+        true => RangeRequest::Partial(range),
+        false => RangeRequest::Unsatisfiable,
+    }
+}
+
+// path of a file inside a multi-file torrent, as used in URLs
+fn slot_path(slot: &FileSlot) -> String {
+    slot.path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn index_page(name: &str, slots: &[FileSlot]) -> String {
+    let mut page = format!("<!DOCTYPE html>\n<html><head><title>{0}</title></head><body>\n<h1>{0}</h1>\n<ul>\n", escape_html(name));
+    for slot in slots {
+        let path = slot_path(slot);
+        page.push_str(&format!(
+            "<li><a href=\"/{}\">{}</a> ({} bytes)</li>\n",
+            percent_encode(&path),
+            escape_html(&path),
+            slot.length
+        ));
+    }
+    page.push_str("</ul>\n</body></html>\n");
+    page
+}
+
+fn content_type(file_name: &str) -> &'static str {
+    let extension = file_name.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("avi") => "video/x-msvideo",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("txt" | "nfo") => "text/plain; charset=utf-8",
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz" | "tgz") => "application/gzip",
+        Some("tar") => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+fn percent_decode(value: &str) -> Result<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let Some(hex) = bytes.get(index + 1..index + 3) else {
+                bail!("truncated escape in {:?}", value);
+            };
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex)?, 16)?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+// escape everything but unreserved characters and the path separator
+fn percent_encode(value: &str) -> String {
+    value.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
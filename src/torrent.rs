@@ -1,11 +1,13 @@
 // torrent.rs
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use reqwest;
 use serde::{Serialize, Deserialize};
 
+use std::path::{Component, Path, PathBuf};
+
 use crate::encoder;
-use crate::peer;
+use crate::tracker;
 
 // Decode torrent file
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -27,38 +29,20 @@ impl TorrentFile {
         self.info.get_piece_num()
     }
 
-    pub fn track_request(&self) -> Result<reqwest::blocking::Response> {
-        let url = {
-            let announce = &self.announce;
-            let length = self.info.length;
-            let info_hash = {
-                let info_hash_bytes = self.get_hash()?;
-                encoder::encode_percent(&info_hash_bytes)
-            };
-            let peer_id = {
-                let peer_id_bytes = peer::Peer::gen_peer_id().to_vec();
-                encoder::encode_percent(&peer_id_bytes)
-            };
-            
-            format!("{announce}?\
-                    info_hash={info_hash}&\
-                    peer_id={peer_id}&\
-                    port=6881&\
-                    uploaded=0&\
-                    downloaded=0&\
-                    left={length}&\
-                    compact=1"
-            )
-        };
-
-        let raw_response = reqwest::blocking::get(url)?;
+    pub async fn track_request(&self) -> Result<reqwest::Response> {
+        let url = tracker::announce_url(&self.announce, &self.get_hash()?, tracker::DEFAULT_PORT, self.info.total_length(), None);
+        let raw_response = reqwest::get(url).await?;
         Ok(raw_response)
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TorrentInfo {
+    // single-file mode only, multi-file torrents list their sizes in `files`
+    #[serde(default, skip_serializing_if = "is_zero")]
     pub length: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileEntry>>,
     pub name: String,
     #[serde(rename="piece length")]
     pub piece_length: u32,
@@ -73,12 +57,101 @@ impl TorrentInfo {
         Ok(hash)
     }
 
+    // 0 for pieces past the end, which `validate` rules out
     pub fn get_piece_length_real(&self, piece_index: u32) -> u32 {
         let piece_length = self.piece_length as u64;
-        piece_length.min(self.length - piece_index as u64 * piece_length as u64) as u32
+        piece_length.min(self.total_length().saturating_sub(piece_index as u64 * piece_length)) as u32
+    }
+
+    // Check the pieces fit the payload: whole hashes, exactly one per piece, and
+    // file lengths that add up. Everything after decoding relies on it.
+    pub fn validate(&self) -> Result<()> {
+        if self.piece_length == 0 {
+            bail!("piece length of 0");
+        }
+        if !self.pieces.len().is_multiple_of(20) {
+            bail!("piece hashes of {} bytes, not a multiple of 20", self.pieces.len());
+        }
+        let total_length = match &self.files {
+            Some(files) => files.iter().try_fold(0u64, |total, file| total.checked_add(file.length)),
+            None => Some(self.length),
+        };
+        let Some(total_length) = total_length else {
+            bail!("file lengths add up past {} bytes", u64::MAX);
+        };
+        let piece_num = total_length.div_ceil(self.piece_length as u64);
+        if piece_num != self.get_piece_num() as u64 {
+            bail!("{} piece hashes for {} bytes in pieces of {}", self.get_piece_num(), total_length, self.piece_length);
+        }
+        Ok(())
     }
 
     pub fn get_piece_num(&self) -> usize {
         self.pieces.len() / 20
     }
+
+    pub fn get_piece_hash(&self, piece_index: u32) -> &[u8] {
+        let start = piece_index as usize * 20;
+        &self.pieces[start..start + 20]
+    }
+
+    // payload size, summed over all files in multi-file mode
+    pub fn total_length(&self) -> u64 {
+        match &self.files {
+            Some(files) => files.iter().map(|file| file.length).sum(),
+            None => self.length,
+        }
+    }
+
+    // Place every file on the logical byte stream. A single-file torrent is stored
+    // at `root` itself, a multi-file torrent below the `root` directory.
+    pub fn get_file_slots(&self, root: &Path) -> Vec<FileSlot> {
+        let files = match &self.files {
+            Some(files) => files,
+            None => {
+                return vec![FileSlot{ path: root.to_path_buf(), offset: 0, length: self.length }];
+            }
+        };
+
+        let mut slots = Vec::new();
+        let mut offset = 0u64;
+        for file in files {
+            slots.push(FileSlot{ path: root.join(file.relative_path()), offset, length: file.length });
+            offset += file.length;
+        }
+        slots
+    }
+}
+
+// File entry of a multi-file torrent
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileEntry {
+    pub length: u64,
+    pub path: Vec<String>,
+}
+
+impl FileEntry {
+    // path below the torrent root, dropping components that could escape it
+    pub fn relative_path(&self) -> PathBuf {
+        let mut path = PathBuf::new();
+        for part in self.path.iter() {
+            let mut components = Path::new(part).components();
+            if let (Some(Component::Normal(_)), None) = (components.next(), components.next()) {
+                path.push(part);
+            }
+        }
+        path
+    }
+}
+
+// A file together with its position in the torrent's byte stream
+#[derive(Clone, Debug)]
+pub struct FileSlot {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
 }
\ No newline at end of file
@@ -1,17 +1,63 @@
 // tracker.rs
 
 use anyhow::Result;
+use reqwest;
 use serde::{Serialize, Deserialize, Deserializer};
 use serde_bytes;
 
 use std::net::{Ipv4Addr, SocketAddrV4};
 
+use crate::decoder;
+use crate::encoder;
+use crate::peer;
+
+// port announced when we do not listen for peers
+pub const DEFAULT_PORT: u16 = 6881;
+
 // Tracker response struct
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TrackerResponse {
     pub interval: i32,
     #[serde(deserialize_with = "deserialize_peers")]
     pub peers: Vec<SocketAddrV4>,
+    #[serde(rename = "tracker id", default, skip_serializing_if = "Option::is_none")]
+    pub tracker_id: Option<String>,
+}
+
+// Build the announce url, echoing the tracker id from an earlier response if any
+pub fn announce_url(announce: &str, info_hash: &[u8], port: u16, left: u64, tracker_id: Option<&str>) -> String {
+    let info_hash = encoder::encode_percent(info_hash);
+    let peer_id = {
+        let peer_id_bytes = peer::Peer::gen_peer_id().to_vec();
+        encoder::encode_percent(&peer_id_bytes)
+    };
+
+    let mut url = format!("{announce}?\
+            info_hash={info_hash}&\
+            peer_id={peer_id}&\
+            port={port}&\
+            uploaded=0&\
+            downloaded=0&\
+            left={left}&\
+            compact=1"
+    );
+    if let Some(tracker_id) = tracker_id {
+        url.push_str(&format!("&trackerid={}", encoder::encode_percent(tracker_id.as_bytes())));
+    }
+    url
+}
+
+// Announce to the tracker and decode its response
+pub async fn announce(
+    announce: &str,
+    info_hash: &[u8],
+    port: u16,
+    left: u64,
+    tracker_id: Option<&str>
+) -> Result<TrackerResponse> {
+    let url = announce_url(announce, info_hash, port, left, tracker_id);
+    let raw_response = reqwest::get(url).await?.bytes().await?;
+    decoder::decode_tracker_response(&raw_response)
 }
 
 fn deserialize_peers<'de, D>(deserializer: D) -> Result<Vec<SocketAddrV4>, D::Error>
@@ -19,14 +65,26 @@ where
     D: Deserializer<'de>,
 {
     let bytes: Vec<u8> = serde_bytes::deserialize(deserializer)?;
+    Ok(decode_compact_peers(&bytes))
+}
+
+// Compact peer list, 4 bytes ip and 2 bytes port per peer
+pub fn decode_compact_peers(bytes: &[u8]) -> Vec<SocketAddrV4> {
     let mut peers: Vec<SocketAddrV4> = Vec::new();
-    
-    for chunk in bytes.chunks(6) {
+    for chunk in bytes.chunks_exact(6) {
         let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
         let port = u16::from_be_bytes([chunk[4], chunk[5]]);
         peers.push(SocketAddrV4::new(ip, port));
     }
-    
-    Ok(peers)
+    peers
+}
+
+pub fn encode_compact_peers<'a>(peers: impl IntoIterator<Item = &'a SocketAddrV4>) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for peer_addr in peers {
+        bytes.extend_from_slice(&peer_addr.ip().octets());
+        bytes.extend_from_slice(&peer_addr.port().to_be_bytes());
+    }
+    bytes
 }
 
@@ -0,0 +1,336 @@
+// transport.rs
+
+use anyhow::{bail, Result};
+use tokio::io::{self, AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+
+use crate::utp::{UtpSocket, UtpStream};
+
+// bytes an in-memory connection buffers each way, like a socket's buffers
+const MEMORY_BUFFER: usize = 256 * 1024;
+
+// Which transports connections to peers go over, and which is tried first
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransportPreference {
+    // TCP only, no uTP socket is opened
+    #[default]
+    Tcp,
+    // uTP only, incoming TCP connections are refused too
+    Utp,
+    // TCP first, uTP if the peer cannot be reached over TCP
+    PreferTcp,
+    // uTP first, TCP if the peer does not answer over uTP
+    PreferUtp,
+}
+
+impl FromStr for TransportPreference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "utp" => Ok(Self::Utp),
+            "prefer-tcp" => Ok(Self::PreferTcp),
+            "prefer-utp" => Ok(Self::PreferUtp),
+            _ => bail!("unknown transport {:?}, expected tcp, utp, prefer-tcp or prefer-utp", s),
+        }
+    }
+}
+
+pub type TransportFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+// How connections to peers are opened and taken: over sockets, or within the
+// process so whole swarms can run in a test
+pub trait Transport: fmt::Debug + Send + Sync {
+    // Open a connection to the peer at `peer_addr`
+    fn connect(&self, peer_addr: SocketAddrV4) -> TransportFuture<'_, PeerStream>;
+
+    // Open a connection to a peer said to speak uTP, over uTP first where the
+    // transport has a choice
+    fn connect_utp_first(&self, peer_addr: SocketAddrV4) -> TransportFuture<'_, PeerStream> {
+        self.connect(peer_addr)
+    }
+
+    // Take connections on `addr` from now on
+    fn listen(&self, addr: SocketAddrV4) -> TransportFuture<'_, ()>;
+
+    // The next peer connecting, never comes while not listening
+    fn accept(&self) -> TransportFuture<'_, PeerStream>;
+}
+
+// TCP always, uTP over `utp` unless the preference is TCP only
+#[derive(Debug, Default)]
+pub struct SocketTransport {
+    preference: TransportPreference,
+    utp: Option<Arc<UtpSocket>>,
+    listener: OnceLock<TcpListener>,
+}
+
+impl SocketTransport {
+    pub fn new(preference: TransportPreference, utp: Option<Arc<UtpSocket>>) -> Self {
+        Self{ preference, utp, listener: OnceLock::new() }
+    }
+
+    // Connect over the preferred transport, falling back to the other one. Peers
+    // known to speak uTP are tried over it first unless TCP is all we use.
+    async fn connect_preferred(&self, peer_addr: SocketAddrV4, utp_first: bool) -> Result<PeerStream> {
+        let preference = match (self.preference, utp_first) {
+            (TransportPreference::PreferTcp, true) => TransportPreference::PreferUtp,
+            (preference, _) => preference,
+        };
+        let utp = match (preference, &self.utp) {
+            (TransportPreference::Tcp, _) => return Ok(PeerStream::Tcp(TcpStream::connect(peer_addr).await?)),
+            (_, None) => bail!("uTP is not enabled"),
+            (_, Some(utp)) => utp,
+        };
+        match preference {
+            TransportPreference::PreferTcp => match TcpStream::connect(peer_addr).await {
+                Ok(stream) => Ok(PeerStream::Tcp(stream)),
+                Err(_) => Ok(PeerStream::Utp(utp.connect(peer_addr).await?)),
+            },
+            TransportPreference::PreferUtp => match utp.connect(peer_addr).await {
+                Ok(stream) => Ok(PeerStream::Utp(stream)),
+                Err(_) => Ok(PeerStream::Tcp(TcpStream::connect(peer_addr).await?)),
+            },
+            _ => Ok(PeerStream::Utp(utp.connect(peer_addr).await?)),
+        }
+    }
+
+    // No TCP listener if only uTP is wanted, so TCP connections are refused
+    // right away. The uTP socket is bound already.
+    async fn bind(&self, addr: SocketAddrV4) -> Result<()> {
+        if self.preference == TransportPreference::Utp {
+            return Ok(());
+        }
+        if self.listener.set(TcpListener::bind(addr).await?).is_err() {
+            bail!("already listening");
+        }
+        Ok(())
+    }
+
+    async fn accept_any(&self) -> Result<PeerStream> {
+        tokio::select! {
+            accepted = accept_tcp(self.listener.get()) => Ok(PeerStream::Tcp(accepted?)),
+            accepted = accept_utp(self.utp.as_deref()) => Ok(PeerStream::Utp(accepted?)),
+        }
+    }
+}
+
+impl Transport for SocketTransport {
+    fn connect(&self, peer_addr: SocketAddrV4) -> TransportFuture<'_, PeerStream> {
+        Box::pin(self.connect_preferred(peer_addr, false))
+    }
+
+    fn connect_utp_first(&self, peer_addr: SocketAddrV4) -> TransportFuture<'_, PeerStream> {
+        Box::pin(self.connect_preferred(peer_addr, true))
+    }
+
+    fn listen(&self, addr: SocketAddrV4) -> TransportFuture<'_, ()> {
+        Box::pin(self.bind(addr))
+    }
+
+    fn accept(&self) -> TransportFuture<'_, PeerStream> {
+        Box::pin(self.accept_any())
+    }
+}
+
+// the next peer connecting over TCP or uTP, never without a listener or socket
+async fn accept_tcp(listener: Option<&TcpListener>) -> Result<TcpStream> {
+    match listener {
+        Some(listener) => Ok(listener.accept().await?.0),
+        None => std::future::pending().await,
+    }
+}
+
+async fn accept_utp(utp: Option<&UtpSocket>) -> Result<UtpStream> {
+    match utp {
+        Some(utp) => utp.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// The peers of a swarm running within one process. They connect to each other
+// by address over in-memory pipes, no socket is opened.
+#[derive(Debug)]
+pub struct MemoryNetwork {
+    listeners: Mutex<HashMap<SocketAddrV4, mpsc::UnboundedSender<PeerStream>>>,
+    next_port: AtomicU16, // local ports of outgoing connections
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self{ listeners: Mutex::new(HashMap::new()), next_port: AtomicU16::new(49152) })
+    }
+
+    // The transport of a peer at `ip`
+    pub fn transport(self: &Arc<Self>, ip: Ipv4Addr) -> MemoryTransport {
+        let (incoming_sender, incoming) = mpsc::unbounded_channel();
+        MemoryTransport{
+            network: self.clone(),
+            ip,
+            listening: Mutex::new(None),
+            incoming_sender,
+            incoming: AsyncMutex::new(incoming),
+        }
+    }
+}
+
+// A peer of a `MemoryNetwork`, connections to the address it listens on are
+// refused again once it is dropped
+#[derive(Debug)]
+pub struct MemoryTransport {
+    network: Arc<MemoryNetwork>,
+    ip: Ipv4Addr,
+    listening: Mutex<Option<SocketAddrV4>>,
+    incoming_sender: mpsc::UnboundedSender<PeerStream>,
+    incoming: AsyncMutex<mpsc::UnboundedReceiver<PeerStream>>,
+}
+
+impl MemoryTransport {
+    fn open(&self, peer_addr: SocketAddrV4) -> Result<PeerStream> {
+        let listeners = self.network.listeners.lock().unwrap();
+        let Some(listener) = listeners.get(&peer_addr) else {
+            bail!("connection to {} refused", peer_addr);
+        };
+        let local_addr = SocketAddrV4::new(self.ip, self.network.next_port.fetch_add(1, Ordering::Relaxed));
+        let (ours, theirs) = io::duplex(MEMORY_BUFFER);
+        let theirs = MemoryStream{ stream: theirs, local_addr: peer_addr, peer_addr: local_addr };
+        if listener.send(PeerStream::Memory(theirs)).is_err() {
+            bail!("connection to {} refused", peer_addr);
+        }
+        Ok(PeerStream::Memory(MemoryStream{ stream: ours, local_addr, peer_addr }))
+    }
+
+    // peers listening on every address are reached on our own
+    fn bind(&self, addr: SocketAddrV4) -> Result<()> {
+        let addr = match addr.ip().is_unspecified() {
+            true => SocketAddrV4::new(self.ip, addr.port()),
+            false => addr,
+        };
+        let mut listening = self.listening.lock().unwrap();
+        if listening.is_some() {
+            bail!("already listening");
+        }
+        let mut listeners = self.network.listeners.lock().unwrap();
+        if listeners.contains_key(&addr) {
+            bail!("{} is in use", addr);
+        }
+        listeners.insert(addr, self.incoming_sender.clone());
+        *listening = Some(addr);
+        Ok(())
+    }
+
+    async fn next_incoming(&self) -> Result<PeerStream> {
+        match self.incoming.lock().await.recv().await {
+            Some(stream) => Ok(stream),
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn connect(&self, peer_addr: SocketAddrV4) -> TransportFuture<'_, PeerStream> {
+        Box::pin(std::future::ready(self.open(peer_addr)))
+    }
+
+    fn listen(&self, addr: SocketAddrV4) -> TransportFuture<'_, ()> {
+        Box::pin(std::future::ready(self.bind(addr)))
+    }
+
+    fn accept(&self) -> TransportFuture<'_, PeerStream> {
+        Box::pin(self.next_incoming())
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        if let Some(addr) = self.listening.lock().unwrap().take() {
+            self.network.listeners.lock().unwrap().remove(&addr);
+        }
+    }
+}
+
+// One end of an in-memory connection
+#[derive(Debug)]
+pub struct MemoryStream {
+    stream: DuplexStream,
+    local_addr: SocketAddrV4,
+    peer_addr: SocketAddrV4,
+}
+
+// A connection to a peer over TCP, uTP or in memory
+#[derive(Debug)]
+pub enum PeerStream {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+    Memory(MemoryStream),
+}
+
+impl PeerStream {
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        match self {
+            PeerStream::Tcp(stream) => Ok(stream.peer_addr()?),
+            PeerStream::Utp(stream) => Ok(stream.peer_addr()),
+            PeerStream::Memory(stream) => Ok(SocketAddr::V4(stream.peer_addr)),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        match self {
+            PeerStream::Tcp(stream) => Ok(stream.local_addr()?),
+            PeerStream::Utp(stream) => Ok(stream.local_addr()),
+            PeerStream::Memory(stream) => Ok(SocketAddr::V4(stream.local_addr)),
+        }
+    }
+
+    pub fn is_utp(&self) -> bool {
+        matches!(self, PeerStream::Utp(_))
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Memory(stream) => Pin::new(&mut stream.stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_write(cx, data),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_write(cx, data),
+            PeerStream::Memory(stream) => Pin::new(&mut stream.stream).poll_write(cx, data),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Memory(stream) => Pin::new(&mut stream.stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Memory(stream) => Pin::new(&mut stream.stream).poll_shutdown(cx),
+        }
+    }
+}
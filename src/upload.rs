@@ -0,0 +1,365 @@
+// upload.rs
+
+use anyhow::{bail, Result};
+use tokio::signal;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time;
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::bitfield::Bitfield;
+use crate::choker::{Choker, PeerStats, DEFAULT_UPLOAD_SLOTS, RECHOKE_INTERVAL};
+use crate::dht;
+use crate::download::DownloadOptions;
+use crate::encoder;
+use crate::extension::ExtensionRegistry;
+use crate::lsd;
+use crate::message::{Message, MessageLimits};
+use crate::mse::EncryptionPolicy;
+use crate::peer::{PeerConnection, PeerTimeouts};
+use crate::pex::{self, PexSwarm};
+use crate::storage::{FileStorage, Storage};
+use crate::torrent::TorrentInfo;
+use crate::tracker;
+use crate::transport::{PeerStream, Transport};
+use crate::verify::{self, PieceStatus};
+
+// pieces a fast extension peer may fetch from us while choked
+pub const ALLOWED_FAST_PIECES: usize = 10;
+
+// Seed the data at `root` until Ctrl-C, after checking which pieces it holds.
+// An empty `announce` skips the tracker, with a DHT node or local service
+// discovery in `options` we announce there too.
+pub async fn seed(
+    info: &TorrentInfo,
+    announce: &str,
+    root: &Path,
+    listen: SocketAddrV4,
+    options: &DownloadOptions
+) -> Result<()> {
+    let storage: Arc<dyn Storage> = Arc::new(FileStorage::new(info, root));
+    let report = verify::verify_storage(info, storage.clone()).await?;
+    let good_num = report.count(PieceStatus::Good);
+    println!("Seeding {} of {} pieces", good_num, info.get_piece_num());
+
+    let transport = options.transport();
+    transport.listen(listen).await?;
+    let left = info.total_length().saturating_sub(good_num as u64 * info.piece_length as u64);
+    let info_hash = info.get_hash()?;
+    if !announce.is_empty() {
+        if let Err(e) = tracker::announce(announce, &info_hash, listen.port(), left, None).await {
+            eprintln!("Tracker announce failed: {}", e);
+        }
+    }
+    // seeds have no use for the peers PEX finds, but pass on the ones they know
+    let (discovered, _) = mpsc::unbounded_channel();
+    let slots = options.upload_slots.unwrap_or(DEFAULT_UPLOAD_SLOTS);
+    let pex = PexSwarm::new(discovered);
+    let uploader = Arc::new(Uploader::new(info, storage, report.bitfield(), slots, pex, options.encryption, options.message_limits, options.peer_timeouts)?);
+    let dht_announcer = async {
+        let Some(dht) = &options.dht else {
+            return std::future::pending().await;
+        };
+        let mut interval = time::interval(dht::ANNOUNCE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = dht.announce(&info_hash, listen.port()).await {
+                eprintln!("DHT announce failed: {}", e);
+            }
+        }
+    };
+    let lsd_announcer = async {
+        let Some(lsd) = &options.lsd else {
+            return std::future::pending().await;
+        };
+        let mut interval = time::interval(lsd::ANNOUNCE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = lsd.announce(&info_hash, listen.port()).await {
+                eprintln!("Local peer announce failed: {}", e);
+            }
+        }
+    };
+    tokio::select! {
+        result = uploader.serve(transport) => result,
+        _ = dht_announcer => Ok(()),
+        _ = lsd_announcer => Ok(()),
+        result = signal::ctrl_c() => Ok(result?),
+    }
+}
+
+// Serves pieces to the peers that connect to us. Which of them get served is
+// up to the choker, asked again every `RECHOKE_INTERVAL`.
+pub struct Uploader {
+    info: TorrentInfo,
+    info_hash: Vec<u8>,
+    storage: Arc<dyn Storage>,
+    have: Mutex<Bitfield>,
+    peers: Mutex<HashMap<SocketAddrV4, UploadPeer>>,
+    downloaded: Mutex<HashMap<[u8; 20], u64>>, // bytes our downloads got, by peer id
+    choker: Mutex<Choker>,
+    pex: Arc<PexSwarm>,
+    encryption: EncryptionPolicy,
+    limits: MessageLimits,
+    timeouts: PeerTimeouts,
+}
+
+struct UploadPeer {
+    peer_id: [u8; 20],
+    interested: bool,
+    choked: bool,
+    uploaded: u64,
+    allowed_fast: BTreeSet<u32>,
+    // totals at the last rechoke, rates are measured from there
+    last_uploaded: u64,
+    last_downloaded: u64,
+    control: mpsc::UnboundedSender<Message>,
+}
+
+impl Uploader {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        info: &TorrentInfo,
+        storage: Arc<dyn Storage>,
+        have: Bitfield,
+        slots: usize,
+        pex: Arc<PexSwarm>,
+        encryption: EncryptionPolicy,
+        limits: MessageLimits,
+        timeouts: PeerTimeouts
+    ) -> Result<Self> {
+        Ok(Self{
+            info: info.clone(),
+            info_hash: info.get_hash()?,
+            storage,
+            have: Mutex::new(have),
+            peers: Mutex::new(HashMap::new()),
+            downloaded: Mutex::new(HashMap::new()),
+            choker: Mutex::new(Choker::new(slots)),
+            pex,
+            encryption,
+            limits,
+            timeouts,
+        })
+    }
+
+    // Accept peers over `transport`, which has to be listening, and rechoke them
+    // until the future is dropped, which also drops every connection
+    pub async fn serve(self: Arc<Self>, transport: Arc<dyn Transport>) -> Result<()> {
+        let mut connections = JoinSet::new();
+        let mut rechoke = time::interval(RECHOKE_INTERVAL);
+        let mut last_rechoke = Instant::now();
+        loop {
+            let stream = tokio::select! {
+                accepted = transport.accept() => accepted?,
+                _ = rechoke.tick() => {
+                    self.rechoke(last_rechoke.elapsed());
+                    last_rechoke = Instant::now();
+                    continue;
+                },
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            };
+            let uploader = self.clone();
+            connections.spawn(async move {
+                if let Err(e) = uploader.serve_peer(stream).await {
+                    eprintln!("Stopped uploading to a peer: {}", e);
+                }
+            });
+        }
+    }
+
+    // Our download finished a piece: credit the peer with the bytes it sent
+    // for tit-for-tat, and let the peers we serve know we have the piece
+    pub fn piece_downloaded(&self, peer_id: &[u8; 20], piece_index: u32, bytes: u64) {
+        *self.downloaded.lock().unwrap().entry(*peer_id).or_insert(0) += bytes;
+        let mut have = self.have.lock().unwrap();
+        if have.get(piece_index as usize) {
+            return;
+        }
+        have.set(piece_index as usize, true);
+        drop(have);
+        for peer in self.peers.lock().unwrap().values() {
+            let _ = peer.control.send(Message::Have(piece_index));
+        }
+    }
+
+    async fn serve_peer(&self, stream: PeerStream) -> Result<()> {
+        let SocketAddr::V4(peer_addr) = stream.peer_addr()? else {
+            bail!("only IPv4 peers are supported");
+        };
+        let mut extensions = ExtensionRegistry::new();
+        extensions.set_listen_port(stream.local_addr()?.port());
+        extensions.register(pex::EXTENSION_NAME, Box::new(self.pex.handler(peer_addr, false)));
+        let mut connection = PeerConnection::accept(
+            stream,
+            &self.info,
+            extensions,
+            self.encryption,
+            self.limits,
+            self.timeouts
+        ).await?;
+        let addr = connection.peer_addr;
+        let (control, mut control_receiver) = mpsc::unbounded_channel();
+        let allowed_fast = match connection.fast {
+            true => allowed_fast_set(*addr.ip(), &self.info_hash, self.info.get_piece_num(), ALLOWED_FAST_PIECES),
+            false => BTreeSet::new(),
+        };
+        let last_downloaded = self.downloaded.lock().unwrap().get(&connection.peer_id).copied().unwrap_or(0);
+        self.peers.lock().unwrap().insert(addr, UploadPeer{
+            peer_id: connection.peer_id,
+            interested: false,
+            choked: true,
+            uploaded: 0,
+            allowed_fast,
+            last_uploaded: 0,
+            last_downloaded,
+            control,
+        });
+
+        let result = self.exchange(&mut connection, &mut control_receiver).await;
+        self.peers.lock().unwrap().remove(&addr);
+        result
+    }
+
+    async fn exchange(
+        &self,
+        connection: &mut PeerConnection,
+        control_receiver: &mut mpsc::UnboundedReceiver<Message>
+    ) -> Result<()> {
+        let addr = connection.peer_addr;
+        let (have, allowed_fast) = {
+            let have = self.have.lock().unwrap().clone();
+            let peers = self.peers.lock().unwrap();
+            let allowed_fast = peers.get(&addr).map(|peer| peer.allowed_fast.clone()).unwrap_or_default();
+            (have, allowed_fast)
+        };
+        let have_message = match connection.fast {
+            true if have.count_ones() == have.len() => Message::HaveAll,
+            true if have.count_ones() == 0 => Message::HaveNone,
+            _ => Message::Bitfield(have.as_bytes().to_vec()),
+        };
+        connection.send(&have_message).await?;
+        connection.send_extension_handshake().await?;
+        for piece_index in allowed_fast {
+            if have.get(piece_index as usize) {
+                connection.send(&Message::AllowedFast(piece_index)).await?;
+            }
+        }
+
+        loop {
+            tokio::select! {
+                message = connection.next_message() => match message? {
+                    Message::Interested => self.set_interested(addr, true),
+                    Message::NotInterested => self.set_interested(addr, false),
+                    Message::Request{ index, begin, length } => {
+                        // without the fast extension requests we refuse are dropped
+                        // silently, a choked peer asks again once unchoked
+                        if !self.may_serve(addr, index, begin, length) {
+                            if connection.fast {
+                                connection.send(&Message::Reject{ index, begin, length }).await?;
+                            }
+                            continue;
+                        }
+                        let block = self.storage.read_block(index, begin, length)?;
+                        connection.send(&Message::Piece{ index, begin, block }).await?;
+                        if let Some(peer) = self.peers.lock().unwrap().get_mut(&addr) {
+                            peer.uploaded += length as u64;
+                        }
+                    },
+                    _ => {},
+                },
+                Some(message) = control_receiver.recv() => connection.send(&message).await?,
+            }
+        }
+    }
+
+    // an interested peer is unchoked right away while a slot is free, otherwise
+    // it waits for the next rechoke
+    fn set_interested(&self, addr: SocketAddrV4, interested: bool) {
+        let slots = self.choker.lock().unwrap().slots();
+        let mut peers = self.peers.lock().unwrap();
+        let unchoked = peers.values().filter(|peer| !peer.choked).count();
+        let Some(peer) = peers.get_mut(&addr) else {
+            return;
+        };
+        peer.interested = interested;
+        if interested && peer.choked && unchoked < slots {
+            peer.choked = false;
+            let _ = peer.control.send(Message::Unchoke);
+        }
+    }
+
+    fn may_serve(&self, addr: SocketAddrV4, piece_index: u32, begin: u32, length: u32) -> bool {
+        let unchoked = self.peers
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .is_some_and(|peer| !peer.choked || peer.allowed_fast.contains(&piece_index));
+        let in_piece = (piece_index as usize) < self.info.get_piece_num()
+            && begin.checked_add(length).is_some_and(|end| end <= self.info.get_piece_length_real(piece_index));
+        unchoked
+            && in_piece
+            && length > 0
+            && self.have.lock().unwrap().get(piece_index as usize)
+    }
+
+    fn rechoke(&self, elapsed: Duration) {
+        let seeding = {
+            let have = self.have.lock().unwrap();
+            have.count_ones() == have.len()
+        };
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let downloaded = self.downloaded.lock().unwrap();
+        let mut peers = self.peers.lock().unwrap();
+        let stats: Vec<PeerStats> = peers
+            .iter_mut()
+            .map(|(&addr, peer)| {
+                let total_downloaded = downloaded.get(&peer.peer_id).copied().unwrap_or(0);
+                let stats = PeerStats{
+                    addr,
+                    interested: peer.interested,
+                    download_rate: (total_downloaded - peer.last_downloaded) as f64 / seconds,
+                    upload_rate: (peer.uploaded - peer.last_uploaded) as f64 / seconds,
+                };
+                peer.last_downloaded = total_downloaded;
+                peer.last_uploaded = peer.uploaded;
+                stats
+            })
+            .collect();
+
+        let unchoked = self.choker.lock().unwrap().rechoke(&stats, seeding, Instant::now());
+        for (addr, peer) in peers.iter_mut() {
+            let choked = !unchoked.contains(addr);
+            if choked != peer.choked {
+                peer.choked = choked;
+                let _ = peer.control.send(if choked { Message::Choke } else { Message::Unchoke });
+            }
+        }
+    }
+}
+
+// The canonical allowed fast set of BEP 6, derived from the peer's /24 network
+// and the info hash so reconnecting from a nearby address gains nothing. Holds
+// `count` pieces, or every piece of a smaller torrent.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8], piece_num: usize, count: usize) -> BTreeSet<u32> {
+    let mut allowed_fast = BTreeSet::new();
+    let wanted = count.min(piece_num);
+    let mut x = (u32::from(ip) & 0xFFFFFF00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while allowed_fast.len() < wanted {
+        x = encoder::encode_sha1(&x).unwrap_or_default();
+        for chunk in x.chunks_exact(4) {
+            if allowed_fast.len() == wanted {
+                break;
+            }
+            let y = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            allowed_fast.insert(y % piece_num as u32);
+        }
+    }
+    allowed_fast
+}
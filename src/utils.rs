@@ -3,15 +3,26 @@
 use anyhow::Result;
 use hex;
 
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 use crate::magnet::MagnetLink;
 use crate::torrent::TorrentFile;
 use crate::tracker::TrackerResponse;
+use crate::verify::{PieceStatus, VerifyReport};
+
+// how long a receive loop waits after its socket fails, so a socket failing
+// over and over does not spin
+pub const SOCKET_ERROR_BACKOFF: Duration = Duration::from_millis(500);
 
 // Command "info" printing
 pub fn print_torrent(torrent: &TorrentFile) -> Result<()> {
     // print tracker url and info length
     println!("Tracker URL: {}", torrent.announce);
-    println!("Length: {}", torrent.info.length);
+    println!("Length: {}", torrent.info.total_length());
 
     // print info hash
     let info_hash = torrent.get_hash()?;
@@ -38,6 +49,32 @@ pub fn print_peers(tracker_response: &TrackerResponse) {
 
 // Command "magnet_parse" printing
 pub fn print_magnet(magnet_info: &MagnetLink) {
-    println!("Tracker URL: {}", magnet_info.tr);
+    match &magnet_info.tr {
+        Some(tr) => println!("Tracker URL: {}", tr),
+        None => println!("Tracker URL: none, peers come from the DHT"),
+    }
     println!("Info Hash: {}", magnet_info.get_hex_hash());
 }
+
+// Command "verify" printing
+pub fn print_verify(report: &VerifyReport) {
+    println!("Good Pieces: {}", report.count(PieceStatus::Good));
+    println!("Bad Pieces: {}", report.count(PieceStatus::Bad));
+    println!("Missing Pieces: {}", report.count(PieceStatus::Missing));
+    println!("Completion: {:.2}%", report.percentage());
+    println!("Bitfield: {}", hex::encode(report.bitfield().as_bytes()));
+}
+
+// Replace the file at `path` with `data` through a temporary file next to it. The
+// data is synced to disk before the rename, so after a crash the file holds
+// either the old or the new content, never a torn or empty one.
+pub fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let mut temp_path = OsString::from(path);
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
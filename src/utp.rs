@@ -0,0 +1,911 @@
+// utp.rs
+
+use anyhow::{bail, Result};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration, Instant};
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::utils;
+
+const VERSION: u8 = 1;
+
+// packet types
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+const HEADER_LENGTH: usize = 20;
+
+// payload of one packet, keeps packets under the usual path MTU
+const MAX_PAYLOAD: usize = 1400;
+
+// LEDBAT: the window grows while the queuing delay our packets see stays under
+// the target, and shrinks once they start queuing behind other traffic
+const TARGET_DELAY: u32 = 100_000; // microseconds
+const MAX_WINDOW_INCREASE: f64 = 3000.0; // bytes per round trip
+const MIN_WINDOW: usize = MAX_PAYLOAD;
+const MAX_WINDOW: usize = 1024 * 1024;
+const INITIAL_WINDOW: usize = 2 * MAX_PAYLOAD;
+
+// the lowest delay of the last one to two of these is taken as the base delay
+const BASE_DELAY_INTERVAL: Duration = Duration::from_secs(60);
+
+// bytes buffered for the reader, advertised as our window, and for the writer
+const RECEIVE_BUFFER: usize = 1024 * 1024;
+const SEND_BUFFER: usize = 64 * 1024;
+
+// packets further ahead of the last one in order are dropped
+const MAX_OUT_OF_ORDER: u16 = 1024;
+
+// bytes held for the reader and out of order together. A packet not fitting is
+// dropped, past our advertised window by more than the one packet a sender may
+// probe a closed window with.
+const MAX_BUFFERED: usize = RECEIVE_BUFFER + MAX_PAYLOAD;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+
+// a packet sent this often without an ack ends the connection
+const MAX_TRANSMISSIONS: u32 = 6;
+
+// a packet is lost once this many acks repeat the one before it, or this many
+// packets after it are selectively acked
+const LOSS_THRESHOLD: u32 = 3;
+
+// a SYN is sent this often a second apart before connecting fails
+const SYN_SENDS: u32 = 3;
+const SYN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// incoming connections waiting for `accept`
+const ACCEPT_QUEUE: usize = 16;
+
+const MAX_DATAGRAM: usize = 65536;
+
+// the fixed part of every packet
+#[derive(Clone, Copy, Debug)]
+struct Header {
+    kind: u8,
+    extension: u8,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_difference: u32,
+    window: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_LENGTH] {
+        let mut bytes = [0u8; HEADER_LENGTH];
+        bytes[0] = self.kind << 4 | VERSION;
+        bytes[1] = self.extension;
+        bytes[2..4].copy_from_slice(&self.connection_id.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.timestamp_difference.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.window.to_be_bytes());
+        bytes[16..18].copy_from_slice(&self.seq_nr.to_be_bytes());
+        bytes[18..20].copy_from_slice(&self.ack_nr.to_be_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug)]
+struct Packet {
+    header: Header,
+    selective_ack: Option<Vec<u8>>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    // None for anything that is not a uTP packet, like the DHT's bencoded ones
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LENGTH || bytes[0] & 0x0f != VERSION || bytes[0] >> 4 > ST_SYN {
+            return None;
+        }
+        let u16_at = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        let header = Header{
+            kind: bytes[0] >> 4,
+            extension: bytes[1],
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+        };
+        // extensions are chained, each naming the type of the next
+        let mut selective_ack = None;
+        let mut extension = header.extension;
+        let mut offset = HEADER_LENGTH;
+        while extension != 0 {
+            let next = *bytes.get(offset)?;
+            let length = *bytes.get(offset + 1)? as usize;
+            let data = bytes.get(offset + 2..offset + 2 + length)?;
+            if extension == EXTENSION_SELECTIVE_ACK {
+                selective_ack = Some(data.to_vec());
+            }
+            extension = next;
+            offset += 2 + length;
+        }
+        Some(Self{ header, selective_ack, payload: bytes[offset..].to_vec() })
+    }
+}
+
+// `a` comes before `b`, sequence numbers wrap around
+fn seq_less(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+fn now_micros() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u32
+}
+
+type ConnectionKey = (SocketAddr, u16);
+type Connections = Arc<Mutex<HashMap<ConnectionKey, mpsc::UnboundedSender<Packet>>>>;
+type OtherPackets = Arc<Mutex<Option<mpsc::UnboundedSender<(Vec<u8>, SocketAddrV4)>>>>;
+
+// A UDP socket carrying uTP connections (BEP 29), both ones we open and ones
+// peers open to us. Packets that are not uTP can be handed to the DHT sharing
+// the port.
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+    connections: Connections,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<UtpStream>>,
+    other_packets: OtherPackets,
+    receiver: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddrV4) -> Result<Arc<Self>> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let connections: Connections = Arc::new(Mutex::new(HashMap::new()));
+        let (incoming_sender, incoming) = mpsc::channel(ACCEPT_QUEUE);
+        let other_packets: OtherPackets = Arc::new(Mutex::new(None));
+        let receiver = tokio::spawn(receive(socket.clone(), connections.clone(), incoming_sender, other_packets.clone()));
+        Ok(Arc::new(Self{
+            socket,
+            connections,
+            incoming: tokio::sync::Mutex::new(incoming),
+            other_packets,
+            receiver: Mutex::new(Some(receiver)),
+        }))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    // The socket and the packets arriving on it that are not uTP, for the DHT
+    // to answer on the same port
+    pub fn share(&self) -> (Arc<UdpSocket>, mpsc::UnboundedReceiver<(Vec<u8>, SocketAddrV4)>) {
+        let (sender, packets) = mpsc::unbounded_channel();
+        *self.other_packets.lock().unwrap() = Some(sender);
+        (self.socket.clone(), packets)
+    }
+
+    pub async fn connect(&self, peer_addr: SocketAddrV4) -> Result<UtpStream> {
+        let peer = SocketAddr::V4(peer_addr);
+        let recv_id = loop {
+            let recv_id: u16 = rand::random();
+            if !self.connections.lock().unwrap().contains_key(&(peer, recv_id)) {
+                break recv_id;
+            }
+        };
+        let (established, connected) = oneshot::channel();
+        let stream = start_connection(
+            &self.socket,
+            &self.connections,
+            peer,
+            recv_id,
+            recv_id.wrapping_add(1),
+            1,
+            0,
+            Some(established)
+        )?;
+        match connected.await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => Err(e),
+            Err(_) => bail!("uTP connection to {} failed", peer_addr),
+        }
+    }
+
+    // Wait for a peer to connect
+    pub async fn accept(&self) -> Result<UtpStream> {
+        match self.incoming.lock().await.recv().await {
+            Some(stream) => Ok(stream),
+            None => bail!("uTP socket closed"),
+        }
+    }
+}
+
+impl fmt::Debug for UtpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpSocket")
+            .field("local_addr", &self.socket.local_addr().ok())
+            .field("connections", &self.connections.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            receiver.abort();
+        }
+    }
+}
+
+// hand packets to their connections until the socket is dropped
+async fn receive(
+    socket: Arc<UdpSocket>,
+    connections: Connections,
+    incoming: mpsc::Sender<UtpStream>,
+    other_packets: OtherPackets
+) {
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (length, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("uTP socket error: {}", e);
+                time::sleep(utils::SOCKET_ERROR_BACKOFF).await;
+                continue;
+            },
+        };
+        let Some(packet) = Packet::parse(&buffer[..length]) else {
+            if let (SocketAddr::V4(from), Some(other_packets)) = (from, other_packets.lock().unwrap().as_ref()) {
+                let _ = other_packets.send((buffer[..length].to_vec(), from));
+            }
+            continue;
+        };
+        let header = packet.header;
+        // a SYN names the id the peer receives on, ours is the next one
+        let recv_id = match header.kind {
+            ST_SYN => header.connection_id.wrapping_add(1),
+            _ => header.connection_id,
+        };
+        let connection = connections.lock().unwrap().get(&(from, recv_id)).cloned();
+        match connection {
+            Some(connection) => {
+                let _ = connection.send(packet);
+            },
+            None if header.kind == ST_SYN => {
+                let Ok(stream) = start_connection(
+                    &socket,
+                    &connections,
+                    from,
+                    recv_id,
+                    header.connection_id,
+                    rand::random(),
+                    header.seq_nr,
+                    None
+                ) else {
+                    continue;
+                };
+                if let Some(connection) = connections.lock().unwrap().get(&(from, recv_id)) {
+                    let _ = connection.send(packet);
+                }
+                // nobody accepting, the dropped stream closes the connection again
+                let _ = incoming.try_send(stream);
+            },
+            None if header.kind == ST_DATA || header.kind == ST_FIN => {
+                let reset = Header{
+                    kind: ST_RESET,
+                    extension: 0,
+                    connection_id: header.connection_id,
+                    timestamp: now_micros(),
+                    timestamp_difference: 0,
+                    window: 0,
+                    seq_nr: rand::random(),
+                    ack_nr: header.seq_nr,
+                };
+                let _ = socket.send_to(&reset.to_bytes(), from).await;
+            },
+            None => {},
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn start_connection(
+    socket: &Arc<UdpSocket>,
+    connections: &Connections,
+    peer: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    seq_nr: u16,
+    ack_nr: u16,
+    established: Option<oneshot::Sender<Result<()>>>
+) -> Result<UtpStream> {
+    let (sender, inbound) = mpsc::unbounded_channel();
+    connections.lock().unwrap().insert((peer, recv_id), sender);
+    let shared = Arc::new(Shared::default());
+    let connecting = established.is_some();
+    let connection = Connection{
+        socket: socket.clone(),
+        peer,
+        recv_id,
+        send_id,
+        seq_nr,
+        ack_nr,
+        connected: !connecting,
+        established,
+        shared: shared.clone(),
+        outbox: Vec::new(),
+        unacked: VecDeque::new(),
+        in_flight: 0,
+        max_window: INITIAL_WINDOW as f64,
+        peer_window: MAX_PAYLOAD,
+        rtt: None,
+        timeout: INITIAL_TIMEOUT,
+        timeout_at: None,
+        last_ack: ack_nr,
+        duplicate_acks: 0,
+        recovery_end: None,
+        reply_micro: 0,
+        base_delay: BaseDelay::new(),
+        out_of_order: HashMap::new(),
+        out_of_order_bytes: 0,
+        read_buffered: 0,
+        fin_sent: false,
+        fin_received: false,
+        ack_pending: !connecting,
+        failed: false,
+    };
+    let local_addr = socket.local_addr()?;
+    let key = (peer, recv_id);
+    let connections = connections.clone();
+    tokio::spawn(async move {
+        connection.run(inbound).await;
+        connections.lock().unwrap().remove(&key);
+    });
+    Ok(UtpStream{ shared, peer_addr: peer, local_addr })
+}
+
+// What a connection and its stream share: the bytes in each direction and who
+// to wake about them
+#[derive(Default)]
+struct Shared {
+    buffers: Mutex<Buffers>,
+    notify: Notify, // the stream wrote, read or closed
+}
+
+#[derive(Default)]
+struct Buffers {
+    read: VecDeque<u8>,
+    eof: bool,
+    error: Option<io::ErrorKind>,
+    read_waker: Option<Waker>,
+    write: VecDeque<u8>,
+    write_waker: Option<Waker>,
+    closing: bool,       // no more writes, a FIN follows the buffered bytes
+    dropped: bool,       // nobody reads any more either
+    window_update: bool, // the reader made room in a nearly full buffer
+}
+
+impl Buffers {
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// One connection as a byte stream, like a TcpStream
+pub struct UtpStream {
+    shared: Arc<Shared>,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl fmt::Debug for UtpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpStream").field("peer_addr", &self.peer_addr).finish()
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut buffers = self.shared.buffers.lock().unwrap();
+        if !buffers.read.is_empty() {
+            let was_full = buffers.read.len() + MAX_PAYLOAD > RECEIVE_BUFFER;
+            let length = buffers.read.len().min(buf.remaining());
+            let data: Vec<u8> = buffers.read.drain(..length).collect();
+            buf.put_slice(&data);
+            if was_full {
+                buffers.window_update = true;
+                self.shared.notify.notify_one();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = buffers.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if buffers.eof {
+            return Poll::Ready(Ok(()));
+        }
+        buffers.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let mut buffers = self.shared.buffers.lock().unwrap();
+        if let Some(kind) = buffers.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if buffers.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let space = SEND_BUFFER - buffers.write.len();
+        if space == 0 {
+            buffers.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let length = data.len().min(space);
+        buffers.write.extend(&data[..length]);
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(length))
+    }
+
+    // done once everything written went out, not necessarily acked
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut buffers = self.shared.buffers.lock().unwrap();
+        if let Some(kind) = buffers.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if buffers.write.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        buffers.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.buffers.lock().unwrap().closing = true;
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut buffers = self.shared.buffers.lock().unwrap();
+        buffers.closing = true;
+        buffers.dropped = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+// a packet waiting for its ack
+struct Sent {
+    kind: u8,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+// the lowest one-way delay seen lately, queuing is measured against it
+struct BaseDelay {
+    current: u32,
+    previous: u32,
+    since: Instant,
+}
+
+impl BaseDelay {
+    fn new() -> Self {
+        Self{ current: u32::MAX, previous: u32::MAX, since: Instant::now() }
+    }
+
+    fn add(&mut self, delay: u32) -> u32 {
+        if self.since.elapsed() >= BASE_DELAY_INTERVAL {
+            self.previous = self.current;
+            self.current = u32::MAX;
+            self.since = Instant::now();
+        }
+        self.current = self.current.min(delay);
+        self.current.min(self.previous)
+    }
+}
+
+// The state of one connection, driven by a task of its own that handles the
+// peer's packets, the stream's writes and retransmission timeouts
+struct Connection {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    seq_nr: u16, // of the next packet we send
+    ack_nr: u16, // of the last packet received in order
+    connected: bool,
+    established: Option<oneshot::Sender<Result<()>>>,
+    shared: Arc<Shared>,
+    outbox: Vec<Vec<u8>>,
+    unacked: VecDeque<Sent>,
+    in_flight: usize,
+    max_window: f64,
+    peer_window: usize,
+    rtt: Option<(f64, f64)>, // smoothed round trip time and its variance, in seconds
+    timeout: Duration,
+    timeout_at: Option<Instant>,
+    last_ack: u16,
+    duplicate_acks: u32,
+    recovery_end: Option<u16>, // the window is not halved again until this packet is acked
+    reply_micro: u32, // delay of the peer's last packet, sent back to it
+    base_delay: BaseDelay,
+    out_of_order: HashMap<u16, Packet>,
+    out_of_order_bytes: usize,
+    read_buffered: usize, // bytes the reader has yet to take, as of the last look
+    fin_sent: bool,
+    fin_received: bool,
+    ack_pending: bool,
+    failed: bool,
+}
+
+impl Connection {
+    async fn run(mut self, mut inbound: mpsc::UnboundedReceiver<Packet>) {
+        if !self.connected {
+            self.send_new(ST_SYN, Vec::new());
+            self.timeout_at = Some(Instant::now() + SYN_TIMEOUT);
+        }
+        let shared = self.shared.clone();
+        loop {
+            self.send_pending().await;
+            if self.is_finished() {
+                return;
+            }
+            let deadline = self.timeout_at.unwrap_or_else(|| Instant::now() + MAX_TIMEOUT);
+            tokio::select! {
+                packet = inbound.recv() => match packet {
+                    Some(packet) => self.handle(packet),
+                    None => self.fail(io::ErrorKind::ConnectionAborted),
+                },
+                _ = shared.notify.notified() => {},
+                _ = time::sleep_until(deadline) => self.on_timeout(),
+            }
+            // answer a burst of packets with one ack
+            while let Ok(packet) = inbound.try_recv() {
+                self.handle(packet);
+            }
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        if self.failed {
+            return true;
+        }
+        let dropped = self.shared.buffers.lock().unwrap().dropped;
+        match self.connected {
+            true => self.fin_sent && self.unacked.is_empty() && (self.fin_received || dropped),
+            false => dropped && self.established.is_none(),
+        }
+    }
+
+    fn handle(&mut self, packet: Packet) {
+        let header = packet.header;
+        self.reply_micro = now_micros().wrapping_sub(header.timestamp);
+        self.peer_window = header.window as usize;
+        match header.kind {
+            ST_RESET => return self.fail(io::ErrorKind::ConnectionReset),
+            // the peer missed our answer to its SYN
+            ST_SYN => {
+                self.ack_pending = self.connected;
+                return;
+            },
+            _ => {},
+        }
+        if !self.connected {
+            if header.kind != ST_STATE {
+                return;
+            }
+            self.connected = true;
+            self.ack_nr = header.seq_nr.wrapping_sub(1);
+            if let Some(established) = self.established.take() {
+                let _ = established.send(Ok(()));
+            }
+        }
+        self.process_ack(header.kind, header.ack_nr, packet.selective_ack.as_deref(), header.timestamp_difference);
+        if header.kind == ST_DATA || header.kind == ST_FIN {
+            self.receive(packet);
+        }
+    }
+
+    fn receive(&mut self, packet: Packet) {
+        let seq_nr = packet.header.seq_nr;
+        self.ack_pending = true;
+        let is_new = seq_less(self.ack_nr, seq_nr) && !self.out_of_order.contains_key(&seq_nr);
+        self.read_buffered = self.shared.buffers.lock().unwrap().read.len();
+        if !is_new || self.read_buffered + self.out_of_order_bytes + packet.payload.len() > MAX_BUFFERED {
+            return;
+        }
+        if seq_nr == self.ack_nr.wrapping_add(1) {
+            self.deliver(packet);
+            while let Some(packet) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                self.out_of_order_bytes -= packet.payload.len();
+                self.deliver(packet);
+            }
+        } else if seq_nr.wrapping_sub(self.ack_nr) <= MAX_OUT_OF_ORDER {
+            self.out_of_order_bytes += packet.payload.len();
+            self.out_of_order.insert(seq_nr, packet);
+        }
+    }
+
+    fn deliver(&mut self, packet: Packet) {
+        self.ack_nr = packet.header.seq_nr;
+        if self.fin_received {
+            return;
+        }
+        let mut buffers = self.shared.buffers.lock().unwrap();
+        buffers.read.extend(&packet.payload);
+        self.read_buffered = buffers.read.len();
+        if packet.header.kind == ST_FIN {
+            self.fin_received = true;
+            buffers.eof = true;
+        }
+        buffers.wake();
+    }
+
+    fn process_ack(&mut self, kind: u8, ack_nr: u16, selective_ack: Option<&[u8]>, delay: u32) {
+        let now = Instant::now();
+        let mut acked = Vec::new();
+        while self.unacked.front().is_some_and(|sent| !seq_less(ack_nr, sent.seq_nr)) {
+            acked.extend(self.unacked.pop_front());
+        }
+        // bit i of the selective ack stands for packet ack_nr + 2 + i
+        let mut selectively_acked = Vec::new();
+        for (index, byte) in selective_ack.unwrap_or_default().iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (1 << bit) == 0 {
+                    continue;
+                }
+                let seq_nr = ack_nr.wrapping_add(2 + (index * 8 + bit) as u16);
+                selectively_acked.push(seq_nr);
+                if let Some(position) = self.unacked.iter().position(|sent| sent.seq_nr == seq_nr) {
+                    acked.extend(self.unacked.remove(position));
+                }
+            }
+        }
+
+        // the peer keeps acking the same packet, the one after it was lost
+        match kind == ST_STATE && ack_nr == self.last_ack && !self.unacked.is_empty() {
+            true => self.duplicate_acks += 1,
+            false => self.duplicate_acks = 0,
+        }
+        self.last_ack = ack_nr;
+        if self.recovery_end.is_some_and(|end| !seq_less(ack_nr, end)) {
+            self.recovery_end = None;
+        }
+        self.detect_losses(&selectively_acked);
+
+        if acked.is_empty() {
+            return;
+        }
+        let mut acked_bytes = 0;
+        for sent in &acked {
+            acked_bytes += sent.payload.len();
+            if sent.transmissions == 1 {
+                self.update_rtt((now - sent.sent_at).as_secs_f64());
+            }
+        }
+        self.in_flight -= acked_bytes;
+        self.timeout_at = (!self.unacked.is_empty()).then(|| now + self.timeout);
+        if acked_bytes > 0 {
+            self.update_window(acked_bytes, delay);
+        }
+    }
+
+    // Send again the packets the acks show lost: the first one after repeated
+    // acks, and every one with enough later packets selectively acked. A packet
+    // is not sent again within a round trip of its last send. The window is
+    // halved once per window of losses.
+    fn detect_losses(&mut self, selectively_acked: &[u16]) {
+        let round_trip = self.rtt.map_or(self.timeout, |(rtt, _)| Duration::from_secs_f64(rtt));
+        let lost: Vec<usize> = self.unacked.iter()
+            .enumerate()
+            .filter(|&(position, sent)| {
+                let acked_after = selectively_acked.iter().filter(|&&seq_nr| seq_less(sent.seq_nr, seq_nr)).count() as u32;
+                (acked_after >= LOSS_THRESHOLD || (position == 0 && self.duplicate_acks >= LOSS_THRESHOLD))
+                    && sent.sent_at.elapsed() > round_trip
+            })
+            .map(|(position, _)| position)
+            .collect();
+        if lost.is_empty() {
+            return;
+        }
+        if self.recovery_end.is_none() {
+            self.max_window = (self.max_window / 2.0).max(MIN_WINDOW as f64);
+            self.recovery_end = Some(self.seq_nr);
+        }
+        for position in lost {
+            self.retransmit(position);
+        }
+    }
+
+    fn update_rtt(&mut self, sample: f64) {
+        let (rtt, rtt_var) = match self.rtt {
+            None => (sample, sample / 2.0),
+            Some((rtt, rtt_var)) => (rtt + (sample - rtt) / 8.0, rtt_var + ((rtt - sample).abs() - rtt_var) / 4.0),
+        };
+        self.rtt = Some((rtt, rtt_var));
+        self.timeout = Duration::from_secs_f64(rtt + 4.0 * rtt_var).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    // LEDBAT: grow by up to MAX_WINDOW_INCREASE a round trip while under the
+    // target delay, shrink as far over it
+    fn update_window(&mut self, acked_bytes: usize, delay: u32) {
+        let off_target = match delay {
+            0 => 1.0, // the peer has not measured our delay yet
+            _ => {
+                let base_delay = self.base_delay.add(delay);
+                let queuing_delay = delay.wrapping_sub(base_delay).min(2 * TARGET_DELAY);
+                (TARGET_DELAY as f64 - queuing_delay as f64) / TARGET_DELAY as f64
+            },
+        };
+        let window_factor = (acked_bytes as f64 / self.max_window).min(1.0);
+        self.max_window = (self.max_window + MAX_WINDOW_INCREASE * off_target * window_factor)
+            .clamp(MIN_WINDOW as f64, MAX_WINDOW as f64);
+    }
+
+    fn on_timeout(&mut self) {
+        let now = Instant::now();
+        let Some(front) = self.unacked.front() else {
+            self.timeout_at = None;
+            return;
+        };
+        if !self.connected {
+            if front.transmissions >= SYN_SENDS {
+                if let Some(established) = self.established.take() {
+                    let _ = established.send(Err(anyhow::anyhow!("uTP connection to {} timed out", self.peer)));
+                }
+                self.failed = true;
+                return;
+            }
+            self.retransmit(0);
+            self.timeout_at = Some(now + SYN_TIMEOUT);
+            return;
+        }
+        if front.transmissions >= MAX_TRANSMISSIONS {
+            return self.fail(io::ErrorKind::TimedOut);
+        }
+        self.max_window = MIN_WINDOW as f64;
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+        self.retransmit(0);
+        self.timeout_at = Some(now + self.timeout);
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.failed = true;
+        let mut buffers = self.shared.buffers.lock().unwrap();
+        buffers.error = Some(kind);
+        buffers.wake();
+    }
+
+    // Queue what the window allows of the written bytes, then the FIN once the
+    // stream is closed, and an ack if no data packet carries one
+    async fn send_pending(&mut self) {
+        if self.connected && !self.failed {
+            let shared = self.shared.clone();
+            let mut buffers = shared.buffers.lock().unwrap();
+            self.read_buffered = buffers.read.len();
+            loop {
+                let length = buffers.write.len().min(MAX_PAYLOAD);
+                let window = (self.max_window as usize).min(self.peer_window);
+                if length == 0 || (self.in_flight > 0 && self.in_flight + length > window) {
+                    break;
+                }
+                let payload = buffers.write.drain(..length).collect();
+                self.send_new(ST_DATA, payload);
+            }
+            if buffers.write.len() < SEND_BUFFER {
+                if let Some(waker) = buffers.write_waker.take() {
+                    waker.wake();
+                }
+            }
+            if buffers.closing && buffers.write.is_empty() && !self.fin_sent {
+                self.fin_sent = true;
+                self.send_new(ST_FIN, Vec::new());
+            }
+            if buffers.window_update {
+                buffers.window_update = false;
+                self.ack_pending = true;
+            }
+        }
+        if self.ack_pending && self.connected {
+            self.ack_pending = false;
+            let datagram = self.datagram(ST_STATE, self.seq_nr, &[]);
+            self.outbox.push(datagram);
+        }
+        for datagram in std::mem::take(&mut self.outbox) {
+            let _ = self.socket.send_to(&datagram, self.peer).await;
+        }
+    }
+
+    fn send_new(&mut self, kind: u8, payload: Vec<u8>) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = seq_nr.wrapping_add(1);
+        self.in_flight += payload.len();
+        let datagram = self.datagram(kind, seq_nr, &payload);
+        self.outbox.push(datagram);
+        self.unacked.push_back(Sent{ kind, seq_nr, payload, sent_at: Instant::now(), transmissions: 1 });
+        self.timeout_at.get_or_insert_with(|| Instant::now() + self.timeout);
+        // the packet carries our ack
+        self.ack_pending = false;
+    }
+
+    fn retransmit(&mut self, position: usize) {
+        let Some(sent) = self.unacked.get_mut(position) else {
+            return;
+        };
+        sent.transmissions += 1;
+        sent.sent_at = Instant::now();
+        let (kind, seq_nr, payload) = (sent.kind, sent.seq_nr, sent.payload.clone());
+        let datagram = self.datagram(kind, seq_nr, &payload);
+        self.outbox.push(datagram);
+    }
+
+    fn datagram(&self, kind: u8, seq_nr: u16, payload: &[u8]) -> Vec<u8> {
+        let selective_ack = self.selective_ack();
+        let header = Header{
+            kind,
+            extension: if selective_ack.is_some() { EXTENSION_SELECTIVE_ACK } else { 0 },
+            // a SYN carries the id we receive on, everything else the peer's
+            connection_id: if kind == ST_SYN { self.recv_id } else { self.send_id },
+            timestamp: now_micros(),
+            timestamp_difference: self.reply_micro,
+            window: RECEIVE_BUFFER.saturating_sub(self.read_buffered + self.out_of_order_bytes) as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+        };
+        let mut datagram = header.to_bytes().to_vec();
+        if let Some(selective_ack) = selective_ack {
+            datagram.extend_from_slice(&[0, selective_ack.len() as u8]);
+            datagram.extend(selective_ack);
+        }
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    // which of the 32 packets after the next expected one arrived already
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+        let mut bitmask = vec![0u8; 4];
+        for index in 0..32 {
+            if self.out_of_order.contains_key(&self.ack_nr.wrapping_add(2 + index as u16)) {
+                bitmask[index / 8] |= 1 << (index % 8);
+            }
+        }
+        Some(bitmask)
+    }
+}
@@ -0,0 +1,104 @@
+// verify.rs
+
+use anyhow::Result;
+use tokio::task;
+
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::bitfield::Bitfield;
+use crate::encoder;
+use crate::storage::{FileStorage, Storage};
+use crate::torrent::TorrentInfo;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PieceStatus {
+    Good,
+    Bad,     // data present but the hash does not match
+    Missing, // a covering file is absent or too short
+}
+
+pub struct VerifyReport {
+    pub statuses: Vec<PieceStatus>,
+}
+
+impl VerifyReport {
+    pub fn count(&self, status: PieceStatus) -> usize {
+        self.statuses.iter().filter(|&&piece_status| piece_status == status).count()
+    }
+
+    pub fn percentage(&self) -> f64 {
+        match self.statuses.len() {
+            0 => 100.0,
+            piece_num => self.count(PieceStatus::Good) as f64 * 100.0 / piece_num as f64,
+        }
+    }
+
+    // bitfield of good pieces
+    pub fn bitfield(&self) -> Bitfield {
+        let mut bitfield = Bitfield::new(self.statuses.len());
+        for (piece_index, status) in self.statuses.iter().enumerate() {
+            bitfield.set(piece_index, *status == PieceStatus::Good);
+        }
+        bitfield
+    }
+}
+
+// Hash every piece of the data stored at `root` against the torrent
+pub async fn verify(info: &TorrentInfo, root: &Path) -> Result<VerifyReport> {
+    verify_storage(info, Arc::new(FileStorage::new(info, root))).await
+}
+
+// Pieces are handed out to one blocking worker per core and read one at a time,
+// so memory use stays at a piece per worker whatever the payload size.
+pub async fn verify_storage(info: &TorrentInfo, storage: Arc<dyn Storage>) -> Result<VerifyReport> {
+    let piece_num = info.get_piece_num();
+    let info = Arc::new(info.clone());
+    let next_piece = Arc::new(AtomicUsize::new(0));
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get()).min(piece_num.max(1));
+
+    let mut tasks = Vec::new();
+    for _ in 0..workers {
+        let info_clone = info.clone();
+        let storage_clone = storage.clone();
+        let next_piece_clone = next_piece.clone();
+
+        let task = task::spawn_blocking(move || -> Result<Vec<(usize, PieceStatus)>> {
+            let mut results = Vec::new();
+            loop {
+                let piece_index = next_piece_clone.fetch_add(1, Ordering::Relaxed);
+                if piece_index >= piece_num {
+                    break;
+                }
+                let status = check_piece(&info_clone, storage_clone.as_ref(), piece_index as u32)?;
+                results.push((piece_index, status));
+            }
+            Ok(results)
+        });
+        tasks.push(task);
+    }
+
+    let mut statuses = vec![PieceStatus::Missing; piece_num];
+    for task in tasks {
+        for (piece_index, status) in task.await?? {
+            statuses[piece_index] = status;
+        }
+    }
+    Ok(VerifyReport{ statuses })
+}
+
+// a piece that cannot be read, because a file is absent or too short, is missing
+fn check_piece(info: &TorrentInfo, storage: &dyn Storage, piece_index: u32) -> Result<PieceStatus> {
+    let piece_length = info.get_piece_length_real(piece_index);
+    let piece_buffer = match storage.read_block(piece_index, 0, piece_length) {
+        Ok(piece_buffer) => piece_buffer,
+        Err(_) => return Ok(PieceStatus::Missing),
+    };
+
+    let hash = encoder::encode_sha1(&piece_buffer)?;
+    match hash == info.get_piece_hash(piece_index) {
+        true => Ok(PieceStatus::Good),
+        false => Ok(PieceStatus::Bad),
+    }
+}
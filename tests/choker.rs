@@ -0,0 +1,90 @@
+// Which peers the choker hands the upload slots to, and the optimistic unchoke
+
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use bittorrent::choker::{Choker, PeerStats, OPTIMISTIC_INTERVAL, RECHOKE_INTERVAL};
+
+fn addr(host: u8) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, host), 6881)
+}
+
+fn peer(host: u8, interested: bool, download_rate: f64, upload_rate: f64) -> PeerStats {
+    PeerStats{ addr: addr(host), interested, download_rate, upload_rate }
+}
+
+fn addrs(hosts: &[u8]) -> HashSet<SocketAddrV4> {
+    hosts.iter().map(|&host| addr(host)).collect()
+}
+
+#[test]
+fn fastest_interested_peers_get_the_slots() {
+    // peer 1 is the fastest either way but not interested
+    let peers = [
+        peer(1, false, 900.0, 900.0),
+        peer(2, true, 300.0, 10.0),
+        peer(3, true, 100.0, 500.0),
+        peer(4, true, 200.0, 400.0),
+        peer(5, true, 0.0, 0.0),
+    ];
+    // the two regular slots, and one of the others unchoked optimistically
+    let check = |unchoked: HashSet<SocketAddrV4>, regular: &[u8]| {
+        assert_eq!(unchoked.len(), 3, "{:?}", unchoked);
+        assert!(unchoked.is_superset(&addrs(regular)), "{:?}", unchoked);
+        assert!(!unchoked.contains(&addr(1)), "{:?}", unchoked);
+    };
+    let now = Instant::now();
+    // downloading, our best sources
+    check(Choker::new(2).rechoke(&peers, false, now), &[2, 4]);
+    // seeding, the peers taking the most from us
+    check(Choker::new(2).rechoke(&peers, true, now), &[3, 4]);
+
+    // nobody interested, nobody unchoked
+    let peers = [peer(1, false, 900.0, 900.0)];
+    assert!(Choker::new(2).rechoke(&peers, false, now).is_empty());
+}
+
+#[test]
+fn optimistic_unchoke_rotates_every_30_seconds() {
+    // peer 1 holds the one regular slot, the others take optimistic turns
+    let peers = [
+        peer(1, true, 500.0, 0.0),
+        peer(2, true, 0.0, 0.0),
+        peer(3, true, 0.0, 0.0),
+        peer(4, true, 0.0, 0.0),
+    ];
+    let optimistic = |unchoked: HashSet<SocketAddrV4>| {
+        assert_eq!(unchoked.len(), 2);
+        assert!(unchoked.contains(&addr(1)));
+        unchoked.into_iter().find(|&unchoked| unchoked != addr(1)).unwrap()
+    };
+    let mut choker = Choker::new(1);
+    let start = Instant::now();
+    let mut current = optimistic(choker.rechoke(&peers, false, start));
+    for turn in 1..=5u32 {
+        let turn_start = start + turn * OPTIMISTIC_INTERVAL;
+        // rechokes within the 30 seconds leave it where it is
+        for rechoke in 1..3u32 {
+            let now = turn_start - OPTIMISTIC_INTERVAL + rechoke * RECHOKE_INTERVAL - Duration::from_millis(1);
+            assert_eq!(optimistic(choker.rechoke(&peers, false, now)), current);
+        }
+        let next = optimistic(choker.rechoke(&peers, false, turn_start));
+        assert_ne!(next, current, "turn {}", turn);
+        current = next;
+    }
+}
+
+#[test]
+fn optimistic_unchoke_moves_on_from_peers_that_lose_interest() {
+    let mut choker = Choker::new(1);
+    let now = Instant::now();
+    let peers = [peer(1, true, 500.0, 0.0), peer(2, true, 0.0, 0.0)];
+    assert_eq!(choker.rechoke(&peers, false, now), addrs(&[1, 2]));
+
+    let peers = [peer(1, true, 500.0, 0.0), peer(2, false, 0.0, 0.0), peer(3, true, 0.0, 0.0)];
+    assert_eq!(choker.rechoke(&peers, false, now + RECHOKE_INTERVAL), addrs(&[1, 3]));
+    // earning a regular slot frees the optimistic one too
+    let peers = [peer(1, true, 0.0, 0.0), peer(3, true, 500.0, 0.0)];
+    assert_eq!(choker.rechoke(&peers, false, now + 2 * RECHOKE_INTERVAL), addrs(&[1, 3]));
+}
@@ -0,0 +1,171 @@
+// Fixtures shared by the integration tests: payloads and their torrents, a
+// tracker stand-in, seeders on the in-memory network and scratch directories.
+// Each test file uses only some of them.
+#![allow(dead_code)]
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use bittorrent::bitfield::Bitfield;
+use bittorrent::encoder;
+use bittorrent::message::MessageLimits;
+use bittorrent::mse::EncryptionPolicy;
+use bittorrent::peer::PeerTimeouts;
+use bittorrent::pex::PexSwarm;
+use bittorrent::storage::{MemoryStorage, Storage};
+use bittorrent::torrent::{FileEntry, TorrentInfo};
+use bittorrent::tracker;
+use bittorrent::transport::{MemoryNetwork, Transport};
+use bittorrent::upload::Uploader;
+
+// bytes that differ from piece to piece, so misplaced data fails the hash check
+pub fn payload(length: usize) -> Vec<u8> {
+    (0..length as u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect()
+}
+
+fn piece_hashes(payload: &[u8], piece_length: u32) -> Vec<u8> {
+    let mut pieces = Vec::new();
+    for piece in payload.chunks(piece_length as usize) {
+        pieces.extend_from_slice(&encoder::encode_sha1(piece).unwrap());
+    }
+    pieces
+}
+
+// a single-file torrent of `payload`
+pub fn torrent(name: &str, piece_length: u32, payload: &[u8]) -> TorrentInfo {
+    TorrentInfo{
+        length: payload.len() as u64,
+        files: None,
+        name: name.to_string(),
+        piece_length,
+        pieces: piece_hashes(payload, piece_length),
+    }
+}
+
+// a multi-file torrent of `payload` split into files of `lengths` bytes
+pub fn multi_file_torrent(name: &str, piece_length: u32, payload: &[u8], lengths: &[u64]) -> TorrentInfo {
+    assert_eq!(lengths.iter().sum::<u64>(), payload.len() as u64);
+    let files = lengths
+        .iter()
+        .enumerate()
+        .map(|(index, &length)| FileEntry{ length, path: vec![format!("file{}.bin", index)] })
+        .collect();
+    TorrentInfo{
+        length: 0,
+        files: Some(files),
+        name: name.to_string(),
+        piece_length,
+        pieces: piece_hashes(payload, piece_length),
+    }
+}
+
+pub fn all_pieces(piece_num: usize) -> Bitfield {
+    let mut pieces = Bitfield::new(piece_num);
+    for piece_index in 0..piece_num {
+        pieces.set(piece_index, true);
+    }
+    pieces
+}
+
+// A fresh directory under the system's temporary one, removed by the caller
+pub fn test_dir(prefix: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}-{:08x}", prefix, std::process::id(), rand::random::<u32>()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// A seeder running the real upload pipeline at `addr`, holding all of `payload`
+pub async fn start_seeder(
+    network: &Arc<MemoryNetwork>,
+    addr: SocketAddrV4,
+    info: &TorrentInfo,
+    payload: &[u8],
+    encryption: EncryptionPolicy,
+    timeouts: PeerTimeouts
+) {
+    let have = all_pieces(info.get_piece_num());
+    start_partial_seeder(network, addr, info, payload, &have, encryption, timeouts).await;
+}
+
+// A peer running the upload pipeline with only the pieces of `payload` in `have`
+pub async fn start_partial_seeder(
+    network: &Arc<MemoryNetwork>,
+    addr: SocketAddrV4,
+    info: &TorrentInfo,
+    payload: &[u8],
+    have: &Bitfield,
+    encryption: EncryptionPolicy,
+    timeouts: PeerTimeouts
+) {
+    start_uploader(network, addr, info, payload, have, 4, encryption, timeouts).await;
+}
+
+// The uploader behind a partial seeder, with `slots` upload slots
+#[allow(clippy::too_many_arguments)]
+pub async fn start_uploader(
+    network: &Arc<MemoryNetwork>,
+    addr: SocketAddrV4,
+    info: &TorrentInfo,
+    payload: &[u8],
+    have: &Bitfield,
+    slots: usize,
+    encryption: EncryptionPolicy,
+    timeouts: PeerTimeouts
+) {
+    let storage = MemoryStorage::new(info);
+    for (piece_index, piece) in payload.chunks(info.piece_length as usize).enumerate() {
+        if have.get(piece_index) {
+            storage.write_block(piece_index as u32, 0, piece).unwrap();
+        }
+    }
+    let (discovered, _) = mpsc::unbounded_channel();
+    let uploader = Arc::new(Uploader::new(
+        info,
+        Arc::new(storage),
+        have.clone(),
+        slots,
+        PexSwarm::new(discovered),
+        encryption,
+        MessageLimits::default(),
+        timeouts
+    ).unwrap());
+    let transport: Arc<dyn Transport> = Arc::new(network.transport(*addr.ip()));
+    transport.listen(addr).await.unwrap();
+    tokio::spawn(uploader.serve(transport));
+}
+
+// An HTTP tracker on loopback handing out `peers` to every announce, returns the
+// announce url
+pub async fn tracker_stand_in(peers: Vec<SocketAddrV4>) -> String {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let compact = tracker::encode_compact_peers(&peers);
+    let mut body = format!("d8:intervali1800e5:peers{}:", compact.len()).into_bytes();
+    body.extend_from_slice(&compact);
+    body.push(b'e');
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let body = body.clone();
+            tokio::spawn(async move {
+                // the request is a GET without a body
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(length) => request.extend_from_slice(&buffer[..length]),
+                    }
+                }
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                let _ = stream.write_all(header.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            });
+        }
+    });
+    url
+}
@@ -0,0 +1,85 @@
+// DHT nodes on loopback finding each other and the peers announced to them
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+
+use bittorrent::dht::{Dht, MAX_INFO_HASHES};
+
+const CLUSTER_SIZE: usize = 12;
+
+async fn cluster() -> Vec<Arc<Dht>> {
+    let mut nodes = Vec::new();
+    for _ in 0..CLUSTER_SIZE {
+        nodes.push(Dht::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0), None).await.unwrap());
+    }
+    let bootstrap = vec![nodes[0].local_addr().unwrap().to_string()];
+    for node in &nodes[1..] {
+        node.bootstrap(&bootstrap).await;
+    }
+    // the first nodes joined before the rest existed
+    for node in &nodes {
+        node.bootstrap(&[]).await;
+    }
+    nodes
+}
+
+#[tokio::test]
+async fn bootstrap_fills_routing_tables() {
+    let nodes = cluster().await;
+    for node in &nodes {
+        assert!(node.node_num() >= CLUSTER_SIZE / 2, "node knows only {} nodes", node.node_num());
+    }
+}
+
+#[tokio::test]
+async fn announced_peer_is_found_by_other_nodes() {
+    let nodes = cluster().await;
+    let info_hash = [0x5a; 20];
+    nodes[3].announce(&info_hash, 51413).await.unwrap();
+
+    let peer = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 51413);
+    for node in &nodes {
+        let peers = node.get_peers(&info_hash).await.unwrap();
+        assert!(peers.contains(&peer), "{:?} missing from {:?}", peer, peers);
+    }
+    assert!(nodes[5].get_peers(&[0xa5; 20]).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn routing_table_survives_restart() {
+    let nodes = cluster().await;
+    let path = std::env::temp_dir().join(format!("dht-test-{}.state", std::process::id()));
+    nodes[1].save_state(&path).unwrap();
+
+    let restarted = Dht::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0), Some(&path)).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(restarted.id(), nodes[1].id());
+    assert_eq!(restarted.node_num(), nodes[1].node_num());
+    // no bootstrap node needed, the saved nodes answer
+    assert!(restarted.bootstrap(&[]).await >= CLUSTER_SIZE / 2);
+}
+
+#[tokio::test]
+async fn stored_info_hashes_are_capped() {
+    let node = Dht::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0), None).await.unwrap();
+    let announcer = Dht::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0), None).await.unwrap();
+    let SocketAddr::V4(addr) = node.local_addr().unwrap() else { unreachable!() };
+    let info_hash = |index: usize| {
+        let mut hash = [0u8; 20];
+        hash[..8].copy_from_slice(&(index as u64).to_be_bytes());
+        hash
+    };
+
+    // tokens depend only on our ip, one serves every announce
+    let token = announcer.get_peers_from(addr, &info_hash(0)).await.unwrap().token.unwrap();
+    for index in 0..=MAX_INFO_HASHES {
+        announcer.announce_peer(addr, &info_hash(index), 6881, token.clone()).await.unwrap();
+    }
+    let peer = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881);
+    assert_eq!(announcer.get_peers_from(addr, &info_hash(0)).await.unwrap().peers, vec![peer]);
+    assert!(announcer.get_peers_from(addr, &info_hash(MAX_INFO_HASHES)).await.unwrap().peers.is_empty());
+
+    // peers already stored for a hash can still be updated
+    announcer.announce_peer(addr, &info_hash(1), 6882, token).await.unwrap();
+    assert_eq!(announcer.get_peers_from(addr, &info_hash(1)).await.unwrap().peers.len(), 2);
+}
@@ -0,0 +1,125 @@
+// Downloading a byte range of the payload: which pieces it covers, where they go
+// in the part file, and the slice cut out of them
+
+mod common;
+
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bittorrent::bitfield::Bitfield;
+use bittorrent::download::{self, DownloadOptions};
+use bittorrent::mse::EncryptionPolicy;
+use bittorrent::peer::{PeerTimeouts, BLOCK_SIZE};
+use bittorrent::storage::{FileStorage, Storage};
+use bittorrent::torrent::TorrentInfo;
+use bittorrent::transport::MemoryNetwork;
+
+const PIECE_LENGTH: u32 = 2 * BLOCK_SIZE;
+const PIECE_NUM: usize = 6;
+const SEEDER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+const LEECHER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 100);
+
+// two files meeting inside piece 2, the last piece short
+fn torrent() -> (TorrentInfo, Vec<u8>) {
+    let payload = common::payload(PIECE_NUM * PIECE_LENGTH as usize - 5000);
+    let first = 2 * PIECE_LENGTH as u64 + 1234;
+    let info = common::multi_file_torrent("range-test", PIECE_LENGTH, &payload, &[first, payload.len() as u64 - first]);
+    (info, payload)
+}
+
+// Download `range` from a peer holding only the pieces covering it, so fetching
+// any other piece would stall the download
+async fn download_range(info: &TorrentInfo, payload: &[u8], range: Range<u64>) -> Vec<u8> {
+    let piece_length = PIECE_LENGTH as u64;
+    let mut covering = Bitfield::new(PIECE_NUM);
+    for piece_index in range.start / piece_length..=(range.end - 1) / piece_length {
+        covering.set(piece_index as usize, true);
+    }
+    let network = MemoryNetwork::new();
+    common::start_partial_seeder(&network, SEEDER, info, payload, &covering, EncryptionPolicy::Disabled, PeerTimeouts::default()).await;
+    let options = DownloadOptions{
+        transport: Some(Arc::new(network.transport(LEECHER_IP))),
+        encryption: EncryptionPolicy::Disabled,
+        ..Default::default()
+    };
+    let announce = common::tracker_stand_in(vec![SEEDER]).await;
+
+    let dir = common::test_dir("range-test");
+    let out_path = dir.join("slice.bin");
+    let download = download::download_range(info, &announce, range.clone(), &out_path, &options);
+    tokio::time::timeout(Duration::from_secs(30), download).await.expect("download timed out").unwrap();
+    let slice = fs::read(&out_path).unwrap();
+    assert!(!dir.join("slice.bin.part").exists());
+    fs::remove_dir_all(&dir).unwrap();
+    slice
+}
+
+#[tokio::test]
+async fn ranges_starting_and_ending_mid_piece() {
+    let (info, payload) = torrent();
+    let piece_length = PIECE_LENGTH as u64;
+    for range in [
+        100..200,                                  // inside one piece
+        piece_length - 10..piece_length + 10,      // across a piece boundary
+        piece_length + 5..4 * piece_length - 5,    // over whole pieces in between
+    ] {
+        let slice = download_range(&info, &payload, range.clone()).await;
+        assert!(slice == payload[range.start as usize..range.end as usize], "{:?}", range);
+    }
+}
+
+#[tokio::test]
+async fn range_of_exactly_the_last_short_piece() {
+    let (info, payload) = torrent();
+    let last_piece = (PIECE_NUM - 1) as u64 * PIECE_LENGTH as u64;
+    let slice = download_range(&info, &payload, last_piece..payload.len() as u64).await;
+    assert_eq!(slice.len(), info.get_piece_length_real(PIECE_NUM as u32 - 1) as usize);
+    assert!(slice == payload[last_piece as usize..]);
+}
+
+#[tokio::test]
+async fn range_across_a_file_boundary() {
+    let (info, payload) = torrent();
+    let boundary = 2 * PIECE_LENGTH as u64 + 1234;
+    let range = boundary - 100..boundary + 100;
+    let slice = download_range(&info, &payload, range.clone()).await;
+    assert!(slice == payload[range.start as usize..range.end as usize]);
+}
+
+#[tokio::test]
+async fn ranges_past_the_payload_are_refused() {
+    let (info, _) = torrent();
+    let total_length = info.total_length();
+    let dir = common::test_dir("range-test");
+    let out_path = dir.join("slice.bin");
+    for range in [10..10, total_length - 1..total_length + 1] {
+        let result = download::download_range(&info, "", range.clone(), &out_path, &DownloadOptions::default()).await;
+        assert!(result.is_err(), "{:?}", range);
+    }
+    assert!(!out_path.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn covering_pieces_are_stored_back_to_back() {
+    let (info, payload) = torrent();
+    let dir = common::test_dir("range-test");
+    let path = dir.join("pieces.part");
+    let storage = FileStorage::for_pieces(&info, 3..=5, &path);
+    let piece_length = PIECE_LENGTH as usize;
+    for piece_index in 3..=5u32 {
+        let start = piece_index as usize * piece_length;
+        let length = info.get_piece_length_real(piece_index) as usize;
+        storage.write_block(piece_index, 0, &payload[start..start + length]).unwrap();
+    }
+    // pieces outside the run have nowhere to go
+    assert!(storage.write_block(2, 0, &payload[..10]).is_err());
+    storage.flush().unwrap();
+
+    assert!(fs::read(&path).unwrap() == payload[3 * piece_length..]);
+    assert_eq!(storage.read_block(4, 100, 50).unwrap(), payload[4 * piece_length + 100..4 * piece_length + 150]);
+    fs::remove_dir_all(&dir).unwrap();
+}
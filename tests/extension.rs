@@ -0,0 +1,128 @@
+// The extension protocol of BEP 10: ids on both sides, and which handler an
+// extended message goes to
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use bittorrent::extension::{ExtensionHandler, ExtensionHandshake, ExtensionRegistry, HANDSHAKE_ID};
+use bittorrent::message::Message;
+
+// Keeps the messages it gets and answers each with `reply`
+#[derive(Clone, Default)]
+struct Recorder {
+    received: Arc<Mutex<Vec<Vec<u8>>>>,
+    handshakes: Arc<Mutex<usize>>,
+    reply: Option<Vec<u8>>,
+}
+
+impl ExtensionHandler for Recorder {
+    fn on_handshake(&mut self, _handshake: &ExtensionHandshake) -> Result<()> {
+        *self.handshakes.lock().unwrap() += 1;
+        Ok(())
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.received.lock().unwrap().push(payload.to_vec());
+        Ok(self.reply.clone())
+    }
+
+    fn pending(&mut self) -> Option<Vec<u8>> {
+        Some(b"unprompted".to_vec())
+    }
+}
+
+fn peer_handshake(m: &[(&str, i64)]) -> Vec<u8> {
+    let handshake = ExtensionHandshake{
+        m: m.iter().map(|&(name, id)| (name.to_string(), id)).collect(),
+        ..Default::default()
+    };
+    handshake.to_bytes().unwrap()
+}
+
+#[test]
+fn local_ids_are_assigned_once_per_name() {
+    let mut registry = ExtensionRegistry::new();
+    assert_eq!(registry.register("ut_pex", Box::new(Recorder::default())), 1);
+    assert_eq!(registry.declare("ut_metadata"), 2);
+    // a second time keeps the id and swaps the handler
+    assert_eq!(registry.register("ut_pex", Box::new(Recorder::default())), 1);
+    assert_eq!(registry.declare("ut_pex"), 1);
+
+    assert_eq!(registry.local_id("ut_pex"), Some(1));
+    assert_eq!(registry.local_id("ut_metadata"), Some(2));
+    assert_eq!(registry.local_id("lt_donthave"), None);
+    let Message::Extended{ id: HANDSHAKE_ID, payload } = registry.handshake_message().unwrap() else {
+        panic!("not an extension handshake");
+    };
+    let handshake = ExtensionHandshake::parse(&payload).unwrap();
+    assert_eq!(handshake.m.len(), 2);
+    assert_eq!(handshake.extension_id("ut_metadata"), Some(2));
+}
+
+#[test]
+fn messages_go_to_the_handler_of_their_id() {
+    let mut registry = ExtensionRegistry::new();
+    let pex = Recorder{ reply: Some(b"pong".to_vec()), ..Default::default() };
+    let metadata = Recorder::default();
+    registry.register("ut_pex", Box::new(pex.clone()));
+    registry.register("ut_metadata", Box::new(metadata.clone()));
+    registry.declare("lt_donthave");
+    registry.handle(HANDSHAKE_ID, &peer_handshake(&[("ut_pex", 7)])).unwrap();
+    assert_eq!(*pex.handshakes.lock().unwrap(), 1);
+    assert_eq!(*metadata.handshakes.lock().unwrap(), 1);
+
+    // the reply goes out under the peer's id
+    let reply = registry.handle(1, b"ping").unwrap();
+    assert_eq!(reply, Some(Message::Extended{ id: 7, payload: b"pong".to_vec() }));
+    assert_eq!(*pex.received.lock().unwrap(), [b"ping".to_vec()]);
+    // the peer does not support ut_metadata, our handler gets its messages anyway
+    // but has nowhere to send unprompted ones
+    assert_eq!(registry.handle(2, b"piece").unwrap(), None);
+    assert_eq!(*metadata.received.lock().unwrap(), [b"piece".to_vec()]);
+    assert_eq!(registry.pending_messages(), [Message::Extended{ id: 7, payload: b"unprompted".to_vec() }]);
+
+    // declared without a handler, and ids we never assigned
+    for id in [3, 4, 255] {
+        assert_eq!(registry.handle(id, b"ping").unwrap(), None);
+    }
+    assert_eq!(pex.received.lock().unwrap().len(), 1);
+    assert_eq!(metadata.received.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn peer_handshakes_set_and_clear_remote_ids() {
+    let mut registry = ExtensionRegistry::new();
+    assert_eq!(registry.message("ut_pex", Vec::new()), None);
+    let handshake = peer_handshake(&[("ut_pex", 1), ("ut_metadata", 3), ("too_big", 256), ("negative", -1)]);
+    registry.handle(HANDSHAKE_ID, &handshake).unwrap();
+    assert_eq!(registry.remote_id("ut_pex"), Some(1));
+    assert_eq!(registry.remote_id("ut_metadata"), Some(3));
+    assert_eq!(registry.remote_id("too_big"), None);
+    assert_eq!(registry.remote_id("negative"), None);
+    assert_eq!(registry.message("ut_metadata", b"x".to_vec()), Some(Message::Extended{ id: 3, payload: b"x".to_vec() }));
+    assert_eq!(registry.peer_handshake().unwrap().m.len(), 4);
+
+    // a later handshake moves one id, disables another with 0 and leaves the rest
+    registry.handle(HANDSHAKE_ID, &peer_handshake(&[("ut_pex", 2), ("ut_metadata", 0)])).unwrap();
+    assert_eq!(registry.remote_id("ut_pex"), Some(2));
+    assert_eq!(registry.remote_id("ut_metadata"), None);
+    assert_eq!(registry.message("ut_metadata", Vec::new()), None);
+
+    assert!(registry.handle(HANDSHAKE_ID, b"not bencode").is_err());
+}
+
+#[test]
+fn handshake_fields_are_optional() {
+    let handshake = ExtensionHandshake::parse(b"de").unwrap();
+    assert!(handshake.m.is_empty());
+    assert_eq!(handshake.your_ip(), None);
+
+    let handshake = ExtensionHandshake::parse(b"d1:md6:ut_pexi0e7:ut_holei300ee1:pi6881e1:v4:test6:yourip4:\x0a\x00\x00\x07e").unwrap();
+    assert_eq!(handshake.extension_id("ut_pex"), None);
+    assert_eq!(handshake.extension_id("ut_hole"), None);
+    assert_eq!(handshake.p, Some(6881));
+    assert_eq!(handshake.v.as_deref(), Some("test"));
+    assert_eq!(handshake.your_ip(), Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7))));
+}
@@ -0,0 +1,88 @@
+// Peer handshakes, and the ones we refuse
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+use bittorrent::handshake::{Capabilities, Handshake, HandshakeError, HANDSHAKE_LENGTH};
+
+const INFO_HASH: [u8; 20] = [0xab; 20];
+const PEER_ID: [u8; 20] = *b"-RB0001-abcdefghijkl";
+
+fn handshake_bytes() -> Vec<u8> {
+    let capabilities = Capabilities{ extension: true, fast: true, dht: false };
+    Handshake::new(&INFO_HASH, PEER_ID, capabilities).unwrap().to_bytes()
+}
+
+fn parse_error(bytes: &[u8]) -> HandshakeError {
+    Handshake::parse(bytes).unwrap_err()
+}
+
+// Hands out one byte per read, like a slow link
+struct Trickle<'a>(&'a [u8]);
+
+impl AsyncRead for Trickle<'_> {
+    fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        if let Some((&byte, rest)) = self.0.split_first() {
+            buf.put_slice(&[byte]);
+            self.0 = rest;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[test]
+fn handshake_roundtrips() {
+    let bytes = handshake_bytes();
+    assert_eq!(bytes.len(), HANDSHAKE_LENGTH);
+    assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+
+    let handshake = Handshake::parse(&bytes).unwrap();
+    assert_eq!(handshake.info_hash, INFO_HASH);
+    assert_eq!(handshake.peer_id, PEER_ID);
+    assert_eq!(handshake.capabilities(), Capabilities{ extension: true, fast: true, dht: false });
+    assert_eq!(handshake.to_bytes(), bytes);
+}
+
+#[test]
+fn unknown_reserved_bits_are_kept() {
+    let mut bytes = handshake_bytes();
+    bytes[20] = 0x80;
+    bytes[27] |= 0x01;
+    let handshake = Handshake::parse(&bytes).unwrap();
+    assert!(handshake.capabilities().dht);
+    assert_eq!(handshake.to_bytes(), bytes);
+}
+
+#[test]
+fn other_protocols_are_refused() {
+    let mut bytes = handshake_bytes();
+    bytes[0] = 18;
+    assert!(matches!(parse_error(&bytes), HandshakeError::BadProtocolLength(18)));
+
+    let mut bytes = handshake_bytes();
+    bytes[1..20].copy_from_slice(b"BitTorrent protocoL");
+    assert!(matches!(parse_error(&bytes), HandshakeError::BadProtocol(_)));
+
+    assert!(matches!(parse_error(&handshake_bytes()[..67]), HandshakeError::BadLength(67)));
+}
+
+#[test]
+fn handshake_has_to_be_for_our_torrent() {
+    let handshake = Handshake::parse(&handshake_bytes()).unwrap();
+    assert!(handshake.validate(&INFO_HASH, None).is_ok());
+    assert!(handshake.validate(&INFO_HASH, Some(&PEER_ID)).is_ok());
+    assert!(matches!(handshake.validate(&[0xcd; 20], None), Err(HandshakeError::InfoHashMismatch{ .. })));
+    assert!(matches!(handshake.validate(&INFO_HASH, Some(&[0; 20])), Err(HandshakeError::PeerIdMismatch{ .. })));
+}
+
+#[tokio::test]
+async fn handshake_is_read_whole_from_a_slow_link() {
+    let bytes = handshake_bytes();
+    let handshake = Handshake::read(&mut Trickle(&bytes)).await.unwrap();
+    assert_eq!(handshake.peer_id, PEER_ID);
+
+    // a connection closing early is an error, not a handshake with a short peer id
+    assert!(Handshake::read(&mut Trickle(&bytes[..60])).await.is_err());
+}
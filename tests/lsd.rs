@@ -0,0 +1,68 @@
+// Local service discovery announcements as they arrive from the network
+
+use bittorrent::lsd::{self, Announcement};
+
+const COOKIE: &str = "0badc0de";
+
+fn info_hash(byte: u8) -> Vec<u8> {
+    vec![byte; 20]
+}
+
+#[test]
+fn announcements_name_a_port_and_info_hashes() {
+    let packet = format!(
+        "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: {}\r\ninfohash: {}\r\ncookie: someone-else\r\n\r\n\r\n",
+        hex::encode(info_hash(0xaa)),
+        hex::encode(info_hash(0xbb)).to_uppercase()
+    );
+    let announcement = lsd::parse_announcement(packet.as_bytes(), COOKIE);
+    assert_eq!(announcement, Some(Announcement{ port: 6881, info_hashes: vec![info_hash(0xaa), info_hash(0xbb)] }));
+
+    // no cookie at all, odd spacing and case in the header names
+    let packet = format!("BT-SEARCH * HTTP/1.1\r\nPORT:6882\r\n  InfoHash :  {}\r\n\r\n", hex::encode(info_hash(0xcc)));
+    let announcement = lsd::parse_announcement(packet.as_bytes(), COOKIE);
+    assert_eq!(announcement, Some(Announcement{ port: 6882, info_hashes: vec![info_hash(0xcc)] }));
+}
+
+#[test]
+fn our_own_announcements_are_ignored() {
+    let packet = format!(
+        "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: {}\r\ncookie: {}\r\n\r\n\r\n",
+        hex::encode(info_hash(0xaa)),
+        COOKIE
+    );
+    assert_eq!(lsd::parse_announcement(packet.as_bytes(), COOKIE), None);
+    assert!(lsd::parse_announcement(packet.as_bytes(), "another-client").is_some());
+}
+
+#[test]
+fn malformed_announcements_are_dropped_or_trimmed() {
+    let good_hash = hex::encode(info_hash(0xaa));
+    for packet in [
+        String::new(),
+        // another request line, or none
+        format!("M-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: {}\r\n\r\n", good_hash),
+        format!("Port: 6881\r\nInfohash: {}\r\n\r\n", good_hash),
+        // no usable port
+        format!("BT-SEARCH * HTTP/1.1\r\nInfohash: {}\r\n\r\n", good_hash),
+        format!("BT-SEARCH * HTTP/1.1\r\nPort: 0\r\nInfohash: {}\r\n\r\n", good_hash),
+        format!("BT-SEARCH * HTTP/1.1\r\nPort: 70000\r\nInfohash: {}\r\n\r\n", good_hash),
+        format!("BT-SEARCH * HTTP/1.1\r\nPort: http\r\nInfohash: {}\r\n\r\n", good_hash),
+        // the port past the end of the headers
+        format!("BT-SEARCH * HTTP/1.1\r\nInfohash: {}\r\n\r\nPort: 6881\r\n", good_hash),
+    ] {
+        assert_eq!(lsd::parse_announcement(packet.as_bytes(), COOKIE), None, "{:?}", packet);
+    }
+    assert_eq!(lsd::parse_announcement(b"BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\xff\xfe\r\n\r\n", COOKIE), None);
+
+    // info hashes that are not 20 hex encoded bytes are left out, lines without
+    // a colon skipped
+    let packet = format!(
+        "BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: {}\r\nInfohash: abcd\r\nInfohash: {}\r\ngarbage\r\nInfohash: {}\r\n\r\n",
+        "zz".repeat(20),
+        "ab".repeat(21),
+        good_hash
+    );
+    let announcement = lsd::parse_announcement(packet.as_bytes(), COOKIE);
+    assert_eq!(announcement, Some(Announcement{ port: 6881, info_hashes: vec![info_hash(0xaa)] }));
+}
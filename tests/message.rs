@@ -0,0 +1,60 @@
+// Messages peers send us that break the wire protocol
+
+use bittorrent::message::{self, Message, MessageLimits, ProtocolError};
+
+const PIECE_NUM: usize = 20;
+
+async fn read(bytes: &[u8]) -> anyhow::Result<Message> {
+    let mut reader = bytes;
+    message::read_message(&mut reader, &MessageLimits::default(), PIECE_NUM).await
+}
+
+fn protocol_error(result: anyhow::Result<Message>) -> ProtocolError {
+    result.unwrap_err().downcast().expect("not a protocol error")
+}
+
+#[tokio::test]
+async fn huge_length_is_rejected_before_reading_the_payload() {
+    // no payload follows, the length alone has to be refused
+    let error = protocol_error(read(&[0xff, 0xff, 0xff, 0xff, 7]).await);
+    assert!(matches!(error, ProtocolError::MessageTooLong{ id: 7, .. }), "{}", error);
+    let error = protocol_error(read(&[0xff, 0xff, 0xff, 0xff, 99]).await);
+    assert!(matches!(error, ProtocolError::MessageTooLong{ id: 99, .. }), "{}", error);
+}
+
+#[tokio::test]
+async fn bitfield_has_to_fit_the_torrent() {
+    let mut bytes = vec![0, 0, 0, 4, 5];
+    bytes.extend_from_slice(&[0xff; 3]);
+    assert_eq!(read(&bytes).await.unwrap(), Message::Bitfield(vec![0xff; 3]));
+
+    let mut bytes = vec![0, 0, 1, 1, 5];
+    bytes.extend_from_slice(&[0xff; 256]);
+    let error = protocol_error(read(&bytes).await);
+    assert!(matches!(error, ProtocolError::MessageTooLong{ id: 5, length: 256, limit: 3 }), "{}", error);
+}
+
+#[tokio::test]
+async fn fixed_size_messages_have_their_size() {
+    // a have with two bytes missing and an unchoke with a payload
+    let error = protocol_error(read(&[0, 0, 0, 3, 4, 0, 0]).await);
+    assert!(matches!(error, ProtocolError::BadPayloadLength{ id: 4, length: 2 }), "{}", error);
+    let error = protocol_error(read(&[0, 0, 0, 2, 1, 0]).await);
+    assert!(matches!(error, ProtocolError::MessageTooLong{ id: 1, length: 1, limit: 0 }), "{}", error);
+}
+
+#[tokio::test]
+async fn blocks_up_to_the_limit_are_read() {
+    let block = vec![7u8; message::DEFAULT_MAX_BLOCK as usize];
+    let bytes = Message::Piece{ index: 1, begin: 0, block: block.clone() }.to_bytes();
+    assert_eq!(read(&bytes).await.unwrap(), Message::Piece{ index: 1, begin: 0, block });
+
+    let bytes = Message::Piece{ index: 1, begin: 0, block: vec![7u8; message::DEFAULT_MAX_BLOCK as usize + 1] }.to_bytes();
+    let error = protocol_error(read(&bytes).await);
+    assert!(matches!(error, ProtocolError::MessageTooLong{ id: 7, .. }), "{}", error);
+}
+
+#[tokio::test]
+async fn keep_alive_has_no_id() {
+    assert_eq!(read(&[0, 0, 0, 0]).await.unwrap(), Message::KeepAlive);
+}
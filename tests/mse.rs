@@ -0,0 +1,84 @@
+// Message Stream Encryption between two ends of a pipe, under every policy pair
+
+use std::time::Duration;
+
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use bittorrent::handshake::{Capabilities, Handshake};
+use bittorrent::mse::{self, EncryptionPolicy, MseStream, Rc4};
+
+const INFO_HASH: [u8; 20] = [0x3c; 20];
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Connect with `outgoing` to a side accepting with `incoming`, then trade a
+// handshake and a reply so both directions are checked
+async fn roundtrip(outgoing: EncryptionPolicy, incoming: EncryptionPolicy) -> anyhow::Result<(bool, bool)> {
+    let (ours, theirs) = io::duplex(64 * 1024);
+    let handshake = Handshake::new(&INFO_HASH, [1; 20], Capabilities::default())?;
+
+    let connecting = async {
+        let mut stream = mse::connect(ours, &INFO_HASH, outgoing, TIMEOUT).await?;
+        stream.write_all(&handshake.to_bytes()).await?;
+        stream.flush().await?;
+        let mut reply = [0u8; 5];
+        stream.read_exact(&mut reply).await?;
+        assert_eq!(&reply, b"hello");
+        Ok::<MseStream<DuplexStream>, anyhow::Error>(stream)
+    };
+    let accepting = async {
+        let mut stream = mse::accept(theirs, &INFO_HASH, incoming, TIMEOUT).await?;
+        assert_eq!(Handshake::read(&mut stream).await?, handshake);
+        stream.write_all(b"hello").await?;
+        stream.flush().await?;
+        Ok::<MseStream<DuplexStream>, anyhow::Error>(stream)
+    };
+    let (connected, accepted) = tokio::join!(connecting, accepting);
+    Ok((connected?.is_encrypted(), accepted?.is_encrypted()))
+}
+
+#[tokio::test]
+async fn policies_agree_on_encryption() {
+    use EncryptionPolicy::*;
+    for (outgoing, incoming, encrypted) in [
+        (Disabled, Disabled, false),
+        (Disabled, Enabled, false),
+        (Enabled, Enabled, true),
+        (Enabled, Forced, true),
+        (Forced, Enabled, true),
+        (Forced, Forced, true),
+    ] {
+        let result = roundtrip(outgoing, incoming).await;
+        assert_eq!(result.ok(), Some((encrypted, encrypted)), "{:?} connecting to {:?}", outgoing, incoming);
+    }
+}
+
+#[tokio::test]
+async fn mismatched_policies_are_refused() {
+    use EncryptionPolicy::*;
+    for (outgoing, incoming) in [(Disabled, Forced), (Enabled, Disabled), (Forced, Disabled)] {
+        assert!(roundtrip(outgoing, incoming).await.is_err(), "{:?} connecting to {:?}", outgoing, incoming);
+    }
+}
+
+#[tokio::test]
+async fn silent_peer_times_out() {
+    let (ours, _theirs) = io::duplex(1024);
+    let error = mse::connect(ours, &INFO_HASH, EncryptionPolicy::Forced, Duration::from_millis(100)).await.unwrap_err();
+    assert!(error.to_string().contains("timed out"), "{}", error);
+}
+
+#[test]
+fn rc4_matches_known_answers() {
+    for (key, plaintext, ciphertext) in [
+        ("Key", "Plaintext", "bbf316e8d940af0ad3"),
+        ("Wiki", "pedia", "1021bf0420"),
+        ("Secret", "Attack at dawn", "45a01f645fc35b383552544b9bf5"),
+    ] {
+        let mut data = plaintext.as_bytes().to_vec();
+        Rc4::new(key.as_bytes()).apply(&mut data);
+        assert_eq!(hex::encode(&data), ciphertext);
+        // the same keystream decrypts again
+        Rc4::new(key.as_bytes()).apply(&mut data);
+        assert_eq!(data, plaintext.as_bytes());
+    }
+}
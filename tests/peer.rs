@@ -0,0 +1,160 @@
+// Keep-alives and timeouts of peer connections
+
+mod common;
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{self, Instant};
+
+use bittorrent::encoder;
+use bittorrent::extension::ExtensionRegistry;
+use bittorrent::handshake::{Capabilities, Handshake, HANDSHAKE_LENGTH};
+use bittorrent::message::{Message, MessageLimits, ProtocolError};
+use bittorrent::mse::EncryptionPolicy;
+use bittorrent::peer::{self, PeerConnection, PeerTimeouts, BLOCK_SIZE};
+use bittorrent::torrent::TorrentInfo;
+use bittorrent::transport::{MemoryNetwork, PeerStream, Transport};
+
+const TIMEOUTS: PeerTimeouts = PeerTimeouts{
+    connect: Duration::from_millis(200),
+    handshake: Duration::from_millis(200),
+    encryption: Duration::from_millis(200),
+    request: Duration::from_millis(200),
+    idle: Duration::from_millis(500),
+    keep_alive: Duration::from_millis(100),
+};
+
+fn torrent() -> TorrentInfo {
+    common::torrent("peer-test", BLOCK_SIZE, &common::payload(4 * BLOCK_SIZE as usize))
+}
+
+// A raw connection to a listener we accept with `PeerConnection`
+async fn connect_raw(network: &Arc<MemoryNetwork>) -> (PeerStream, PeerStream) {
+    let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+    let us = network.transport(*addr.ip());
+    us.listen(addr).await.unwrap();
+    let peer = network.transport(Ipv4Addr::new(10, 0, 0, 2)).connect(addr).await.unwrap();
+    (us.accept().await.unwrap(), peer)
+}
+
+async fn accept(stream: PeerStream, info: &TorrentInfo) -> anyhow::Result<PeerConnection> {
+    PeerConnection::accept(stream, info, ExtensionRegistry::new(), EncryptionPolicy::Disabled, MessageLimits::default(), TIMEOUTS).await
+}
+
+#[tokio::test]
+async fn keep_alives_fill_our_silences() {
+    let info = torrent();
+    let network = MemoryNetwork::new();
+    let (incoming, mut peer) = connect_raw(&network).await;
+    let handshake = Handshake::new(&info.get_hash().unwrap(), [0x42; 20], Capabilities::default()).unwrap();
+    peer.write_all(&handshake.to_bytes()).await.unwrap();
+    let _connection = accept(incoming, &info).await.unwrap();
+
+    let mut answer = [0u8; HANDSHAKE_LENGTH];
+    peer.read_exact(&mut answer).await.unwrap();
+    for _ in 0..3 {
+        let mut keep_alive = [0xffu8; 4];
+        time::timeout(Duration::from_secs(1), peer.read_exact(&mut keep_alive)).await.unwrap().unwrap();
+        assert_eq!(keep_alive, [0; 4]);
+    }
+}
+
+#[tokio::test]
+async fn silent_peers_are_dropped() {
+    let info = torrent();
+    let network = MemoryNetwork::new();
+    let (incoming, mut peer) = connect_raw(&network).await;
+    let handshake = Handshake::new(&info.get_hash().unwrap(), [0x42; 20], Capabilities::default()).unwrap();
+    peer.write_all(&handshake.to_bytes()).await.unwrap();
+    let mut connection = accept(incoming, &info).await.unwrap();
+
+    let start = Instant::now();
+    let error = time::timeout(Duration::from_secs(2), connection.next_message()).await.unwrap().unwrap_err();
+    assert!(start.elapsed() >= TIMEOUTS.idle, "dropped after {:?}: {}", start.elapsed(), error);
+    assert!(error.to_string().contains("sent nothing"), "{}", error);
+}
+
+#[tokio::test]
+async fn peers_stalling_the_handshake_are_dropped() {
+    let info = torrent();
+    let network = MemoryNetwork::new();
+    let (incoming, mut peer) = connect_raw(&network).await;
+    // half a handshake, then nothing
+    let handshake = Handshake::new(&info.get_hash().unwrap(), [0x42; 20], Capabilities::default()).unwrap();
+    peer.write_all(&handshake.to_bytes()[..30]).await.unwrap();
+
+    let result = time::timeout(Duration::from_secs(2), accept(incoming, &info)).await.unwrap();
+    let error = result.err().expect("accepted half a handshake");
+    assert!(error.to_string().contains("timed out"), "{}", error);
+}
+
+#[tokio::test]
+async fn pieces_past_the_payload_are_refused() {
+    // more piece hashes than the length has data for
+    let mut info = torrent();
+    info.pieces = vec![0; 8 * 20];
+    let network = MemoryNetwork::new();
+    let (incoming, mut peer) = connect_raw(&network).await;
+    let handshake = Handshake::new(&info.get_hash().unwrap(), [0x42; 20], Capabilities::default()).unwrap();
+    peer.write_all(&handshake.to_bytes()).await.unwrap();
+    let mut connection = accept(incoming, &info).await.unwrap();
+
+    peer.write_all(&Message::Have(6).to_bytes()).await.unwrap();
+    let error = time::timeout(Duration::from_secs(1), connection.next_message()).await.unwrap().unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(ProtocolError::BadPiece{ index: 6 })), "{}", error);
+}
+
+// A peer handing out `metadata` for `info_hash` over ut_metadata
+async fn metadata_peer(network: &Arc<MemoryNetwork>, info_hash: &[u8], metadata: &TorrentInfo) -> SocketAddrV4 {
+    let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 3), 6881);
+    let transport = network.transport(*addr.ip());
+    transport.listen(addr).await.unwrap();
+    let capabilities = Capabilities{ extension: true, ..Default::default() };
+    let handshake = Handshake::new(info_hash, [0x42; 20], capabilities).unwrap();
+    let mut extensions = ExtensionRegistry::new();
+    extensions.declare("ut_metadata");
+    let raw_info = encoder::encode_bencode(metadata).unwrap();
+    let mut data = format!("d8:msg_typei1e5:piecei0e10:total_sizei{}ee", raw_info.len()).into_bytes();
+    data.extend_from_slice(&raw_info);
+    let mut reply = Vec::new();
+    reply.extend_from_slice(&(data.len() as u32 + 2).to_be_bytes());
+    reply.extend_from_slice(&[20, 1]);
+    reply.extend_from_slice(&data);
+
+    tokio::spawn(async move {
+        let mut peer = transport.accept().await.unwrap();
+        let mut theirs = [0u8; HANDSHAKE_LENGTH];
+        peer.read_exact(&mut theirs).await.unwrap();
+        peer.write_all(&handshake.to_bytes()).await.unwrap();
+        peer.write_all(&Message::Bitfield(vec![0xf0]).to_bytes()).await.unwrap();
+        peer.write_all(&extensions.handshake_message().unwrap().to_bytes()).await.unwrap();
+        peer.write_all(&reply).await.unwrap();
+        // hold the connection open until the other side is done
+        let _ = peer.read_to_end(&mut Vec::new()).await;
+    });
+    addr
+}
+
+#[tokio::test]
+async fn metadata_must_hash_to_the_info_hash() {
+    let info = torrent();
+    let info_hash = info.get_hash().unwrap();
+    let network = MemoryNetwork::new();
+    let transport = network.transport(Ipv4Addr::new(10, 0, 0, 2));
+
+    let honest = metadata_peer(&network, &info_hash, &info).await;
+    let received = peer::magnet_request_info(honest, &info_hash, true, &transport).await.unwrap();
+    assert_eq!(received.get_hash().unwrap(), info_hash);
+
+    // a different torrent offered under our info hash
+    let network = MemoryNetwork::new();
+    let transport = network.transport(Ipv4Addr::new(10, 0, 0, 2));
+    let mut swapped = torrent();
+    swapped.name = "something-else".to_string();
+    let liar = metadata_peer(&network, &info_hash, &swapped).await;
+    let error = peer::magnet_request_info(liar, &info_hash, true, &transport).await.unwrap_err();
+    assert!(error.to_string().contains("does not match the info hash"), "{}", error);
+}
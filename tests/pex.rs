@@ -0,0 +1,75 @@
+// Peer exchange: the peers we list to others, and what we make of the lists we get
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use tokio::sync::mpsc;
+
+use bittorrent::extension::ExtensionHandler;
+use bittorrent::pex::{self, PexMessage, PexSwarm};
+
+fn addr(last: u8) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, last), 6881)
+}
+
+#[test]
+fn peers_we_dial_are_listed_once_connected() {
+    let (discovered, _) = mpsc::unbounded_channel();
+    let swarm = PexSwarm::new(discovered);
+    // a dial that never completes lists nothing
+    drop(swarm.handler(addr(1), true));
+    let handler = swarm.handler(addr(1), true);
+    assert!(swarm.connected().is_empty());
+
+    let listing = swarm.mark_connected(addr(1));
+    assert_eq!(swarm.connected().get(&addr(1)), Some(&pex::FLAG_REACHABLE));
+    drop(handler);
+    drop(listing);
+    assert!(swarm.connected().is_empty());
+}
+
+#[test]
+fn duplicate_connections_keep_the_peer_listed() {
+    let (discovered, _) = mpsc::unbounded_channel();
+    let swarm = PexSwarm::new(discovered);
+    let first = swarm.mark_connected(addr(1));
+    let second = swarm.mark_connected(addr(1));
+    swarm.add_flags(addr(1), pex::FLAG_SEED);
+
+    drop(first);
+    assert_eq!(swarm.connected().get(&addr(1)), Some(&(pex::FLAG_REACHABLE | pex::FLAG_SEED)));
+    drop(second);
+    assert!(swarm.connected().is_empty());
+}
+
+#[test]
+fn listed_peers_are_discovered_with_their_flags() {
+    let (discovered, mut found) = mpsc::unbounded_channel();
+    let swarm = PexSwarm::new(discovered);
+    let added = [(addr(2), pex::FLAG_UTP | pex::FLAG_ENCRYPTION), (addr(3), 0)];
+    let message = PexMessage::new(&added, &[]).to_bytes().unwrap();
+    swarm.handler(addr(1), true).on_message(&message).unwrap();
+
+    assert_eq!(found.try_recv().unwrap(), addr(2));
+    assert_eq!(found.try_recv().unwrap(), addr(3));
+    assert_eq!(swarm.learned_flags(addr(2)), pex::FLAG_UTP | pex::FLAG_ENCRYPTION);
+    assert_eq!(swarm.learned_flags(addr(3)), 0);
+    assert_eq!(swarm.learned_flags(addr(4)), 0);
+}
+
+#[test]
+fn dropped_peers_are_marked_until_listed_again() {
+    let (discovered, _found) = mpsc::unbounded_channel();
+    let swarm = PexSwarm::new(discovered);
+    let added = PexMessage::new(&[(addr(2), pex::FLAG_UTP)], &[]).to_bytes().unwrap();
+    let dropped = PexMessage::new(&[], &[addr(2), addr(3)]).to_bytes().unwrap();
+    swarm.handler(addr(1), true).on_message(&added).unwrap();
+    swarm.handler(addr(4), true).on_message(&dropped).unwrap();
+
+    assert!(swarm.is_dropped(addr(2)));
+    assert_eq!(swarm.learned_flags(addr(2)), 0);
+    // never listed by anyone, so nothing to forget
+    assert!(!swarm.is_dropped(addr(3)));
+
+    swarm.handler(addr(5), true).on_message(&added).unwrap();
+    assert!(!swarm.is_dropped(addr(2)));
+}
@@ -0,0 +1,152 @@
+// Which piece the picker hands out next, and which files it wants at all
+
+mod common;
+
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use glob::Pattern;
+
+use bittorrent::bitfield::Bitfield;
+use bittorrent::picker::{self, PiecePicker, Priority, SEQUENTIAL_WINDOW};
+use bittorrent::torrent::{FileEntry, TorrentInfo};
+
+const PIECE_NUM: usize = 40;
+
+// files of 100, 150 and 50 bytes in pieces of 64
+fn multi_file() -> TorrentInfo {
+    let files = [("movie.mkv", 100), ("sample.mkv", 150), ("notes.txt", 50)]
+        .into_iter()
+        .map(|(name, length)| FileEntry{ length, path: vec![name.to_string()] })
+        .collect();
+    TorrentInfo{ length: 0, files: Some(files), name: "test".to_string(), piece_length: 64, pieces: vec![0; 5 * 20] }
+}
+
+#[test]
+fn sequential_picks_stay_within_the_window() {
+    let mut picker = PiecePicker::new(PIECE_NUM);
+    picker.set_sequential(true);
+    let mut have = Bitfield::new(PIECE_NUM);
+    let available = common::all_pieces(PIECE_NUM);
+
+    for expected in 0..SEQUENTIAL_WINDOW {
+        assert_eq!(picker.pick(&have, &available, &BTreeSet::new()), Some(expected));
+    }
+    // the first missing piece holds the window back
+    assert_eq!(picker.pick(&have, &available, &BTreeSet::new()), None);
+    have.set(0, true);
+    picker.release(0);
+    assert_eq!(picker.pick(&have, &available, &BTreeSet::new()), Some(SEQUENTIAL_WINDOW));
+
+    // high priority pieces are not held back
+    picker.set_priority(PIECE_NUM as u32 - 1, Priority::High);
+    assert_eq!(picker.pick(&have, &available, &BTreeSet::new()), Some(PIECE_NUM as u32 - 1));
+}
+
+#[test]
+fn deadlines_come_first_earliest_first() {
+    let mut picker = PiecePicker::new(PIECE_NUM);
+    picker.set_sequential(true);
+    let mut have = Bitfield::new(PIECE_NUM);
+    let available = common::all_pieces(PIECE_NUM);
+    let now = Instant::now();
+    picker.set_deadline(30, now + Duration::from_secs(2));
+    picker.set_deadline(20, now + Duration::from_secs(3));
+    // an earlier deadline for the same piece wins, a later one does not
+    picker.set_deadline(20, now + Duration::from_secs(1));
+    picker.set_deadline(20, now + Duration::from_secs(5));
+    picker.set_deadline(10, now);
+    have.set(10, true);
+    picker.set_priority(35, Priority::Skip);
+    picker.set_deadline(35, now);
+
+    assert_eq!(picker.pick(&have, &available, &BTreeSet::new()), Some(20));
+    assert_eq!(picker.pick(&have, &available, &BTreeSet::new()), Some(30));
+    assert_eq!(picker.pick(&have, &available, &BTreeSet::new()), Some(0));
+}
+
+#[test]
+fn cleared_deadlines_go_back_to_priority_order() {
+    let mut picker = PiecePicker::new(PIECE_NUM);
+    picker.set_sequential(true);
+    let have = Bitfield::new(PIECE_NUM);
+    let available = common::all_pieces(PIECE_NUM);
+    let now = Instant::now();
+    picker.set_deadline(30, now);
+    picker.set_deadline(31, now + Duration::from_secs(1));
+    // a seek away from 30 and 31, then a new deadline at 10
+    picker.clear_deadline(30);
+    picker.clear_deadline(31);
+    picker.set_deadline(10, now + Duration::from_secs(5));
+
+    assert_eq!(picker.pick(&have, &available, &BTreeSet::new()), Some(10));
+    assert_eq!(picker.pick(&have, &available, &BTreeSet::new()), Some(0));
+}
+
+#[test]
+fn deadlines_need_a_peer_with_the_piece() {
+    let mut picker = PiecePicker::new(PIECE_NUM);
+    let have = Bitfield::new(PIECE_NUM);
+    let mut available = Bitfield::new(PIECE_NUM);
+    available.set(3, true);
+    picker.set_deadline(7, Instant::now());
+
+    assert_eq!(picker.pick(&have, &available, &BTreeSet::new()), Some(3));
+}
+
+#[test]
+fn endgame_doubles_up_on_the_least_fetched_piece() {
+    let mut picker = PiecePicker::new(3);
+    let have = Bitfield::new(3);
+    let available = common::all_pieces(3);
+    assert_eq!(picker.pick_endgame(&have, &available), None);
+
+    let mut picked: Vec<u32> = (0..3).filter_map(|_| picker.pick(&have, &available, &BTreeSet::new())).collect();
+    picked.sort();
+    assert_eq!(picked, vec![0, 1, 2]);
+    assert_eq!(picker.pick(&have, &available, &BTreeSet::new()), None);
+
+    let first = picker.pick_endgame(&have, &available).unwrap();
+    let second = picker.pick_endgame(&have, &available).unwrap();
+    assert_ne!(first, second);
+    // a piece going back to the picker ends endgame
+    picker.release(first);
+    picker.release(first);
+    assert_eq!(picker.pick_endgame(&have, &available), None);
+    assert_eq!(picker.pick(&have, &available, &BTreeSet::new()), Some(first));
+}
+
+#[test]
+fn missing_pieces_follow_priority_then_order() {
+    let mut picker = PiecePicker::new(6);
+    let mut have = Bitfield::new(6);
+    picker.set_priority(4, Priority::High);
+    picker.set_priority(1, Priority::Low);
+    picker.set_priority(2, Priority::Skip);
+    picker.set_priority(5, Priority::High);
+    assert_eq!(picker.missing_pieces(&have), vec![4, 5, 0, 3, 1]);
+
+    picker.set_priority(4, Priority::Normal);
+    have.set(5, true);
+    assert_eq!(picker.missing_pieces(&have), vec![0, 3, 4, 1]);
+    assert_eq!(picker.wanted().count_ones(), 5);
+}
+
+#[test]
+fn select_files_matches_globs() {
+    let info = multi_file();
+    let only = [Pattern::new("*.mkv").unwrap()];
+    let exclude = [Pattern::new("sample*").unwrap()];
+    assert_eq!(picker::select_files(&info, &only, &exclude), vec![Priority::Normal, Priority::Skip, Priority::Skip]);
+    assert_eq!(picker::select_files(&info, &[], &exclude), vec![Priority::Normal, Priority::Skip, Priority::Normal]);
+    assert_eq!(picker::select_files(&info, &[], &[]), vec![Priority::Normal; 3]);
+}
+
+#[test]
+fn pieces_shared_with_wanted_files_are_kept() {
+    let info = multi_file();
+    // bytes 0..100, 100..250 and 250..300 over pieces of 64
+    let picker = PiecePicker::from_file_priorities(&info, &[Priority::Skip, Priority::Skip, Priority::High]);
+    let priorities: Vec<Priority> = (0..5).map(|piece_index| picker.priority(piece_index)).collect();
+    assert_eq!(priorities, vec![Priority::Skip, Priority::Skip, Priority::Skip, Priority::High, Priority::High]);
+}
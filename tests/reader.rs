@@ -0,0 +1,87 @@
+// Reading a torrent as a byte stream while it downloads from an in-memory seeder
+
+mod common;
+
+use std::io::SeekFrom;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use bittorrent::download::{self, DownloadHandle, DownloadOptions};
+use bittorrent::mse::EncryptionPolicy;
+use bittorrent::peer::{PeerTimeouts, BLOCK_SIZE};
+use bittorrent::reader::TorrentReader;
+use bittorrent::storage::MemoryStorage;
+use bittorrent::torrent::TorrentInfo;
+use bittorrent::transport::MemoryNetwork;
+
+const PIECE_LENGTH: u32 = BLOCK_SIZE;
+const PIECE_NUM: usize = 64;
+const SEEDER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+const LEECHER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 100);
+
+// a payload ending short of a whole piece, and its torrent
+fn torrent() -> (TorrentInfo, Vec<u8>) {
+    let payload = common::payload(PIECE_NUM * PIECE_LENGTH as usize - 100);
+    (common::torrent("reader-test", PIECE_LENGTH, &payload), payload)
+}
+
+// Start downloading from a single seeder on an in-memory network
+async fn start_download(info: &TorrentInfo, payload: &[u8]) -> Arc<DownloadHandle> {
+    let network = MemoryNetwork::new();
+    common::start_seeder(&network, SEEDER, info, payload, EncryptionPolicy::Disabled, PeerTimeouts::default()).await;
+
+    let options = DownloadOptions{
+        transport: Some(Arc::new(network.transport(LEECHER_IP))),
+        encryption: EncryptionPolicy::Disabled,
+        ..Default::default()
+    };
+    let storage = Arc::new(MemoryStorage::new(info));
+    let announce = common::tracker_stand_in(vec![SEEDER]).await;
+    Arc::new(download::start(info, &announce, storage, &options).unwrap())
+}
+
+fn pieces_had(handle: &DownloadHandle) -> usize {
+    (0..PIECE_NUM as u32).filter(|&piece_index| handle.has_piece(piece_index)).count()
+}
+
+#[tokio::test]
+async fn reads_pull_their_pieces_ahead_of_the_rest() {
+    let (info, payload) = torrent();
+    let handle = start_download(&info, &payload).await;
+    let mut reader = TorrentReader::new(handle.clone());
+    reader.set_readahead(0);
+
+    // the last piece is due now, the picker would otherwise get to it last or at random
+    let last_piece = (PIECE_NUM - 1) as u64 * PIECE_LENGTH as u64;
+    reader.seek(SeekFrom::Start(last_piece)).await.unwrap();
+    let mut tail = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), reader.read_to_end(&mut tail)).await.unwrap().unwrap();
+    assert!(tail == payload[last_piece as usize..]);
+    assert!(pieces_had(&handle) < PIECE_NUM / 4, "{} pieces arrived before the one read", pieces_had(&handle));
+
+    reader.rewind().await.unwrap();
+    let mut whole = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), reader.read_to_end(&mut whole)).await.unwrap().unwrap();
+    assert!(whole == payload);
+}
+
+#[tokio::test]
+async fn seeking_past_the_end_reads_nothing() {
+    let (info, payload) = torrent();
+    let handle = start_download(&info, &payload).await;
+    let mut reader = TorrentReader::new(handle);
+    assert_eq!(reader.len(), payload.len() as u64);
+
+    assert_eq!(reader.seek(SeekFrom::End(1000)).await.unwrap(), payload.len() as u64 + 1000);
+    let mut buffer = [0u8; 16];
+    assert_eq!(reader.read(&mut buffer).await.unwrap(), 0);
+    assert!(reader.seek(SeekFrom::Current(-(payload.len() as i64) - 2000)).await.is_err());
+
+    // back inside, reads pick up from there
+    assert_eq!(reader.seek(SeekFrom::End(-16)).await.unwrap(), payload.len() as u64 - 16);
+    tokio::time::timeout(Duration::from_secs(10), reader.read_exact(&mut buffer)).await.unwrap().unwrap();
+    assert_eq!(buffer[..], payload[payload.len() - 16..]);
+}
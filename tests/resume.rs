@@ -0,0 +1,160 @@
+// Picking a download up again: fast-resume files, and rechecking the output
+// when there is none
+
+mod common;
+
+use std::fs::{self, File};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bittorrent::bitfield::Bitfield;
+use bittorrent::download::{self, DownloadOptions};
+use bittorrent::mse::EncryptionPolicy;
+use bittorrent::peer::{PeerTimeouts, BLOCK_SIZE};
+use bittorrent::resume::{FastResume, Progress, ResumeFile, UnfinishedPiece};
+use bittorrent::torrent::TorrentInfo;
+use bittorrent::transport::MemoryNetwork;
+
+const PIECE_LENGTH: u32 = 2 * BLOCK_SIZE;
+const PIECE_NUM: usize = 8;
+const SEEDER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+const LEECHER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 100);
+
+// two files meeting inside piece 3, the last piece short
+fn torrent() -> (TorrentInfo, Vec<u8>) {
+    let payload = common::payload(PIECE_NUM * PIECE_LENGTH as usize - 1000);
+    let first = 3 * PIECE_LENGTH as u64 + 500;
+    let info = common::multi_file_torrent("resume-test", PIECE_LENGTH, &payload, &[first, payload.len() as u64 - first]);
+    (info, payload)
+}
+
+// the output files holding what there is of `payload`, and their resume file
+fn output(dir: &Path, info: &TorrentInfo, payload: &[u8]) -> ResumeFile {
+    let root = dir.join(&info.name);
+    let resume_file = ResumeFile::new(info, root.to_str().unwrap()).unwrap();
+    for slot in &resume_file.slots {
+        fs::create_dir_all(slot.path.parent().unwrap()).unwrap();
+        let end = ((slot.offset + slot.length) as usize).min(payload.len());
+        fs::write(&slot.path, &payload[slot.offset as usize..end]).unwrap();
+    }
+    resume_file
+}
+
+fn progress() -> Progress {
+    let mut progress = Progress::new(PIECE_NUM);
+    progress.piece_verified(0);
+    progress.piece_verified(3);
+    progress.peers.insert(SEEDER);
+    progress.tracker_ids.insert("http://tracker.example/announce".to_string(), "abc".to_string());
+    progress
+}
+
+#[test]
+fn captured_progress_loads_back() {
+    let (info, payload) = torrent();
+    let dir = common::test_dir("resume-test");
+    let resume_file = output(&dir, &info, &payload);
+    let mut progress = progress();
+    progress.block_received(&info, 5, 1);
+    // the last piece is short, down to its second block
+    progress.block_received(&info, 7, 0);
+    resume_file.save(&progress).unwrap();
+
+    let fast_resume = resume_file.load().unwrap().expect("no fast-resume file");
+    assert!(fast_resume.matches(&resume_file.info_hash, &resume_file.slots));
+    let loaded = fast_resume.into_progress(&info);
+    assert_eq!(loaded.have, progress.have);
+    assert_eq!(loaded.unfinished, progress.unfinished);
+    let blocks = loaded.received_blocks(&info, 5);
+    assert!(!blocks.get(0) && blocks.get(1));
+    let blocks = loaded.received_blocks(&info, 7);
+    assert_eq!(blocks.len(), 2);
+    assert!(blocks.get(0) && !blocks.get(1));
+    assert_eq!(loaded.peers, progress.peers);
+    assert_eq!(loaded.tracker_ids, progress.tracker_ids);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn changed_files_or_another_torrent_do_not_match() {
+    let (info, payload) = torrent();
+    let dir = common::test_dir("resume-test");
+    let resume_file = output(&dir, &info, &payload);
+    let fast_resume = FastResume::capture(&resume_file.info_hash, &progress(), &resume_file.slots).unwrap();
+    assert!(fast_resume.matches(&resume_file.info_hash, &resume_file.slots));
+    assert!(!fast_resume.matches(&[0; 20], &resume_file.slots));
+
+    // written to within the same second, the size unchanged
+    let path = &resume_file.slots[1].path;
+    let file = File::options().write(true).open(path).unwrap();
+    let mtime = file.metadata().unwrap().modified().unwrap();
+    file.set_modified(mtime + Duration::from_millis(1)).unwrap();
+    assert!(!fast_resume.matches(&resume_file.info_hash, &resume_file.slots));
+
+    // cut short, the mtime put back
+    file.set_modified(mtime).unwrap();
+    assert!(fast_resume.matches(&resume_file.info_hash, &resume_file.slots));
+    file.set_len(100).unwrap();
+    file.set_modified(mtime).unwrap();
+    assert!(!fast_resume.matches(&resume_file.info_hash, &resume_file.slots));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unfinished_pieces_past_the_end_are_dropped() {
+    let (info, _) = torrent();
+    let fast_resume = FastResume{
+        info_hash: info.get_hash().unwrap(),
+        pieces: vec![0b1000_0000],
+        unfinished: vec![
+            UnfinishedPiece{ piece: 2, blocks: vec![0b0100_0000] },
+            UnfinishedPiece{ piece: PIECE_NUM as u32, blocks: vec![0b1000_0000] },
+            UnfinishedPiece{ piece: u32::MAX, blocks: vec![0b1000_0000] },
+        ],
+        files: Vec::new(),
+        peers: Vec::new(),
+        tracker_ids: Default::default(),
+    };
+    let progress = fast_resume.into_progress(&info);
+    assert_eq!(progress.have.count_ones(), 1);
+    assert_eq!(progress.unfinished.keys().copied().collect::<Vec<u32>>(), vec![2]);
+    assert!(progress.received_blocks(&info, 2).get(1));
+}
+
+// The output holds good pieces, a corrupt one and a truncated tail, and there is
+// no fast-resume file. The only peer has just the pieces the recheck should find
+// missing, so the download finishes only if the good ones were kept.
+#[tokio::test]
+async fn rechecked_pieces_are_not_fetched_again() {
+    let (info, payload) = torrent();
+    let dir = common::test_dir("resume-test");
+    let mut damaged = payload.clone();
+    damaged[2 * PIECE_LENGTH as usize + 7] ^= 0xff;
+    damaged.truncate(6 * PIECE_LENGTH as usize + 10);
+    let resume_file = output(&dir, &info, &damaged);
+
+    let mut missing = Bitfield::new(PIECE_NUM);
+    for piece_index in [2, 6, 7] {
+        missing.set(piece_index, true);
+    }
+    let network = MemoryNetwork::new();
+    common::start_partial_seeder(&network, SEEDER, &info, &payload, &missing, EncryptionPolicy::Disabled, PeerTimeouts::default()).await;
+    let options = DownloadOptions{
+        transport: Some(Arc::new(network.transport(LEECHER_IP))),
+        encryption: EncryptionPolicy::Disabled,
+        ..Default::default()
+    };
+    let announce = common::tracker_stand_in(vec![SEEDER]).await;
+    let root = dir.join(&info.name);
+    let download = download::download_file(&info, &announce, root.to_str().unwrap(), &options);
+    tokio::time::timeout(Duration::from_secs(30), download).await.expect("download timed out").unwrap();
+
+    let mut output = Vec::new();
+    for slot in &resume_file.slots {
+        output.extend_from_slice(&fs::read(&slot.path).unwrap());
+    }
+    assert!(output == payload, "output differs from the payload");
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,126 @@
+// Storage backends laying blocks out over a torrent's files
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use bittorrent::storage::{FileStorage, MemoryStorage, MmapStorage, Storage};
+use bittorrent::torrent::TorrentInfo;
+
+const PIECE_LENGTH: u32 = 64;
+
+// files of 100, 0, 300 and 50 bytes, so pieces 1 and 6 straddle a boundary and
+// piece 1 also passes over the empty file
+fn torrent() -> (TorrentInfo, Vec<u8>) {
+    let payload = common::payload(450);
+    (common::multi_file_torrent("storage-test", PIECE_LENGTH, &payload, &[100, 0, 300, 50]), payload)
+}
+
+// Write the payload in 40 byte blocks, which start and end at every offset
+// within pieces and files, then read it back the same way and whole
+fn write_and_read_back(storage: &dyn Storage, info: &TorrentInfo, payload: &[u8]) {
+    for piece_index in 0..info.get_piece_num() as u32 {
+        let piece_start = piece_index as usize * PIECE_LENGTH as usize;
+        let piece_length = info.get_piece_length_real(piece_index) as usize;
+        for offset in (0..piece_length).step_by(40) {
+            let end = (offset + 40).min(piece_length);
+            storage.write_block(piece_index, offset as u32, &payload[piece_start + offset..piece_start + end]).unwrap();
+        }
+    }
+    storage.flush().unwrap();
+
+    for piece_index in 0..info.get_piece_num() as u32 {
+        let piece_start = piece_index as usize * PIECE_LENGTH as usize;
+        let piece_length = info.get_piece_length_real(piece_index);
+        let piece = storage.read_block(piece_index, 0, piece_length).unwrap();
+        assert!(piece == payload[piece_start..piece_start + piece_length as usize], "piece {}", piece_index);
+    }
+    // bytes 94..114, the end of the first file and the start of the third
+    assert_eq!(storage.read_block(1, 30, 20).unwrap(), payload[94..114]);
+    assert!(storage.read_block(7, 0, PIECE_LENGTH).is_err());
+    assert!(storage.write_block(7, 1, &[0; 2]).is_err());
+}
+
+fn files_on_disk(root: &Path) -> Vec<Vec<u8>> {
+    (0..4).map(|index| fs::read(root.join(format!("file{}.bin", index))).unwrap()).collect()
+}
+
+fn split(payload: &[u8]) -> Vec<Vec<u8>> {
+    vec![payload[..100].to_vec(), Vec::new(), payload[100..400].to_vec(), payload[400..].to_vec()]
+}
+
+#[test]
+fn file_storage_spans_file_boundaries() {
+    let (info, payload) = torrent();
+    let dir = common::test_dir("storage-test");
+    let storage = FileStorage::new(&info, &dir);
+    assert!(!storage.has_file(0));
+
+    write_and_read_back(&storage, &info, &payload);
+    assert_eq!(files_on_disk(&dir), split(&payload));
+    assert!((0..4).all(|file_index| storage.has_file(file_index)));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn memory_storage_spans_file_boundaries() {
+    let (info, payload) = torrent();
+    let storage = MemoryStorage::new(&info);
+    assert!(!storage.has_file(0));
+
+    write_and_read_back(&storage, &info, &payload);
+    assert!(storage.to_vec() == payload);
+    // nothing is ever written to the empty file
+    assert_eq!((0..4).map(|file_index| storage.has_file(file_index)).collect::<Vec<bool>>(), [true, false, true, true]);
+}
+
+#[test]
+fn mmap_storage_spans_file_boundaries() {
+    let (info, payload) = torrent();
+    let dir = common::test_dir("storage-test");
+    let storage = MmapStorage::open(&info, &dir).unwrap();
+    assert!(!storage.has_file(0));
+
+    write_and_read_back(&storage, &info, &payload);
+    drop(storage);
+    assert_eq!(files_on_disk(&dir), split(&payload));
+    // opened again, the files are there already
+    let storage = MmapStorage::open(&info, &dir).unwrap();
+    assert!(storage.has_file(0));
+    assert_eq!(storage.read_block(6, 0, PIECE_LENGTH).unwrap(), payload[384..448]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn skipped_files_share_their_boundary_pieces_through_the_partfile() {
+    let (info, payload) = torrent();
+    let dir = common::test_dir("storage-test");
+    let partfile_path = dir.join("storage-test.parts");
+    let storage = FileStorage::new(&info, &dir).with_skipped_files(vec![false, false, true, false], partfile_path.clone());
+
+    for piece_index in [0, 1, 6] {
+        let piece_start = piece_index as usize * PIECE_LENGTH as usize;
+        let piece_length = info.get_piece_length_real(piece_index) as usize;
+        storage.write_block(piece_index, 0, &payload[piece_start..piece_start + piece_length]).unwrap();
+    }
+    // piece 3 is the skipped file's alone
+    assert!(storage.write_block(3, 0, &payload[192..256]).is_err());
+    assert!(storage.read_block(3, 0, PIECE_LENGTH).is_err());
+    storage.flush().unwrap();
+
+    assert_eq!(storage.read_block(1, 0, PIECE_LENGTH).unwrap(), payload[64..128]);
+    assert_eq!(storage.read_block(6, 0, PIECE_LENGTH).unwrap(), payload[384..448]);
+    assert!(!dir.join("file2.bin").exists());
+    assert!(!storage.has_file(2));
+    assert_eq!(fs::read(dir.join("file0.bin")).unwrap(), payload[..100]);
+    assert!(dir.join("file1.bin").exists());
+    // the tail of the last file is written in piece 7, which is left out here
+    assert_eq!(fs::read(dir.join("file3.bin")).unwrap()[..48], payload[400..448]);
+
+    // a piece-sized slot for each of pieces 1 and 6, holding their skipped bytes
+    let partfile = fs::read(&partfile_path).unwrap();
+    assert_eq!(partfile[36..64], payload[100..128]);
+    assert_eq!(partfile[64..80], payload[384..400]);
+    fs::remove_dir_all(&dir).unwrap();
+}
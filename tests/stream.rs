@@ -0,0 +1,126 @@
+// The HTTP server in front of a download, asked for ranges of a finished payload
+
+mod common;
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use bittorrent::download::{self, DownloadOptions};
+use bittorrent::stream;
+
+const PIECE_LENGTH: u32 = 1024;
+const LENGTH: usize = 5000;
+
+struct Server {
+    addr: SocketAddr,
+    payload: Vec<u8>,
+    dir: PathBuf,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Serve a payload that is on disk already, so no peer is needed
+async fn serve_finished() -> Server {
+    let payload = common::payload(LENGTH);
+    let info = common::torrent("video.mp4", PIECE_LENGTH, &payload);
+
+    let dir = common::test_dir("stream-test");
+    let path = dir.join(&info.name);
+    std::fs::write(&path, &payload).unwrap();
+    let handle = download::start_file(&info, "", path.to_str().unwrap(), &DownloadOptions::default()).await.unwrap();
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(stream::serve(Arc::new(handle), listener));
+    Server{ addr, payload, dir }
+}
+
+// Send a request and split the response into its head and body
+async fn request(addr: SocketAddr, method: &str, range: Option<&str>) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut head = format!("{} / HTTP/1.1\r\nHost: localhost\r\n", method);
+    if let Some(range) = range {
+        head.push_str(&format!("Range: {}\r\n", range));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await.unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let split = response.windows(4).position(|window| window == b"\r\n\r\n").expect("no end of head");
+    (String::from_utf8(response[..split].to_vec()).unwrap(), response[split + 4..].to_vec())
+}
+
+fn status(head: &str) -> &str {
+    head.lines().next().unwrap()
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (header, value) = line.split_once(':')?;
+        header.eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+#[tokio::test]
+async fn whole_file_without_a_range() {
+    let server = serve_finished().await;
+    let (head, body) = request(server.addr, "GET", None).await;
+    assert_eq!(status(&head), "HTTP/1.1 200 OK");
+    assert_eq!(header(&head, "Content-Type"), Some("video/mp4"));
+    assert_eq!(header(&head, "Accept-Ranges"), Some("bytes"));
+    assert!(body == server.payload);
+
+    let (head, body) = request(server.addr, "HEAD", None).await;
+    assert_eq!(header(&head, "Content-Length"), Some("5000"));
+    assert!(body.is_empty());
+}
+
+#[tokio::test]
+async fn ranges_are_served_partially() {
+    let server = serve_finished().await;
+    for (range, start, end) in [
+        ("bytes=1000-2999", 1000, 3000),  // across a piece boundary
+        ("bytes=4990-", 4990, 5000),
+        ("bytes=-10", 4990, 5000),        // suffix
+        ("bytes=-9999", 0, 5000),         // suffix longer than the file
+        ("bytes=4000-99999", 4000, 5000), // end past the file
+        ("bytes=7-7", 7, 8),
+    ] {
+        let (head, body) = request(server.addr, "GET", Some(range)).await;
+        assert_eq!(status(&head), "HTTP/1.1 206 Partial Content", "{}", range);
+        let content_range = format!("bytes {}-{}/5000", start, end - 1);
+        assert_eq!(header(&head, "Content-Range"), Some(content_range.as_str()), "{}", range);
+        assert_eq!(header(&head, "Content-Length"), Some((end - start).to_string().as_str()), "{}", range);
+        assert!(body == server.payload[start..end], "{}", range);
+    }
+}
+
+#[tokio::test]
+async fn ranges_past_the_end_are_not_satisfiable() {
+    let server = serve_finished().await;
+    for range in ["bytes=5000-", "bytes=6000-7000", "bytes=-0"] {
+        let (head, body) = request(server.addr, "GET", Some(range)).await;
+        assert_eq!(status(&head), "HTTP/1.1 416 Range Not Satisfiable", "{}", range);
+        assert_eq!(header(&head, "Content-Range"), Some("bytes */5000"), "{}", range);
+        assert!(body.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn ranges_we_do_not_understand_get_the_whole_file() {
+    let server = serve_finished().await;
+    for range in ["bytes=20-10", "bytes=0-1,5-6", "items=0-10", "bytes=a-b"] {
+        let (head, body) = request(server.addr, "GET", Some(range)).await;
+        assert_eq!(status(&head), "HTTP/1.1 200 OK", "{}", range);
+        assert!(body == server.payload, "{}", range);
+    }
+}
@@ -0,0 +1,267 @@
+// Whole swarms in one process: a tracker stand-in on loopback, seeders and
+// misbehaving peers on an in-memory network, and the real download pipeline
+
+mod common;
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use bittorrent::download::{self, DownloadOptions};
+use bittorrent::extension::ExtensionRegistry;
+use bittorrent::message::{Message, MessageLimits};
+use bittorrent::mse::EncryptionPolicy;
+use bittorrent::peer::{PeerConnection, PeerTimeouts, BLOCK_SIZE};
+use bittorrent::torrent::TorrentInfo;
+use bittorrent::transport::{MemoryNetwork, PeerStream, Transport};
+use bittorrent::verify::{self, PieceStatus};
+
+const PIECE_LENGTH: u32 = 2 * BLOCK_SIZE;
+const PIECE_NUM: usize = 24;
+const PEER_PORT: u16 = 6881;
+const LEECHER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 100);
+
+// a swarm that takes longer than this is stuck
+const SWARM_TIMEOUT: Duration = Duration::from_secs(60);
+
+// short enough for peers that went quiet to be dropped well within the swarm timeout
+const TIMEOUTS: PeerTimeouts = PeerTimeouts{
+    connect: Duration::from_secs(1),
+    handshake: Duration::from_secs(1),
+    encryption: Duration::from_secs(1),
+    request: Duration::from_secs(1),
+    idle: Duration::from_secs(3),
+    keep_alive: Duration::from_secs(1),
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Behaviour {
+    // waits this long before every block
+    Slow(Duration),
+    // announces every piece but never unchokes
+    Choking,
+    // flips the bytes of every block
+    Corrupting,
+    // closes the connection after the first block of a piece
+    Disconnecting,
+    // answers with blocks past the end of the piece
+    Misaddressing,
+    // accepts the connection but never answers the handshake
+    Unresponsive,
+    // says nothing after the handshake, not even keep-alives
+    Mute,
+    // unchokes but never answers a request
+    Ignoring,
+}
+
+struct Swarm {
+    network: Arc<MemoryNetwork>,
+    payload: Vec<u8>,
+    info: TorrentInfo,
+    peers: Vec<SocketAddrV4>,
+}
+
+impl Swarm {
+    // A payload that does not end on a piece boundary, and its torrent
+    fn new() -> Self {
+        let payload = common::payload(PIECE_NUM * PIECE_LENGTH as usize - 1000);
+        let info = common::torrent("payload.bin", PIECE_LENGTH, &payload);
+        Self{ network: MemoryNetwork::new(), payload, info, peers: Vec::new() }
+    }
+
+    fn next_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, self.peers.len() as u8 + 1), PEER_PORT)
+    }
+
+    // A seeder running the real upload pipeline
+    async fn add_seeder(&mut self) {
+        let addr = self.next_addr();
+        common::start_seeder(&self.network, addr, &self.info, &self.payload, EncryptionPolicy::Enabled, TIMEOUTS).await;
+        self.peers.push(addr);
+    }
+
+    // A peer that has every piece but serves them as `behaviour` says
+    async fn add_peer(&mut self, behaviour: Behaviour) {
+        let addr = self.next_addr();
+        let transport = self.network.transport(*addr.ip());
+        transport.listen(addr).await.unwrap();
+        let info = self.info.clone();
+        let payload = Arc::new(self.payload.clone());
+        tokio::spawn(async move {
+            while let Ok(stream) = transport.accept().await {
+                let info = info.clone();
+                let payload = payload.clone();
+                tokio::spawn(async move {
+                    let _ = serve_badly(stream, &info, &payload, behaviour).await;
+                });
+            }
+        });
+        self.peers.push(addr);
+    }
+
+    // Download through the tracker stand-in, then check the output byte for byte
+    // and piece by piece
+    async fn download(&self) {
+        let announce = common::tracker_stand_in(self.peers.clone()).await;
+        let dir = common::test_dir("swarm-test");
+        let output = dir.join(&self.info.name);
+        let options = DownloadOptions{
+            transport: Some(Arc::new(self.network.transport(LEECHER_IP))),
+            peer_timeouts: TIMEOUTS,
+            ..Default::default()
+        };
+        let download = download::download_file(&self.info, &announce, output.to_str().unwrap(), &options);
+        tokio::time::timeout(SWARM_TIMEOUT, download).await.expect("download timed out").unwrap();
+
+        let report = verify::verify(&self.info, &output).await.unwrap();
+        assert_eq!(report.count(PieceStatus::Good), PIECE_NUM);
+        assert!(std::fs::read(&output).unwrap() == self.payload, "output differs from the payload");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+async fn serve_badly(stream: PeerStream, info: &TorrentInfo, payload: &[u8], behaviour: Behaviour) -> Result<()> {
+    let timeouts = match behaviour {
+        Behaviour::Unresponsive => return std::future::pending().await,
+        Behaviour::Mute => PeerTimeouts{ keep_alive: Duration::from_secs(3600), ..TIMEOUTS },
+        _ => TIMEOUTS,
+    };
+    let mut peer = PeerConnection::accept(stream, info, ExtensionRegistry::new(), EncryptionPolicy::Enabled, MessageLimits::default(), timeouts).await?;
+    if behaviour == Behaviour::Mute {
+        while peer.next_message().await.is_ok() {}
+        return Ok(());
+    }
+    peer.send(&Message::Bitfield(common::all_pieces(PIECE_NUM).as_bytes().to_vec())).await?;
+    if behaviour != Behaviour::Choking {
+        peer.send(&Message::Unchoke).await?;
+    }
+    loop {
+        let Message::Request{ index, begin, length } = peer.next_message().await? else {
+            continue;
+        };
+        if behaviour == Behaviour::Ignoring {
+            continue;
+        }
+        let start = index as usize * PIECE_LENGTH as usize + begin as usize;
+        let mut block = payload[start..start + length as usize].to_vec();
+        match behaviour {
+            Behaviour::Slow(delay) => tokio::time::sleep(delay).await,
+            Behaviour::Corrupting => block.iter_mut().for_each(|byte| *byte = !*byte),
+            Behaviour::Disconnecting if begin > 0 => return Ok(()),
+            Behaviour::Misaddressing => {
+                peer.send(&Message::Piece{ index, begin: begin + PIECE_LENGTH, block }).await?;
+                continue;
+            },
+            _ => {},
+        }
+        peer.send(&Message::Piece{ index, begin, block }).await?;
+    }
+}
+
+#[tokio::test]
+async fn downloads_from_seeders() {
+    let mut swarm = Swarm::new();
+    for _ in 0..3 {
+        swarm.add_seeder().await;
+    }
+    swarm.download().await;
+}
+
+#[tokio::test]
+async fn slow_peers_do_not_hold_up_the_download() {
+    let mut swarm = Swarm::new();
+    swarm.add_peer(Behaviour::Slow(Duration::from_millis(200))).await;
+    swarm.add_peer(Behaviour::Slow(Duration::from_millis(200))).await;
+    swarm.add_seeder().await;
+    swarm.download().await;
+}
+
+#[tokio::test]
+async fn slow_peers_alone_finish_the_download() {
+    let mut swarm = Swarm::new();
+    swarm.add_peer(Behaviour::Slow(Duration::from_millis(10))).await;
+    swarm.add_peer(Behaviour::Slow(Duration::from_millis(10))).await;
+    swarm.download().await;
+}
+
+#[tokio::test]
+async fn choking_peers_are_worked_around() {
+    let mut swarm = Swarm::new();
+    swarm.add_peer(Behaviour::Choking).await;
+    swarm.add_peer(Behaviour::Choking).await;
+    swarm.add_seeder().await;
+    swarm.download().await;
+}
+
+#[tokio::test]
+async fn corrupt_pieces_are_fetched_again() {
+    let mut swarm = Swarm::new();
+    swarm.add_peer(Behaviour::Corrupting).await;
+    swarm.add_seeder().await;
+    swarm.download().await;
+}
+
+#[tokio::test]
+async fn peers_disconnecting_mid_piece_are_worked_around() {
+    let mut swarm = Swarm::new();
+    swarm.add_peer(Behaviour::Disconnecting).await;
+    swarm.add_peer(Behaviour::Disconnecting).await;
+    swarm.add_seeder().await;
+    swarm.download().await;
+}
+
+#[tokio::test]
+async fn peers_breaking_the_protocol_are_dropped() {
+    let mut swarm = Swarm::new();
+    swarm.add_peer(Behaviour::Misaddressing).await;
+    swarm.add_peer(Behaviour::Misaddressing).await;
+    swarm.add_seeder().await;
+    swarm.download().await;
+}
+
+#[tokio::test]
+async fn black_holed_peers_time_out() {
+    let mut swarm = Swarm::new();
+    swarm.add_peer(Behaviour::Unresponsive).await;
+    swarm.add_peer(Behaviour::Unresponsive).await;
+    swarm.add_seeder().await;
+    swarm.download().await;
+}
+
+#[tokio::test]
+async fn peers_going_quiet_are_dropped() {
+    let mut swarm = Swarm::new();
+    swarm.add_peer(Behaviour::Mute).await;
+    swarm.add_peer(Behaviour::Ignoring).await;
+    swarm.add_seeder().await;
+    swarm.download().await;
+}
+
+#[tokio::test]
+async fn mixed_swarm() {
+    let mut swarm = Swarm::new();
+    swarm.add_peer(Behaviour::Slow(Duration::from_millis(50))).await;
+    swarm.add_peer(Behaviour::Choking).await;
+    swarm.add_peer(Behaviour::Corrupting).await;
+    swarm.add_peer(Behaviour::Disconnecting).await;
+    swarm.add_peer(Behaviour::Misaddressing).await;
+    swarm.add_peer(Behaviour::Unresponsive).await;
+    swarm.add_peer(Behaviour::Ignoring).await;
+    swarm.add_seeder().await;
+    swarm.add_seeder().await;
+    swarm.download().await;
+}
+
+#[tokio::test]
+async fn file_priorities_must_cover_every_file() {
+    let swarm = Swarm::new();
+    let dir = common::test_dir("swarm-test");
+    let output = dir.join(&swarm.info.name);
+    let options = DownloadOptions{ file_priorities: Some(Vec::new()), ..Default::default() };
+    let error = download::download_file(&swarm.info, "", output.to_str().unwrap(), &options).await.unwrap_err();
+    assert!(error.to_string().contains("0 file priorities for a torrent of 1 files"), "{}", error);
+    assert!(!output.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,45 @@
+// Torrents whose pieces do not fit their payload, refused before anything uses them
+
+use bittorrent::torrent::{FileEntry, TorrentInfo};
+
+fn single_file(length: u64, piece_length: u32, piece_num: usize) -> TorrentInfo {
+    TorrentInfo{ length, files: None, name: "test".to_string(), piece_length, pieces: vec![0; piece_num * 20] }
+}
+
+fn multi_file(lengths: &[u64], piece_length: u32, piece_num: usize) -> TorrentInfo {
+    let files = lengths.iter().enumerate()
+        .map(|(index, &length)| FileEntry{ length, path: vec![format!("file{index}")] })
+        .collect();
+    TorrentInfo{ length: 0, files: Some(files), name: "test".to_string(), piece_length, pieces: vec![0; piece_num * 20] }
+}
+
+#[test]
+fn pieces_covering_the_payload_are_accepted() {
+    single_file(1000, 256, 4).validate().unwrap();
+    single_file(1024, 256, 4).validate().unwrap();
+    multi_file(&[100, 0, 412], 256, 2).validate().unwrap();
+}
+
+#[test]
+fn piece_count_has_to_match_the_length() {
+    let error = single_file(1000, 256, 8).validate().unwrap_err();
+    assert_eq!(error.to_string(), "8 piece hashes for 1000 bytes in pieces of 256");
+    assert!(single_file(1025, 256, 4).validate().is_err());
+    assert!(multi_file(&[600, 600], 256, 4).validate().is_err());
+}
+
+#[test]
+fn malformed_pieces_are_refused() {
+    assert!(single_file(1000, 0, 4).validate().is_err());
+    let mut info = single_file(1000, 256, 4);
+    info.pieces.push(0);
+    assert!(info.validate().is_err());
+    assert!(multi_file(&[u64::MAX, 1], 256, 1).validate().is_err());
+}
+
+#[test]
+fn pieces_past_the_end_are_empty() {
+    let info = single_file(1000, 256, 8);
+    assert_eq!(info.get_piece_length_real(3), 1000 - 3 * 256);
+    assert_eq!(info.get_piece_length_real(6), 0);
+}
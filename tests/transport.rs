@@ -0,0 +1,44 @@
+// Peers connecting to each other over an in-memory network
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use bittorrent::transport::{MemoryNetwork, Transport};
+
+#[tokio::test]
+async fn connection_reaches_listener() {
+    let network = MemoryNetwork::new();
+    let seeder = network.transport(Ipv4Addr::new(10, 0, 0, 1));
+    let leecher = network.transport(Ipv4Addr::new(10, 0, 0, 2));
+    seeder.listen(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 6881)).await.unwrap();
+
+    let seeder_addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+    let mut outgoing = leecher.connect(seeder_addr).await.unwrap();
+    let mut incoming = seeder.accept().await.unwrap();
+    assert_eq!(outgoing.peer_addr().unwrap(), SocketAddr::V4(seeder_addr));
+    assert_eq!(incoming.peer_addr().unwrap(), outgoing.local_addr().unwrap());
+
+    outgoing.write_all(b"ping").await.unwrap();
+    let mut buffer = [0u8; 4];
+    incoming.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"ping");
+
+    // the other end sees the connection close
+    drop(outgoing);
+    assert_eq!(incoming.read(&mut buffer).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn connection_refused_without_listener() {
+    let network = MemoryNetwork::new();
+    let leecher = network.transport(Ipv4Addr::new(10, 0, 0, 2));
+    let seeder_addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+    assert!(leecher.connect(seeder_addr).await.is_err());
+
+    let seeder = network.transport(Ipv4Addr::new(10, 0, 0, 1));
+    seeder.listen(seeder_addr).await.unwrap();
+    assert!(leecher.connect(seeder_addr).await.is_ok());
+    drop(seeder);
+    assert!(leecher.connect(seeder_addr).await.is_err());
+}
@@ -0,0 +1,237 @@
+// Serving pieces to the peers that connect to us: who gets served, and the fast
+// extension messages around it
+
+mod common;
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use bittorrent::bitfield::Bitfield;
+use bittorrent::download::{self, DownloadOptions};
+use bittorrent::extension::ExtensionRegistry;
+use bittorrent::message::{Message, MessageLimits};
+use bittorrent::mse::EncryptionPolicy;
+use bittorrent::peer::{PeerConnection, PeerTimeouts};
+use bittorrent::resume::Progress;
+use bittorrent::storage::MemoryStorage;
+use bittorrent::torrent::TorrentInfo;
+use bittorrent::transport::{MemoryNetwork, Transport};
+use bittorrent::upload::{self, ALLOWED_FAST_PIECES};
+
+// more pieces than an allowed fast set holds
+const PIECE_LENGTH: u32 = 1024;
+const PIECE_NUM: usize = 40;
+const SEEDER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+const LEECHER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 100);
+
+fn torrent() -> (TorrentInfo, Vec<u8>) {
+    let payload = common::payload(PIECE_NUM * PIECE_LENGTH as usize);
+    (common::torrent("upload-test", PIECE_LENGTH, &payload), payload)
+}
+
+async fn connect(network: &Arc<MemoryNetwork>, ip: Ipv4Addr, info: &TorrentInfo) -> PeerConnection {
+    let transport = network.transport(ip);
+    PeerConnection::connect(
+        SEEDER,
+        info,
+        ExtensionRegistry::new(),
+        EncryptionPolicy::Disabled,
+        MessageLimits::default(),
+        PeerTimeouts::default(),
+        &transport,
+        false
+    ).await.unwrap()
+}
+
+// Read messages until one `wanted` takes
+async fn wait_for<T>(connection: &mut PeerConnection, wanted: impl Fn(Message) -> Option<T>) -> T {
+    let wait = async {
+        loop {
+            if let Some(found) = wanted(connection.next_message().await.unwrap()) {
+                return found;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), wait).await.expect("message never came")
+}
+
+// Our end of a connection to SEEDER, and the seeder's end for the test to script
+async fn scripted_pair(network: &Arc<MemoryNetwork>, info: &TorrentInfo) -> (PeerConnection, PeerConnection) {
+    let transport = network.transport(*SEEDER.ip());
+    transport.listen(SEEDER).await.unwrap();
+    let accept = async {
+        let stream = transport.accept().await.unwrap();
+        let timeouts = PeerTimeouts::default();
+        PeerConnection::accept(stream, info, ExtensionRegistry::new(), EncryptionPolicy::Disabled, MessageLimits::default(), timeouts).await
+    };
+    let (ours, theirs) = tokio::join!(connect(network, LEECHER_IP, info), accept);
+    (ours, theirs.unwrap())
+}
+
+// what a peer holding `have` of the torrent sends in place of a bitfield
+async fn have_message(have: &Bitfield) -> (Message, Bitfield) {
+    let (info, payload) = torrent();
+    let network = MemoryNetwork::new();
+    common::start_uploader(&network, SEEDER, &info, &payload, have, 1, EncryptionPolicy::Disabled, PeerTimeouts::default()).await;
+    let mut connection = connect(&network, LEECHER_IP, &info).await;
+    let message = wait_for(&mut connection, |message| {
+        matches!(message, Message::HaveAll | Message::HaveNone | Message::Bitfield(_)).then_some(message)
+    }).await;
+    (message, connection.has.clone())
+}
+
+#[test]
+fn allowed_fast_set_of_bep_6() {
+    // the example of the BEP, 7 and then 9 pieces
+    let ip = Ipv4Addr::new(80, 4, 4, 200);
+    let info_hash = [0xaa; 20];
+    let expected = [1059, 431, 808, 1217, 287, 376, 1188, 353, 508];
+    assert_eq!(upload::allowed_fast_set(ip, &info_hash, 1313, 7), expected[..7].iter().copied().collect());
+    assert_eq!(upload::allowed_fast_set(ip, &info_hash, 1313, 9), expected.into_iter().collect());
+
+    // the same for the whole /24, every piece of a small torrent
+    let set = upload::allowed_fast_set(ip, &info_hash, 1313, ALLOWED_FAST_PIECES);
+    assert_eq!(set.len(), ALLOWED_FAST_PIECES);
+    assert_eq!(upload::allowed_fast_set(Ipv4Addr::new(80, 4, 4, 1), &info_hash, 1313, ALLOWED_FAST_PIECES), set);
+    assert_ne!(upload::allowed_fast_set(Ipv4Addr::new(80, 4, 5, 200), &info_hash, 1313, ALLOWED_FAST_PIECES), set);
+    assert_eq!(upload::allowed_fast_set(ip, &info_hash, 6, ALLOWED_FAST_PIECES), (0..6).collect());
+}
+
+#[tokio::test]
+async fn have_all_and_have_none_stand_in_for_the_bitfield() {
+    let (message, has) = have_message(&common::all_pieces(PIECE_NUM)).await;
+    assert_eq!(message, Message::HaveAll);
+    assert_eq!(has.count_ones(), PIECE_NUM);
+
+    let (message, has) = have_message(&Bitfield::new(PIECE_NUM)).await;
+    assert_eq!(message, Message::HaveNone);
+    assert_eq!(has.count_ones(), 0);
+
+    let mut have = Bitfield::new(PIECE_NUM);
+    have.set(3, true);
+    let (message, has) = have_message(&have).await;
+    assert_eq!(message, Message::Bitfield(have.as_bytes().to_vec()));
+    assert!(has.get(3) && has.count_ones() == 1);
+}
+
+// The peer lets us fetch piece 0 while choking us, then rejects the request.
+// The piece is fetched once it unchokes us instead of failing.
+#[tokio::test]
+async fn rejected_allowed_fast_requests_wait_for_the_unchoke() {
+    let (info, payload) = torrent();
+    let network = MemoryNetwork::new();
+    let (mut ours, mut theirs) = scripted_pair(&network, &info).await;
+    theirs.send(&Message::HaveAll).await.unwrap();
+    theirs.send(&Message::AllowedFast(0)).await.unwrap();
+    wait_for(&mut ours, |message| matches!(message, Message::AllowedFast(0)).then_some(())).await;
+
+    let seeder = tokio::spawn(async move {
+        let mut requests = 0;
+        loop {
+            let Message::Request{ index, begin, length } = theirs.next_message().await.unwrap() else {
+                continue;
+            };
+            requests += 1;
+            if requests == 1 {
+                theirs.send(&Message::Reject{ index, begin, length }).await.unwrap();
+                theirs.send(&Message::Unchoke).await.unwrap();
+                continue;
+            }
+            let block = payload[begin as usize..(begin + length) as usize].to_vec();
+            theirs.send(&Message::Piece{ index, begin, block }).await.unwrap();
+            // dropping the connection could cut the piece short
+            return (requests, theirs);
+        }
+    });
+    let storage = MemoryStorage::new(&info);
+    let progress = Mutex::new(Progress::new(PIECE_NUM));
+    let (blocks, _) = broadcast::channel(1);
+    let download = ours.download_piece(&info, 0, &storage, &progress, &blocks);
+    let delivered = tokio::time::timeout(Duration::from_secs(10), download).await.expect("piece never came").unwrap();
+    assert_eq!(delivered, PIECE_LENGTH as u64);
+    assert!(progress.lock().unwrap().have.get(0));
+    assert!(!ours.allowed_fast.contains(&0));
+    assert_eq!(seeder.await.unwrap().0, 2);
+}
+
+#[tokio::test]
+async fn suggested_pieces_are_fetched_first() {
+    let (info, _) = torrent();
+    let network = MemoryNetwork::new();
+    let transport = network.transport(*SEEDER.ip());
+    transport.listen(SEEDER).await.unwrap();
+    let seeder = {
+        let info = info.clone();
+        tokio::spawn(async move {
+            let stream = transport.accept().await.unwrap();
+            let timeouts = PeerTimeouts::default();
+            let mut peer = PeerConnection::accept(stream, &info, ExtensionRegistry::new(), EncryptionPolicy::Disabled, MessageLimits::default(), timeouts).await.unwrap();
+            peer.send(&Message::HaveAll).await.unwrap();
+            peer.send(&Message::Suggest(17)).await.unwrap();
+            peer.send(&Message::Unchoke).await.unwrap();
+            let first_request = wait_for(&mut peer, |message| match message {
+                Message::Request{ index, .. } => Some(index),
+                _ => None,
+            }).await;
+            // still connected, so the download goes on waiting
+            (first_request, peer)
+        })
+    };
+    let options = DownloadOptions{
+        transport: Some(Arc::new(network.transport(LEECHER_IP))),
+        encryption: EncryptionPolicy::Disabled,
+        ..Default::default()
+    };
+    let announce = common::tracker_stand_in(vec![SEEDER]).await;
+    let dir = common::test_dir("upload-test");
+    let output = dir.join(&info.name);
+    let download = download::download_file(&info, &announce, output.to_str().unwrap(), &options);
+    tokio::select! {
+        result = download => panic!("download ended without the peer serving anything: {:?}", result),
+        seeder = seeder => assert_eq!(seeder.unwrap().0, 17),
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn choked_peers_are_refused_outside_their_allowed_fast_set() {
+    let (info, payload) = torrent();
+    let network = MemoryNetwork::new();
+    let have = common::all_pieces(PIECE_NUM);
+    common::start_uploader(&network, SEEDER, &info, &payload, &have, 1, EncryptionPolicy::Disabled, PeerTimeouts::default()).await;
+
+    // the first interested peer takes the only slot, the second stays choked
+    let mut unchoked = connect(&network, Ipv4Addr::new(10, 0, 1, 1), &info).await;
+    wait_for(&mut unchoked, |message| (message == Message::Unchoke).then_some(())).await;
+    let choked_ip = Ipv4Addr::new(10, 0, 2, 1);
+    let mut choked = connect(&network, choked_ip, &info).await;
+    // the allowed fast pieces are announced once the bitfield is out
+    while choked.allowed_fast.len() < ALLOWED_FAST_PIECES {
+        wait_for(&mut choked, |message| matches!(message, Message::AllowedFast(_)).then_some(())).await;
+    }
+    let info_hash = info.get_hash().unwrap();
+    assert_eq!(choked.allowed_fast, upload::allowed_fast_set(choked_ip, &info_hash, PIECE_NUM, ALLOWED_FAST_PIECES));
+    assert!(choked.is_choked());
+    let allowed = *choked.allowed_fast.first().unwrap();
+    let refused = (0..PIECE_NUM as u32).find(|piece_index| !choked.allowed_fast.contains(piece_index)).unwrap();
+
+    let block = |index: u32, begin: u32, length: u32| {
+        let start = (index * PIECE_LENGTH + begin) as usize;
+        Message::Piece{ index, begin, block: payload[start..start + length as usize].to_vec() }
+    };
+    let served = |message: Message| matches!(message, Message::Piece{ .. } | Message::Reject{ .. }).then_some(message);
+    for (index, begin, length) in [(refused, 0, 100), (allowed, 100, 200)] {
+        choked.send(&Message::Request{ index, begin, length }).await.unwrap();
+        let answer = wait_for(&mut choked, served).await;
+        match index == allowed {
+            true => assert_eq!(answer, block(index, begin, length)),
+            false => assert_eq!(answer, Message::Reject{ index, begin, length }),
+        }
+        // the unchoked peer gets either
+        unchoked.send(&Message::Request{ index, begin, length }).await.unwrap();
+        assert_eq!(wait_for(&mut unchoked, served).await, block(index, begin, length));
+    }
+}
@@ -0,0 +1,204 @@
+// uTP connections between two sockets on loopback, directly and through a UDP
+// relay that loses and reorders packets
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tokio::time;
+
+use bittorrent::utp::UtpSocket;
+
+// a transfer taking longer than this is stuck
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn loopback() -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)
+}
+
+fn addr_v4(addr: SocketAddr) -> SocketAddrV4 {
+    match addr {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => panic!("IPv6 address {}", addr),
+    }
+}
+
+fn payload(length: usize) -> Vec<u8> {
+    (0..length as u32).map(|i| (i.wrapping_mul(2654435761) >> 11) as u8).collect()
+}
+
+// Send `data` from one socket to the other at `addr`, and check it arrives
+// whole, followed by the end of the stream
+async fn transfer(client: &UtpSocket, server: &UtpSocket, addr: SocketAddrV4, data: Vec<u8>) {
+    let expected = data.clone();
+    let (outgoing, incoming) = tokio::join!(client.connect(addr), server.accept());
+    let (mut outgoing, mut incoming) = (outgoing.unwrap(), incoming.unwrap());
+    let sender = tokio::spawn(async move {
+        outgoing.write_all(&data).await.unwrap();
+        outgoing.shutdown().await.unwrap();
+        // keep the connection until the FIN went out
+        time::sleep(Duration::from_secs(1)).await;
+    });
+    let mut received = Vec::new();
+    time::timeout(TRANSFER_TIMEOUT, incoming.read_to_end(&mut received)).await.expect("transfer timed out").unwrap();
+    assert_eq!(received.len(), expected.len());
+    assert!(received == expected, "received bytes differ");
+    sender.await.unwrap();
+}
+
+// Relays datagrams between a client and `server`, the client sending to the
+// returned address. One in `drop_one_in` packets is lost, one in `delay_one_in`
+// is held back a little so the ones after it overtake it.
+async fn lossy_relay(server: SocketAddrV4, drop_one_in: u32, delay_one_in: u32) -> SocketAddrV4 {
+    let front = Arc::new(UdpSocket::bind(loopback()).await.unwrap());
+    let back = Arc::new(UdpSocket::bind(loopback()).await.unwrap());
+    let front_addr = addr_v4(front.local_addr().unwrap());
+    let client: Arc<Mutex<Option<SocketAddr>>> = Arc::new(Mutex::new(None));
+
+    let relay = |from: Arc<UdpSocket>, to: Arc<UdpSocket>, inbound: bool| {
+        let client = client.clone();
+        async move {
+            let mut buffer = vec![0u8; 65536];
+            let mut count = 0u32;
+            loop {
+                let (length, source) = from.recv_from(&mut buffer).await.unwrap();
+                let destination = match inbound {
+                    true => {
+                        *client.lock().await = Some(source);
+                        SocketAddr::V4(server)
+                    },
+                    false => match *client.lock().await {
+                        Some(client) => client,
+                        None => continue,
+                    },
+                };
+                count += 1;
+                if count.is_multiple_of(drop_one_in) {
+                    continue;
+                }
+                let datagram = buffer[..length].to_vec();
+                if count.is_multiple_of(delay_one_in) {
+                    let to = to.clone();
+                    tokio::spawn(async move {
+                        time::sleep(Duration::from_millis(5)).await;
+                        let _ = to.send_to(&datagram, destination).await;
+                    });
+                    continue;
+                }
+                let _ = to.send_to(&datagram, destination).await;
+            }
+        }
+    };
+    tokio::spawn(relay(front.clone(), back.clone(), true));
+    tokio::spawn(relay(back, front, false));
+    front_addr
+}
+
+#[tokio::test]
+async fn connection_reaches_listener() {
+    let client = UtpSocket::bind(loopback()).await.unwrap();
+    let server = UtpSocket::bind(loopback()).await.unwrap();
+    let server_addr = addr_v4(server.local_addr().unwrap());
+
+    let (outgoing, incoming) = tokio::join!(client.connect(server_addr), server.accept());
+    let (mut outgoing, mut incoming) = (outgoing.unwrap(), incoming.unwrap());
+    assert_eq!(outgoing.peer_addr(), SocketAddr::V4(server_addr));
+    assert_eq!(incoming.peer_addr(), client.local_addr().unwrap());
+
+    // both directions carry data
+    outgoing.write_all(b"ping").await.unwrap();
+    let mut buffer = [0u8; 4];
+    incoming.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"ping");
+    incoming.write_all(b"pong").await.unwrap();
+    outgoing.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"pong");
+}
+
+#[tokio::test]
+async fn connecting_without_listener_fails() {
+    let client = UtpSocket::bind(loopback()).await.unwrap();
+    // a UDP port nobody answers uTP on
+    let silent = UdpSocket::bind(loopback()).await.unwrap();
+    let silent_addr = addr_v4(silent.local_addr().unwrap());
+    let result = time::timeout(Duration::from_secs(10), client.connect(silent_addr)).await.expect("connect hung");
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn close_is_seen_by_the_other_end() {
+    let client = UtpSocket::bind(loopback()).await.unwrap();
+    let server = UtpSocket::bind(loopback()).await.unwrap();
+    let server_addr = addr_v4(server.local_addr().unwrap());
+
+    let (outgoing, incoming) = tokio::join!(client.connect(server_addr), server.accept());
+    let (mut outgoing, mut incoming) = (outgoing.unwrap(), incoming.unwrap());
+    outgoing.write_all(b"last words").await.unwrap();
+    drop(outgoing);
+
+    let mut received = Vec::new();
+    time::timeout(Duration::from_secs(5), incoming.read_to_end(&mut received)).await.expect("no FIN").unwrap();
+    assert_eq!(received, b"last words");
+}
+
+#[tokio::test]
+async fn transfers_megabytes() {
+    let client = UtpSocket::bind(loopback()).await.unwrap();
+    let server = UtpSocket::bind(loopback()).await.unwrap();
+    let server_addr = addr_v4(server.local_addr().unwrap());
+    transfer(&client, &server, server_addr, payload(4 * 1024 * 1024)).await;
+}
+
+#[tokio::test]
+async fn transfers_through_loss_and_reordering() {
+    let client = UtpSocket::bind(loopback()).await.unwrap();
+    let server = UtpSocket::bind(loopback()).await.unwrap();
+    let server_addr = addr_v4(server.local_addr().unwrap());
+    let relay_addr = lossy_relay(server_addr, 20, 7).await;
+    transfer(&client, &server, relay_addr, payload(2 * 1024 * 1024)).await;
+}
+
+// A uTP header as the sender of `kind` writes it
+fn raw_header(kind: u8, extension: u8, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Vec<u8> {
+    let mut header = vec![kind << 4 | 1, extension];
+    header.extend_from_slice(&connection_id.to_be_bytes());
+    header.extend_from_slice(&[0; 8]); // timestamp and its difference
+    header.extend_from_slice(&(1024u32 * 1024).to_be_bytes());
+    header.extend_from_slice(&seq_nr.to_be_bytes());
+    header.extend_from_slice(&ack_nr.to_be_bytes());
+    header
+}
+
+#[tokio::test]
+async fn out_of_order_packets_past_the_window_are_dropped() {
+    let server = UtpSocket::bind(loopback()).await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let sender = UdpSocket::bind(loopback()).await.unwrap();
+
+    // connect by hand, the server receives on id 1001 and expects packet 2 next
+    sender.send_to(&raw_header(4, 0, 1000, 1, 0), server_addr).await.unwrap();
+    let mut buffer = vec![0u8; 65536];
+    time::timeout(Duration::from_secs(5), sender.recv_from(&mut buffer)).await.expect("no answer to the SYN").unwrap();
+
+    // 32 packets of 60000 bytes skipping packet 2, far more than a 1 MiB window
+    for seq_nr in 3..35u16 {
+        let mut packet = raw_header(0, 0, 1001, seq_nr, 0);
+        packet.extend(vec![0x5a; 60000]);
+        sender.send_to(&packet, server_addr).await.unwrap();
+        time::sleep(Duration::from_millis(2)).await;
+    }
+
+    // the last ack says which were kept
+    let mut selective_ack = None;
+    while let Ok(Ok((length, _))) = time::timeout(Duration::from_millis(500), sender.recv_from(&mut buffer)).await {
+        if buffer[0] >> 4 == 2 && buffer[1] == 1 && length >= 26 {
+            selective_ack = Some(u32::from_le_bytes([buffer[22], buffer[23], buffer[24], buffer[25]]));
+        }
+    }
+    let selective_ack = selective_ack.expect("no selective ack");
+    // 17 packets fit 1 MiB and one packet of slack, bit i stands for packet 3 + i
+    assert_eq!(selective_ack, (1 << 17) - 1, "kept packets {:032b}", selective_ack);
+}
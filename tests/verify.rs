@@ -0,0 +1,88 @@
+// Checking data on disk against its torrent, through the library and the command
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use bittorrent::encoder;
+use bittorrent::torrent::{TorrentFile, TorrentInfo};
+use bittorrent::verify::{self, PieceStatus};
+
+const PIECE_LENGTH: u32 = 1024;
+const PIECE_NUM: usize = 6;
+
+fn run_verify(torrent_path: &Path, data_path: &Path) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_codecrafters-redis"))
+        .arg("verify")
+        .arg(torrent_path)
+        .arg(data_path)
+        .output()
+        .unwrap();
+    (output.status.success(), String::from_utf8(output.stdout).unwrap())
+}
+
+fn write_torrent(dir: &Path, info: &TorrentInfo) -> PathBuf {
+    let torrent = TorrentFile{ announce: String::new(), info: info.clone() };
+    let path = dir.join("payload.torrent");
+    std::fs::write(&path, encoder::encode_bencode(&torrent).unwrap()).unwrap();
+    path
+}
+
+#[tokio::test]
+async fn corrupt_and_truncated_pieces_are_told_apart() {
+    // the last piece is short
+    let mut payload = common::payload(PIECE_NUM * PIECE_LENGTH as usize - 300);
+    let info = common::torrent("payload.bin", PIECE_LENGTH, &payload);
+    let dir = common::test_dir("verify-test");
+    let torrent_path = write_torrent(&dir, &info);
+    let data_path = dir.join(&info.name);
+
+    std::fs::write(&data_path, &payload).unwrap();
+    let report = verify::verify(&info, &data_path).await.unwrap();
+    assert_eq!(report.count(PieceStatus::Good), PIECE_NUM);
+    assert_eq!(report.percentage(), 100.0);
+    let (success, stdout) = run_verify(&torrent_path, &data_path);
+    assert!(success, "{}", stdout);
+    assert!(stdout.contains("Good Pieces: 6"), "{}", stdout);
+
+    // one flipped byte in piece 1, and the file cut off inside piece 4
+    payload[PIECE_LENGTH as usize + 10] ^= 0xff;
+    payload.truncate(4 * PIECE_LENGTH as usize + 100);
+    std::fs::write(&data_path, &payload).unwrap();
+    let report = verify::verify(&info, &data_path).await.unwrap();
+    let statuses = [
+        PieceStatus::Good,
+        PieceStatus::Bad,
+        PieceStatus::Good,
+        PieceStatus::Good,
+        PieceStatus::Missing,
+        PieceStatus::Missing,
+    ];
+    assert_eq!(report.statuses, statuses);
+    assert_eq!(report.bitfield().as_bytes(), &[0b1011_0000]);
+
+    let (success, stdout) = run_verify(&torrent_path, &data_path);
+    assert!(!success, "{}", stdout);
+    assert!(stdout.contains("Good Pieces: 3"), "{}", stdout);
+    assert!(stdout.contains("Bad Pieces: 1"), "{}", stdout);
+    assert!(stdout.contains("Missing Pieces: 2"), "{}", stdout);
+    assert!(stdout.contains("Completion: 50.00%"), "{}", stdout);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn absent_data_is_missing() {
+    let payload = common::payload(PIECE_NUM * PIECE_LENGTH as usize);
+    let info = common::torrent("payload.bin", PIECE_LENGTH, &payload);
+    let dir = common::test_dir("verify-test");
+    let torrent_path = write_torrent(&dir, &info);
+    let data_path = dir.join(&info.name);
+
+    let report = verify::verify(&info, &data_path).await.unwrap();
+    assert_eq!(report.count(PieceStatus::Missing), PIECE_NUM);
+    let (success, stdout) = run_verify(&torrent_path, &data_path);
+    assert!(!success, "{}", stdout);
+    assert!(stdout.contains("Missing Pieces: 6"), "{}", stdout);
+    std::fs::remove_dir_all(&dir).unwrap();
+}